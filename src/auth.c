@@ -1,41 +1,709 @@
+#include <time.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <limits.h>
+#include <signal.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <termios.h>
+#include <sys/stat.h>
+#include <sys/wait.h>
+#include <crypt.h>
 #include <security/pam_appl.h>
-#include <security/pam_misc.h>
 
 #include "auth.h"
+#include "input.h"
+#include "log.h"
+#include "lock.h"
+#include "vt.h"
+#include "i18n.h"
 
-static struct pam_conv conv = {
-    misc_conv,
-    NULL
+#define MAX_MESSAGES 5
+#define HASH_SIZE 512
+
+// Hash of the duress password, empty if there is none
+static char duress_hash[HASH_SIZE];
+
+// Hash of the password given with --password-hash, empty if PAM is used
+static char password_hash[HASH_SIZE];
+
+struct conv_state;
+
+/**
+ *    Reads the answer to a prompt of the PAM conversation, which has already been printed.
+ *    Returns a newly allocated string, or `NULL` in case of error, EOF or timeout
+ *    (and in this last case sets `timed_out` in the state).
+ *
+ *    The conversation logic knows nothing about where the answers come from,
+ *    so that it can be driven by any source of input.
+ */
+typedef char* (*read_answer_t)(struct conv_state* state, int echo);
+
+/**
+ *    State of a PAM conversation.
+ *
+ *    Informational and error messages sent by the modules are kept in `messages`
+ *    and redrawn above every prompt, so that they don't scroll away while the user
+ *    is asked for something else (e.g. "Touch your security key" during a two-factor authentication).
+ */
+struct conv_state {
+    struct options* options;
+    time_t deadline;
+    int timed_out;
+    char* messages[MAX_MESSAGES];
+    int messages_style[MAX_MESSAGES];
+    int messages_size;
+    int busy_shown;
+    int duress;
+    read_answer_t read_answer;
 };
 
-int auth_authenticate_user(char* user) {
+static void add_message(struct conv_state* state, const char* msg, int style) {
+
+    // When full, drop the oldest message
+    if (state->messages_size == MAX_MESSAGES) {
+        free(state->messages[0]);
+        memmove(&state->messages[0], &state->messages[1], (MAX_MESSAGES - 1) * sizeof(char*));
+        memmove(&state->messages_style[0], &state->messages_style[1], (MAX_MESSAGES - 1) * sizeof(int));
+        state->messages_size--;
+    }
+
+    char* copy = strdup(msg);
+    if (copy != NULL) {
+        state->messages[state->messages_size] = copy;
+        state->messages_style[state->messages_size] = style;
+        state->messages_size++;
+    }
+}
+
+static void free_messages(struct conv_state* state) {
+    for (int i = 0; i < state->messages_size; i++) {
+        free(state->messages[i]);
+    }
+    state->messages_size = 0;
+}
+
+/**
+ *    Shows that the modules are working, until they send a message or the attempt completes.
+ *    PAM calls block, so the line is printed before them and stays as it is while they run.
+ */
+static void show_busy(struct conv_state* state) {
+    if (*state->options->busy_message != 0) {
+        fprintf(stdout, "%s", state->options->busy_message);
+        fflush(stdout);
+        state->busy_shown = 1;
+    }
+}
+
+static void hide_busy(struct conv_state* state) {
+    if (state->busy_shown) {
+        fprintf(stdout, "\r\033[K");
+        state->busy_shown = 0;
+    }
+}
+
+/**
+ *    Redraws the messages area, which starts where the cursor was when the authentication began.
+ */
+static void repaint_messages(struct conv_state* state) {
+    state->busy_shown = 0;
+    fprintf(stdout, "\0338\033[J");
+    for (int i = 0; i < state->messages_size; i++) {
+        if (state->messages_style[i] == PAM_ERROR_MSG) {
+            fprintf(stdout, "%s%s" RESET "\n", state->options->theme.error, state->messages[i]);
+        } else {
+            fprintf(stdout, "%s\n", state->messages[i]);
+        }
+    }
+}
+
+/**
+ *    Releases the responses of a failed conversation.
+ *    Only the answers to prompts are allocated: the entries of the other messages are still zeroed by `calloc`.
+ */
+static void free_responses(struct pam_response* resp, int num_msg) {
+    for (int i = 0; i < num_msg; i++) {
+        if (resp[i].resp != NULL) {
+            memset(resp[i].resp, 0, strlen(resp[i].resp));
+            free(resp[i].resp);
+        }
+    }
+    free(resp);
+}
+
+/**
+ *    Reads the answer to a prompt from the terminal, masking it if requested.
+ */
+static char* read_answer_from_terminal(struct conv_state* state, int echo) {
+    const struct options* options = state->options;
+    if (!echo && options->echo_mode != ECHO_HIDDEN) {
+        return input_read_masked(options->mask_timeout, options->echo_mode == ECHO_DOTS ? options->mask_dots : 0,
+                                 options->echo_mode == ECHO_REVEAL, options->max_input, options->bell,
+                                 state->deadline, &state->timed_out);
+    } else {
+        return input_read_line(echo, state->options->max_input, state->deadline, &state->timed_out);
+    }
+}
+
+/**
+ *    Reads the answer to a prompt from the FIFO given with `--password-fifo`, written for example
+ *    by the on-screen keyboard of a kiosk. Anyone able to write to the FIFO can unlock,
+ *    so it is refused unless only root can write to it.
+ */
+static char* read_answer_from_fifo(struct conv_state* state, int echo) {
+    const char* path = state->options->password_fifo;
+
+    // Opening also for writing never blocks, and the FIFO never reports EOF while we wait for a writer.
+    // The FIFO is closed after every answer, so nothing written in advance is ever used.
+    int fd = open(path, O_RDWR | O_NOFOLLOW | O_CLOEXEC);
+    if (fd < 0) {
+        fprintf(stderr, "Cannot open %s: %s\n", path, strerror(errno));
+        return NULL;
+    }
+
+    struct stat st;
+    if (fstat(fd, &st) < 0 || !S_ISFIFO(st.st_mode) || st.st_uid != 0 || (st.st_mode & (S_IWGRP | S_IWOTH)) != 0) {
+        fprintf(stderr, "Refusing to read from %s: it must be a FIFO owned by root and writable only by root.\n", path);
+        close(fd);
+        return NULL;
+    }
+
+    log_debug("Waiting for the answer on %s", path);
+    char* answer = input_read_fd(fd, state->options->max_input, state->deadline, &state->timed_out);
+    close(fd);
+    fprintf(stdout, "\n");
+    return answer;
+}
+
+/**
+ *    Tells whether `answer` is the password hashed in `expected`.
+ *    The hashes are compared in constant time, so that the time taken does not tell how much of them matches.
+ */
+static int hash_matches(const char* expected, const char* answer) {
+
+    // The scratch area of `crypt_r` is too large for the stack, and is wiped since it held the answer
+    struct crypt_data* data = (struct crypt_data*)calloc(1, sizeof(struct crypt_data));
+    if (data == NULL) {
+        return 0;
+    }
+    const char* hash = crypt_r(answer, expected, data);
     int ret = 0;
+    if (hash != NULL && strlen(hash) == strlen(expected)) {
+        unsigned char diff = 0;
+        for (size_t i = 0; expected[i] != 0; i++) {
+            diff |= (unsigned char)hash[i] ^ (unsigned char)expected[i];
+        }
+        ret = diff == 0;
+    }
+    memset(data, 0, sizeof(struct crypt_data));
+    free(data);
+    return ret;
+}
+
+/**
+ *    Tells whether the answer to a prompt is the duress password.
+ */
+static int is_duress(const char* answer) {
+    return *duress_hash != 0 && hash_matches(duress_hash, answer);
+}
 
-    // We start a new PAM session
+static int conversation(int num_msg, const struct pam_message** msg, struct pam_response** out_resp, void* appdata_ptr) {
+    struct conv_state* state = (struct conv_state*)appdata_ptr;
+
+    // Never leave the caller with a dangling pointer if we fail
+    *out_resp = NULL;
+
+    if (num_msg <= 0 || num_msg > PAM_MAX_NUM_MSG) {
+        return PAM_CONV_ERR;
+    }
+
+    // On success, PAM takes the ownership of the array and of every string in it, and will free them with `free`.
+    // On failure, instead, everything allocated so far is still ours to release.
+    struct pam_response* resp = (struct pam_response*)calloc(num_msg, sizeof(struct pam_response));
+    if (resp == NULL) {
+        return PAM_BUF_ERR;
+    }
+
+    for (int i = 0; i < num_msg; i++) {
+        // Log only the kind of message: prompts and responses might contain secrets
+        log_debug("PAM conversation: message %d of %d, style %d", i + 1, num_msg, msg[i]->msg_style);
+        const char* text = msg[i]->msg != NULL ? msg[i]->msg : "";
+        switch (msg[i]->msg_style) {
+
+            case PAM_ERROR_MSG:
+            case PAM_TEXT_INFO:
+                add_message(state, text, msg[i]->msg_style);
+                repaint_messages(state);
+                break;
+
+            case PAM_PROMPT_ECHO_ON:
+            case PAM_PROMPT_ECHO_OFF:
+                repaint_messages(state);
+                fprintf(stdout, "%s", text);
+                resp[i].resp = state->read_answer(state, msg[i]->msg_style == PAM_PROMPT_ECHO_ON);
+                if (resp[i].resp == NULL) {
+                    goto error;
+                }
+
+                // The duress password never reaches the modules: the conversation is aborted instead
+                if (msg[i]->msg_style == PAM_PROMPT_ECHO_OFF && is_duress(resp[i].resp)) {
+                    state->duress = 1;
+                    goto error;
+                }
+
+                // Checking the answer might take a while, e.g. with modules that ask a server
+                show_busy(state);
+                break;
+
+            default:
+                goto error;
+
+        }
+    }
+
+    *out_resp = resp;
+    return PAM_SUCCESS;
+
+error:
+    free_responses(resp, num_msg);
+    return PAM_CONV_ERR;
+}
+
+static int check_pam_user(struct options* options, pam_handle_t* pamh) {
+    const void* item;
+    int pam_ret = pam_get_item(pamh, PAM_USER, &item);
+    if (pam_ret != PAM_SUCCESS) {
+        return pam_ret;
+    }
+    if (item == NULL) {
+        return PAM_USER_UNKNOWN;
+    }
+
+    const char* pam_user = (const char*)item;
+    if (options_has_user(options, pam_user)) {
+        return PAM_SUCCESS;
+    }
+
+    fprintf(stdout, _("User %s is not allowed to unlock."), pam_user);
+    fprintf(stdout, "\n");
+    return PAM_PERM_DENIED;
+}
+
+/**
+ *    A PAM transaction, which can be used for more than one authentication attempt.
+ */
+struct auth_session {
+    struct options* options;
+    struct conv_state state;
+    struct pam_conv conv;
     pam_handle_t* pamh;
-    int pam_ret = pam_start("simplylock", user, &conv, &pamh);
+    const char* service;
+    int last_status;
+};
+
+static void session_init(struct auth_session* session, struct options* options) {
+    memset(session, 0, sizeof(*session));
+    session->options = options;
+    session->state.options = options;
+    session->state.read_answer = options->password_fifo != NULL ? read_answer_from_fifo : read_answer_from_terminal;
+    session->conv.conv = conversation;
+    session->conv.appdata_ptr = &session->state;
+}
+
+static int session_begin(struct auth_session* session, char* user) {
+    session->service = options_pam_service(session->options, user);
+    log_debug("Starting PAM transaction for user %s with service %s", user != NULL ? user : "(asked by PAM)", session->service);
+    int pam_ret = pam_start(session->service, user, &session->conv, &session->pamh);
+    if (pam_ret != PAM_SUCCESS) {
+        // If `pam_start` failed there's no valid handle, and the PAM functions must not be given one they can't use
+        log_debug("PAM transaction could not be started: error %d", pam_ret);
+        session->pamh = NULL;
+        return -1;
+    }
+    session->last_status = PAM_SUCCESS;
+    return 0;
+}
+
+static void session_finish(struct auth_session* session) {
+    if (session->pamh != NULL) {
+        log_debug("PAM transaction ended: %s", pam_strerror(session->pamh, session->last_status));
+        pam_end(session->pamh, session->last_status);
+        session->pamh = NULL;
+    }
+}
+
+/**
+ *    Refreshes the credentials of the user who just authenticated (e.g. Kerberos tickets),
+ *    and runs the session modules if requested.
+ *    Failures are reported, but don't prevent unlocking: the user has already proven their identity.
+ */
+static void refresh_credentials(struct auth_session* session) {
+    int pam_ret = pam_setcred(session->pamh, PAM_REINITIALIZE_CRED);
+    if (pam_ret != PAM_SUCCESS) {
+        fprintf(stderr, "Cannot refresh credentials: %s\n", pam_strerror(session->pamh, pam_ret));
+    }
+
+    if (session->options->pam_session) {
+        pam_ret = pam_open_session(session->pamh, 0);
+        if (pam_ret != PAM_SUCCESS) {
+            fprintf(stderr, "Cannot open PAM session: %s\n", pam_strerror(session->pamh, pam_ret));
+            return;
+        }
+        pam_ret = pam_close_session(session->pamh, 0);
+        if (pam_ret != PAM_SUCCESS) {
+            fprintf(stderr, "Cannot close PAM session: %s\n", pam_strerror(session->pamh, pam_ret));
+        }
+    }
+}
+
+/**
+ *    Tells whether a PAM error means that the user has been refused,
+ *    rather than that the authentication could not be carried out.
+ */
+static int is_refusal(int pam_ret) {
+    switch (pam_ret) {
+        case PAM_AUTH_ERR:
+        case PAM_CRED_INSUFFICIENT:
+        case PAM_USER_UNKNOWN:
+        case PAM_MAXTRIES:
+        case PAM_PERM_DENIED:
+        case PAM_ACCT_EXPIRED:
+        case PAM_NEW_AUTHTOK_REQD:
+            return 1;
+        default:
+            return 0;
+    }
+}
+
+/**
+ *    Runs a single authentication attempt in an already started transaction.
+ */
+static auth_result_t session_attempt(struct auth_session* session, char* user) {
+    struct conv_state* state = &session->state;
+    state->timed_out = 0;
+    state->busy_shown = 0;
+    state->duress = 0;
+    state->deadline = session->options->prompt_timeout > 0 ? time(NULL) + session->options->prompt_timeout : 0;
+
+    // The messages area begins here
+    fprintf(stdout, "\0337");
+    show_busy(state);
+
+    // Every attempt can be for a different user, and without one PAM asks for it again
+    int pam_ret = pam_set_item(session->pamh, PAM_USER, user);
 
     // Authentication
     if (pam_ret == PAM_SUCCESS) {
-        pam_ret = pam_authenticate(pamh, 0);
-    } else {
-        ret = -1;
+        pam_ret = pam_authenticate(session->pamh, 0);
+    }
+
+    // If PAM chose the user, make sure it is one of those allowed to unlock
+    if (pam_ret == PAM_SUCCESS && user == NULL) {
+        pam_ret = check_pam_user(session->options, session->pamh);
     }
 
     // Authorization
     if (pam_ret == PAM_SUCCESS) {
-        pam_ret = pam_acct_mgmt(pamh, 0);
-    } else {
-        ret = -1;
+        pam_ret = pam_acct_mgmt(session->pamh, 0);
+    }
+
+    if (pam_ret == PAM_SUCCESS) {
+        refresh_credentials(session);
+    }
+
+    hide_busy(state);
+    session->last_status = pam_ret;
+    free_messages(state);
+
+    // Nothing must tell that the duress password has been entered, not even the debug messages
+    if (state->duress) {
+        session_finish(session);
+        return AUTH_DURESS;
     }
+    log_debug("PAM attempt completed: %s", pam_strerror(session->pamh, pam_ret));
 
     // Has the user successfully authenticated?
-    ret = pam_ret == PAM_SUCCESS ? 0 : -1;
+    if (pam_ret == PAM_SUCCESS) {
+        return AUTH_SUCCESS;
+    } else if (state->timed_out) {
+        return AUTH_TIMEOUT;
+    } else if (is_refusal(pam_ret)) {
+        return AUTH_FAILURE;
+    }
 
-    // Terminate PAM session
-    if (pam_end(pamh, pam_ret) != PAM_SUCCESS) {
-        ret = -1;
+    // Something went wrong in the modules: the transaction might be unusable, so the next attempt starts a new one
+    fprintf(stdout, "\n");
+    fprintf(stdout, _("Authentication is not available right now: %s."), pam_strerror(session->pamh, pam_ret));
+    fprintf(stdout, "\n");
+    session_finish(session);
+    return AUTH_ERROR;
+}
+
+/**
+ *    Runs a single authentication attempt against the hash given with `--password-hash`, without PAM.
+ *    Any of the users allowed to unlock is unlocked by the same password.
+ */
+static auth_result_t hash_attempt(struct auth_session* session) {
+    struct conv_state* state = &session->state;
+    state->timed_out = 0;
+    state->busy_shown = 0;
+    state->deadline = session->options->prompt_timeout > 0 ? time(NULL) + session->options->prompt_timeout : 0;
+
+    // The answer is never logged, nor kept longer than needed to check it
+    fprintf(stdout, "%s", _("Password: "));
+    char* answer = state->read_answer(state, 0);
+    if (answer == NULL) {
+        return state->timed_out ? AUTH_TIMEOUT : AUTH_FAILURE;
+    }
+    auth_result_t result = AUTH_FAILURE;
+    if (is_duress(answer)) {
+        result = AUTH_DURESS;
+    } else if (hash_matches(password_hash, answer)) {
+        result = AUTH_SUCCESS;
+    }
+    memset(answer, 0, strlen(answer));
+    free(answer);
+    return result;
+}
+
+/**
+ *    Authenticates the user in a transaction of its own.
+ */
+static auth_result_t authenticate(struct options* options, char* user) {
+    struct auth_session session;
+    session_init(&session, options);
+    if (session_begin(&session, user) < 0) {
+        fprintf(stdout, "\n%s\n", _("Authentication is not available right now: cannot start PAM."));
+        return AUTH_ERROR;
+    }
+    auth_result_t result = session_attempt(&session, user);
+    session_finish(&session);
+    return result;
+}
+
+static void on_sigalrm(int sig) {
+    // Nothing to do: we just need `waitpid` to be interrupted
+}
+
+/**
+ *    Runs the PAM transaction in a child process, so that it can be killed
+ *    if a module hangs (`pam_authenticate` is synchronous and cannot be interrupted otherwise).
+ *
+ *    Since the transaction lives entirely in the child, no PAM handle or memory allocated by the modules
+ *    is ever shared with this process: the child communicates only its result through the exit status.
+ *    Any in-memory state of the modules is lost when the child exits, while state persisted
+ *    by the modules themselves (e.g. failure counters on disk) is preserved.
+ */
+static auth_result_t authenticate_with_timeout(struct options* options, char* user) {
+
+    // Save the state of the terminal, since the child might be killed before restoring it
+    struct termios term;
+    int has_term = tcgetattr(STDIN_FILENO, &term) == 0;
+
+    pid_t childpid = fork();
+    if (childpid == 0) {
+
+        // A crash of a module must not touch the locked terminal
+        lock_handle_crashes(0);
+        _exit(-authenticate(options, user));
+    } else if (childpid == -1) {
+        perror("fork");
+        return AUTH_FAILURE;
+    }
+
+    // Wait for the child, but not more than the timeout.
+    // The handler is installed without SA_RESTART, so that `waitpid` fails with EINTR when the alarm fires.
+    struct sigaction action, old_action;
+    memset(&action, 0, sizeof(action));
+    action.sa_handler = on_sigalrm;
+    sigaction(SIGALRM, &action, &old_action);
+    alarm(options->pam_timeout);
+
+    int status;
+    pid_t wpid = waitpid(childpid, &status, 0);
+    int timed_out = wpid == -1 && errno == EINTR;
+
+    alarm(0);
+    sigaction(SIGALRM, &old_action, NULL);
+
+    if (timed_out) {
+        kill(childpid, SIGKILL);
+        while (waitpid(childpid, &status, 0) == -1 && errno == EINTR);
+        if (has_term) {
+            tcsetattr(STDIN_FILENO, TCSANOW, &term);
+        }
+        fprintf(stdout, "\n%s\n", _("Authentication took too long and has been aborted."));
+        return AUTH_FAILURE;
+    }
+
+    if (wpid == -1 || !WIFEXITED(status)) {
+        return AUTH_FAILURE;
+    }
+    return -WEXITSTATUS(status);
+}
+
+auth_result_t auth_authenticate_user(struct options* options, char* user) {
+    if (options->password_hash != NULL) {
+        struct auth_session session;
+        session_init(&session, options);
+        return hash_attempt(&session);
+    } else if (options->pam_timeout > 0) {
+        return authenticate_with_timeout(options, user);
+    } else {
+        return authenticate(options, user);
+    }
+}
+
+struct auth_session* auth_session_start(struct options* options) {
+    struct auth_session* session = (struct auth_session*)malloc(sizeof(struct auth_session));
+    if (session == NULL) {
+        return NULL;
+    }
+    session_init(session, options);
+    return session;
+}
+
+auth_result_t auth_session_authenticate(struct auth_session* session, char* user) {
+
+    // Without PAM there is no transaction at all
+    if (session->options->password_hash != NULL) {
+        return hash_attempt(session);
+    }
+
+    // A transaction cannot be shared with the child process that enforces the timeout
+    if (session->options->pam_timeout > 0) {
+        return authenticate_with_timeout(session->options, user);
+    }
+
+    // A transaction is bound to its service: users with a different one need a new transaction
+    if (session->pamh != NULL && strcmp(session->service, options_pam_service(session->options, user)) != 0) {
+        session_finish(session);
+    }
+
+    // The transaction is started on the first attempt, or again if starting it failed the last time
+    if (session->pamh == NULL && session_begin(session, user) < 0) {
+        fprintf(stdout, "\n%s\n", _("Authentication is not available right now: cannot start PAM."));
+        return AUTH_ERROR;
+    }
+    return session_attempt(session, user);
+}
+
+void auth_session_end(struct auth_session* session) {
+    if (session != NULL) {
+        session_finish(session);
+        free(session);
+    }
+}
+
+static int check_service(const char* service) {
+
+    // PAM silently falls back to the "other" service when ours is missing,
+    // so look for its configuration in the usual places
+    const char* dirs[] = { "/etc/pam.d", "/usr/lib/pam.d", "/lib/pam.d" };
+    for (int i = 0; i < sizeof(dirs) / sizeof(dirs[0]); i++) {
+        char path[PATH_MAX];
+        snprintf(path, sizeof(path), "%s/%s", dirs[i], service);
+        if (access(path, R_OK) == 0) {
+            return 0;
+        }
+    }
+
+    fprintf(stderr, "PAM configuration for the %s service not found in /etc/pam.d.\n", service);
+    return -1;
+}
+
+int auth_check(const struct options* options) {
+
+    // Without /etc/pam.d, or without PAM at all with --password-hash, the configuration is in /etc/pam.conf, which we don't parse
+    if (options->password_hash != NULL || access("/etc/pam.d", F_OK) < 0) {
+        return 0;
     }
 
+    // Check every service, so that all the missing ones are reported at once
+    int ret = check_service(options->pam_service);
+    for (int i = 0; i < options->user_services_size; i++) {
+        if (check_service(options->user_services[i].service) < 0) {
+            ret = -1;
+        }
+    }
+    if (options->step_up_service != NULL && check_service(options->step_up_service) < 0) {
+        ret = -1;
+    }
+    if (options->smartcard_service != NULL && check_service(options->smartcard_service) < 0) {
+        ret = -1;
+    }
     return ret;
 }
+
+/**
+ *    Loads a password hash from `path` into `buf`, which must be `HASH_SIZE` bytes long.
+ *    On failure, `buf` is left empty.
+ */
+static int load_hash(const char* path, char* buf) {
+    *buf = 0;
+
+    int fd = open(path, O_RDONLY | O_NOFOLLOW | O_CLOEXEC);
+    if (fd < 0) {
+        fprintf(stderr, "Cannot open %s: %s\n", path, strerror(errno));
+        return -1;
+    }
+
+    // Anyone able to read the hash could try to guess the password offline
+    struct stat st;
+    if (fstat(fd, &st) < 0 || !S_ISREG(st.st_mode) || st.st_uid != 0 || (st.st_mode & (S_IRWXG | S_IRWXO)) != 0) {
+        fprintf(stderr, "Refusing to use %s: it must be a regular file owned by root and accessible only by root.\n", path);
+        close(fd);
+        return -1;
+    }
+
+    ssize_t len;
+    while ((len = read(fd, buf, HASH_SIZE - 1)) == -1 && errno == EINTR);
+    close(fd);
+    if (len < 0) {
+        fprintf(stderr, "Cannot read %s: %s\n", path, strerror(errno));
+        *buf = 0;
+        return -1;
+    }
+    buf[len] = 0;
+    buf[strcspn(buf, "\r\n")] = 0;
+
+    // `crypt` fails, or returns a string beginning with '*', when the hash is not one it supports
+    struct crypt_data* data = (struct crypt_data*)calloc(1, sizeof(struct crypt_data));
+    if (data == NULL) {
+        *buf = 0;
+        return -1;
+    }
+    const char* hash = crypt_r("", buf, data);
+    int valid = *buf != 0 && hash != NULL && *hash != '*';
+    free(data);
+    if (!valid) {
+        fprintf(stderr, "%s does not contain a supported password hash.\n", path);
+        *buf = 0;
+        return -1;
+    }
+    return 0;
+}
+
+int auth_load_hashes(const struct options* options) {
+    *duress_hash = 0;
+    *password_hash = 0;
+    if (options->duress_file != NULL && load_hash(options->duress_file, duress_hash) < 0) {
+        return -1;
+    }
+    if (options->password_hash != NULL && load_hash(options->password_hash, password_hash) < 0) {
+        return -1;
+    }
+    return 0;
+}
+
+const char* auth_library_name() {
+#if defined(__LINUX_PAM__)
+    return "Linux-PAM";
+#elif defined(OPENPAM_VERSION)
+    return "OpenPAM";
+#else
+    return "unknown";
+#endif
+}
@@ -1,12 +1,92 @@
 #ifndef __AUTH_H__
 #define __AUTH_H__
 
+#include "options.h"
+
+/**
+ *    Possible outcomes of an authentication attempt.
+ */
+typedef enum {
+    AUTH_SUCCESS = 0,
+    AUTH_FAILURE = -1,
+    AUTH_TIMEOUT = -2,
+    AUTH_ERROR = -3,
+    AUTH_DURESS = -4
+} auth_result_t;
+
+/**
+ *    Uses PAM to authenticate the given user, in a transaction of its own.
+ *
+ *    If `options->prompt_timeout` is set, the user has that many seconds to complete the authentication.
+ *    If `options->pam_timeout` is set, the whole PAM transaction is aborted if it does not complete
+ *    in that many seconds (e.g. because a module is stuck), and this counts as a failed authentication.
+ *
+ *    @param  options SimplyLock options.
+ *    @param  user    User to authenticate, or `NULL` to let PAM ask for the user name.
+ *                    In this case, the user chosen through PAM must be one of `options->users`.
+ *    @return         `AUTH_SUCCESS` if the user successfully authenticated,
+ *                    `AUTH_TIMEOUT` if the user did not complete the authentication in time,
+ *                    `AUTH_ERROR` if the authentication could not be carried out
+ *                    (e.g. a module could not reach its server), which is reported on `stdout`,
+ *                    `AUTH_DURESS` if the duress password loaded with `auth_load_hashes` has been entered
+ *                    (which is never passed to PAM), `AUTH_FAILURE` otherwise.
+ */
+auth_result_t auth_authenticate_user(struct options* options, char* user);
+
+/**
+ *    PAM transaction shared by multiple authentication attempts.
+ */
+struct auth_session;
+
+/**
+ *    Prepares a PAM transaction to be used for more than one authentication attempt,
+ *    so that the modules keep their state (e.g. failure counters) between attempts.
+ *
+ *    @param  options SimplyLock options.
+ *    @return         The new session, or `NULL` in case of error and sets `errno`.
+ */
+struct auth_session* auth_session_start(struct options* options);
+
+/**
+ *    Like `auth_authenticate_user`, but the attempt is made in the transaction of the session.
+ *    When `options->pam_timeout` is set, every attempt still runs in a transaction of its own,
+ *    since it lives in a process that might need to be killed.
+ *
+ *    @param  session Session in which to make the attempt.
+ *    @param  user    User to authenticate, or `NULL` to let PAM ask for the user name.
+ *    @return         Same as `auth_authenticate_user`.
+ */
+auth_result_t auth_session_authenticate(struct auth_session* session, char* user);
+
+/**
+ *    Ends the PAM transaction of the session and releases it.
+ *
+ *    @param session Session to end. Can be `NULL`.
+ */
+void auth_session_end(struct auth_session* session);
+
+/**
+ *    Loads the password hashes from the files given with `--duress-file` and `--password-hash`.
+ *    The files must be regular files owned by root, and not accessible by anybody else.
+ *    Problems are reported on `stderr`.
+ *
+ *    @param  options SimplyLock options.
+ *    @return `0` in case of success or if there are no hashes to load, `-1` otherwise.
+ */
+int auth_load_hashes(const struct options* options);
+
+/**
+ *    Returns the name of the PAM implementation SimplyLock has been compiled against.
+ */
+const char* auth_library_name();
+
 /**
- *    Uses PAM to authenticate the given user.
+ *    Checks that the PAM services used by SimplyLock are configured.
+ *    Problems are reported on `stderr`.
  *
- *    @param  user User to authenticate.
- *    @return      `0` if the user successfully authenticated, `-1` otherwise.
+ *    @param  options SimplyLock options.
+ *    @return `0` if all the services are configured, `-1` otherwise.
  */
-int auth_authenticate_user(char* user);
+int auth_check(const struct options* options);
 
 #endif
@@ -1,11 +1,14 @@
 #include <stdlib.h>
 #include <stdbool.h>
 #include <stdio.h>
+#include <string.h>
 #include <fcntl.h>
 #include <stropts.h>
 #include <unistd.h>
+#include <dirent.h>
 #include <linux/fb.h>
 #include <sys/mman.h>
+#include <sys/stat.h>
 
 #if MAGICKWAND_VERSION == 6
 #include <wand/MagickWand.h>
@@ -18,10 +21,13 @@
 
 #include "bg.h"
 
+// Delay used for animation frames that do not specify a sensible one, in milliseconds
+#define DEFAULT_FRAME_DELAY 100
+
 static bool magick_wand_inited = false;
 
-struct bg {
-    
+struct screen {
+
     // Framebuffer fd and mmapped memory address
     int fbfd;
     char* fbmem;
@@ -32,27 +38,49 @@ struct bg {
     int height;
     int original_bpp;
 
-    // Wands
-    MagickWand* m_wand;
+    // Frame being shown and the one that follows, both already fitted to the screen
+    MagickWand* current;
+    MagickWand* next;
+
+    // Next framebuffer to draw on, if any
+    struct screen* next_screen;
+
+};
+
+struct bg {
+
+    enum background_fill_t fill;
     PixelWand* p_wand;
 
+    // Images to cycle through, when the background is a directory
+    char** files;
+    int files_size;
+
+    // Frames of the background image, each one complete
+    MagickWand* frames;
+    int frames_size;
+
+    // Index of the next frame to load, and how long frames are shown (ms)
+    int index;
+    unsigned int interval;
+    unsigned int current_delay;
+    unsigned int next_delay;
+
+    struct screen* screens;
+
 };
 
-static bool fill_image(struct bg* bg, enum background_fill_t fill) {
+static bool fill_image(MagickWand* wand, int screen_w, int screen_h, enum background_fill_t fill) {
     
     // Extract width and height of the image
-    int img_w = MagickGetImageWidth(bg->m_wand);
-    int img_h = MagickGetImageHeight(bg->m_wand);
-
-    // Screen size
-    int screen_w = bg->width;
-    int screen_h = bg->height;
+    int img_w = MagickGetImageWidth(wand);
+    int img_h = MagickGetImageHeight(wand);
 
     switch (fill) {
         
         case CENTER:
             // This centres the original image on a new canvas.
-            if (MagickExtentImage(bg->m_wand, screen_w, screen_h, -(screen_w - img_w) / 2, -(screen_h - img_h) / 2) == MagickFalse) {
+            if (MagickExtentImage(wand, screen_w, screen_h, -(screen_w - img_w) / 2, -(screen_h - img_h) / 2) == MagickFalse) {
                 fprintf(stderr, "Error manipulating image.\n");
                 return false;
             }
@@ -60,7 +88,7 @@ static bool fill_image(struct bg* bg, enum background_fill_t fill) {
         
         case STRETCH:
             // Resize the image to match the screen size
-            if (MagickResizeImage(bg->m_wand, screen_w, screen_h, LanczosFilter) == MagickFalse) {
+            if (MagickResizeImage(wand, screen_w, screen_h, LanczosFilter) == MagickFalse) {
                 fprintf(stderr, "Error manipulating image.\n");
                 return false;
             }
@@ -78,13 +106,13 @@ static bool fill_image(struct bg* bg, enum background_fill_t fill) {
             int new_h = (int)(ratio * img_h);
 
             // Resize the image
-            if (MagickResizeImage(bg->m_wand, new_w, new_h, LanczosFilter) == MagickFalse) {
+            if (MagickResizeImage(wand, new_w, new_h, LanczosFilter) == MagickFalse) {
                 fprintf(stderr, "Error manipulating image.\n");
                 return false;
             }
 
             // Center the image
-            if (MagickExtentImage(bg->m_wand, screen_w, screen_h, -(screen_w - new_w) / 2, -(screen_h - new_h) / 2) == MagickFalse) {
+            if (MagickExtentImage(wand, screen_w, screen_h, -(screen_w - new_w) / 2, -(screen_h - new_h) / 2) == MagickFalse) {
                 fprintf(stderr, "Error manipulating image.\n");
                 return false;
             }
@@ -105,13 +133,13 @@ static bool fill_image(struct bg* bg, enum background_fill_t fill) {
             int new_h = (int)(ratio * img_h);
 
             // Resize the image
-            if (MagickResizeImage(bg->m_wand, new_w, new_h, LanczosFilter) == MagickFalse) {
+            if (MagickResizeImage(wand, new_w, new_h, LanczosFilter) == MagickFalse) {
                 fprintf(stderr, "Error manipulating image.\n");
                 return false;
             }
 
             // Center the image
-            if (MagickExtentImage(bg->m_wand, screen_w, screen_h, -(screen_w - new_w) / 2, -(screen_h - new_h) / 2) == MagickFalse) {
+            if (MagickExtentImage(wand, screen_w, screen_h, -(screen_w - new_w) / 2, -(screen_h - new_h) / 2) == MagickFalse) {
                 fprintf(stderr, "Error manipulating image.\n");
                 return false;
             }
@@ -131,35 +159,97 @@ static bool fill_image(struct bg* bg, enum background_fill_t fill) {
 
 }
 
-void* bg_init(const char* path, enum background_fill_t fill, const char* fbdev) {
-    
+const char* bg_fill_name(enum background_fill_t fill) {
+    switch (fill) {
+        case CENTER:
+            return "center";
+        case STRETCH:
+            return "stretch";
+        case RESIZE:
+            return "resize";
+        case RESIZE_FILL:
+            return "resize-fill";
+        default:
+            return "unknown";
+    }
+}
+
+unsigned int bg_find_fbdevs(char fbdevs[][BG_FBDEV_PATH_SIZE], unsigned int max) {
+    unsigned int found = 0;
+    struct stat st;
+
+    for (int i = 0; i < BG_MAX_FBDEVS && found < max; i++) {
+        snprintf(fbdevs[found], BG_FBDEV_PATH_SIZE, "/dev/fb%d", i);
+        if (stat(fbdevs[found], &st) == 0 && S_ISCHR(st.st_mode)) {
+            found++;
+        }
+    }
+
+    return found;
+}
+
+static void free_screen(struct screen* screen) {
+
+    // Free MagickWand structures
+    if (screen->current != NULL) {
+        DestroyMagickWand(screen->current);
+    }
+    if (screen->next != NULL) {
+        DestroyMagickWand(screen->next);
+    }
+
+    // Unmap framebuffer memory
+    if (screen->fbmem != NULL && (intptr_t)screen->fbmem != -1) {
+        munmap(screen->fbmem, screen->fbmem_len);
+    }
+
+    // Close framebuffer fd
+    if (screen->fbfd != -1) {
+
+        // Restore original bpp
+        struct fb_var_screeninfo vinfo;
+        if (ioctl(screen->fbfd, FBIOGET_VSCREENINFO, &vinfo) == 0) {
+            vinfo.bits_per_pixel = screen->original_bpp;
+            ioctl(screen->fbfd, FBIOPUT_VSCREENINFO, &vinfo);
+        }
+
+        close(screen->fbfd);
+    }
+
+    free(screen);
+
+}
+
+static struct screen* open_screen(const char* fbdev) {
+
     struct fb_var_screeninfo vinfo;
     struct fb_fix_screeninfo finfo;
 
     // Allocates the memory for the struct
-    struct bg* bg = calloc(1, sizeof(struct bg));
-    if (bg == NULL) {
+    struct screen* screen = calloc(1, sizeof(struct screen));
+    if (screen == NULL) {
         perror("Cannot allocate memory for background image.");
         return NULL;
     }
-    bg->fbfd = -1;
+    screen->fbfd = -1;
 
     // Opens the framebuffer device
     int fbfd = open(fbdev, O_RDWR);
     if (fbfd < 0) {
-        perror("Cannot open framebuffer device.");
+        fprintf(stderr, "Cannot open framebuffer device %s: ", fbdev);
+        perror(NULL);
         goto error;
     }
-    bg->fbfd = fbfd;
+    screen->fbfd = fbfd;
 
     // Gets variable screen information
     if (ioctl(fbfd, FBIOGET_VSCREENINFO, &vinfo)) {
         perror("Error reading variable information from framebuffer.");
         goto error;
     }
-    bg->width = vinfo.xres;
-    bg->height = vinfo.yres;
-    bg->original_bpp = vinfo.bits_per_pixel;
+    screen->width = vinfo.xres;
+    screen->height = vinfo.yres;
+    screen->original_bpp = vinfo.bits_per_pixel;
 
     // Sets 32 bits per pixel
     vinfo.bits_per_pixel = 32;
@@ -175,12 +265,90 @@ void* bg_init(const char* path, enum background_fill_t fill, const char* fbdev)
     }
 
     // Mmap framebuffer memory
-    bg->fbmem = (char*) mmap(0, finfo.smem_len, PROT_READ | PROT_WRITE, MAP_SHARED, fbfd, 0);
-    if ((intptr_t)bg->fbmem == -1) {
+    screen->fbmem = (char*) mmap(0, finfo.smem_len, PROT_READ | PROT_WRITE, MAP_SHARED, fbfd, 0);
+    if ((intptr_t)screen->fbmem == -1) {
         perror("Unable to mmap framebuffer.");
         goto error;
     }
-    bg->fbmem_len = finfo.smem_len;
+    screen->fbmem_len = finfo.smem_len;
+
+    return screen;
+
+error:
+    free_screen(screen);
+    return NULL;
+
+}
+
+/**
+ * Collects the paths of the files in `dir`, in alphabetical order.
+ * Hidden files are skipped.
+ */
+static bool list_images(struct bg* bg, const char* dir) {
+    struct dirent** entries;
+    struct stat st;
+
+    int n = scandir(dir, &entries, NULL, alphasort);
+    if (n < 0) {
+        fprintf(stderr, "Cannot read background directory %s: ", dir);
+        perror(NULL);
+        return false;
+    }
+
+    bg->files = calloc(n > 0 ? n : 1, sizeof(char*));
+    if (bg->files == NULL) {
+        perror("Cannot allocate memory for background images.");
+    }
+    for (int i = 0; i < n; i++) {
+        const char* name = entries[i]->d_name;
+        if (bg->files != NULL && *name != '.') {
+            size_t len = strlen(dir) + strlen(name) + 2;
+            char* file = malloc(len);
+            if (file != NULL) {
+                snprintf(file, len, "%s/%s", dir, name);
+                if (stat(file, &st) == 0 && S_ISREG(st.st_mode)) {
+                    bg->files[bg->files_size++] = file;
+                } else {
+                    free(file);
+                }
+            }
+        }
+        free(entries[i]);
+    }
+    free(entries);
+
+    if (bg->files == NULL) {
+        return false;
+    }
+    if (bg->files_size == 0) {
+        fprintf(stderr, "No images in background directory %s.\n", dir);
+        return false;
+    }
+    return true;
+}
+
+/**
+ * Tells whether `path` is an image in a supported format, reading only its header.
+ */
+static bool can_load(const char* path) {
+    MagickWand* wand = NewMagickWand();
+    if (wand == NULL) {
+        fprintf(stderr, "Cannot allocate magick wand.\n");
+        return false;
+    }
+    bool ok = MagickPingImage(wand, path) == MagickTrue;
+    DestroyMagickWand(wand);
+    return ok;
+}
+
+int bg_check(const char* path) {
+    struct stat st;
+
+    if (stat(path, &st) < 0) {
+        fprintf(stderr, "Cannot access background image %s: ", path);
+        perror(NULL);
+        return -1;
+    }
 
     // Initialize MagickWand if not done yet
     if (!magick_wand_inited) {
@@ -188,28 +356,273 @@ void* bg_init(const char* path, enum background_fill_t fill, const char* fbdev)
         magick_wand_inited = true;
     }
 
-    // Create the needed wands
-    bg->m_wand = NewMagickWand();
-    if (bg->m_wand == NULL) {
-        fprintf(stderr, "Cannot allocate magick wand.\n");
-        goto error;
+    if (!S_ISDIR(st.st_mode)) {
+        if (!can_load(path)) {
+            fprintf(stderr, "Unsupported background image %s.\n", path);
+            return -1;
+        }
+        return 0;
+    }
+
+    // In a slideshow, the images that cannot be loaded are skipped
+    struct bg bg;
+    memset(&bg, 0, sizeof(bg));
+    if (!list_images(&bg, path)) {
+        free(bg.files);
+        return -1;
+    }
+    int ret = -1;
+    for (int i = 0; i < bg.files_size; i++) {
+        if (ret < 0 && can_load(bg.files[i])) {
+            ret = 0;
+        }
+        free(bg.files[i]);
+    }
+    free(bg.files);
+    if (ret < 0) {
+        fprintf(stderr, "No supported images in background directory %s.\n", path);
+    }
+    return ret;
+}
+
+static int frames_count(struct bg* bg) {
+    if (bg->files_size > 0) {
+        return bg->files_size;
     }
+    return bg->frames_size > 0 ? bg->frames_size : 1;
+}
+
+/**
+ * Loads the next frame to show, at its original size.
+ * If there's no image, the returned wand is empty.
+ *
+ * @param delay Receives how long the frame has to be shown, in milliseconds.
+ */
+static MagickWand* load_frame(struct bg* bg, unsigned int* delay) {
+    MagickWand* wand;
+
+    if (bg->files_size > 0) {
+
+        // Slideshow: images that cannot be loaded are skipped
+        for (int tries = 0; tries < bg->files_size; tries++) {
+            const char* file = bg->files[bg->index];
+            bg->index = (bg->index + 1) % bg->files_size;
+
+            wand = NewMagickWand();
+            if (wand == NULL) {
+                fprintf(stderr, "Cannot allocate magick wand.\n");
+                return NULL;
+            }
+            if (MagickReadImage(wand, file) == MagickTrue) {
+
+                // Only the first frame of each image is shown
+                MagickResetIterator(wand);
+                *delay = bg->interval;
+                return wand;
+
+            }
+            DestroyMagickWand(wand);
+        }
+        fprintf(stderr, "Unable to load any background image.\n");
+        return NULL;
+
+    } else if (bg->frames != NULL) {
+
+        MagickSetIteratorIndex(bg->frames, bg->index);
+        bg->index = (bg->index + 1) % bg->frames_size;
+
+        // Delays are in hundredths of a second, and very short ones are not honored by anyone
+        size_t ticks = MagickGetImageDelay(bg->frames);
+        *delay = ticks > 1 ? ticks * 10 : DEFAULT_FRAME_DELAY;
+
+        wand = MagickGetImage(bg->frames);
+        if (wand == NULL) {
+            fprintf(stderr, "Error manipulating image.\n");
+        }
+        return wand;
+
+    } else {
+
+        *delay = 0;
+        wand = NewMagickWand();
+        if (wand == NULL) {
+            fprintf(stderr, "Cannot allocate magick wand.\n");
+        }
+        return wand;
+
+    }
+}
+
+/**
+ * Prepares a copy of `frame` that matches the size of `screen`.
+ */
+static MagickWand* fit_frame(struct bg* bg, struct screen* screen, MagickWand* frame) {
+    MagickWand* wand;
+
+    if (MagickGetNumberImages(frame) == 0) {
+
+        // No image: just fill the screen with the color
+        wand = NewMagickWand();
+        if (wand == NULL) {
+            fprintf(stderr, "Cannot allocate magick wand.\n");
+            return NULL;
+        }
+        if (MagickNewImage(wand, screen->width, screen->height, bg->p_wand) == MagickFalse) {
+            fprintf(stderr, "Error creating background.\n");
+            DestroyMagickWand(wand);
+            return NULL;
+        }
+
+    } else {
+
+        wand = MagickGetImage(frame);
+        if (wand == NULL) {
+            fprintf(stderr, "Error manipulating image.\n");
+            return NULL;
+        }
+        MagickSetImageBackgroundColor(wand, bg->p_wand);
+
+        // Prepares the image so that it matches the screen size
+        if (!fill_image(wand, screen->width, screen->height, bg->fill)) {
+            DestroyMagickWand(wand);
+            return NULL;
+        }
+
+    }
+
+    return wand;
+}
+
+/**
+ * Loads the next frame and fits it to all the screens, ready to be shown.
+ */
+static bool prepare_next(struct bg* bg) {
+    unsigned int delay;
+
+    MagickWand* frame = load_frame(bg, &delay);
+    if (frame == NULL) {
+        return false;
+    }
+
+    bool ok = true;
+    for (struct screen* screen = bg->screens; screen != NULL; screen = screen->next_screen) {
+        if (screen->next != NULL) {
+            DestroyMagickWand(screen->next);
+        }
+        screen->next = fit_frame(bg, screen, frame);
+        ok = ok && screen->next != NULL;
+    }
+    bg->next_delay = delay;
+
+    DestroyMagickWand(frame);
+    return ok;
+}
+
+/**
+ * Makes the prepared frame the current one.
+ */
+static void show_next(struct bg* bg) {
+    for (struct screen* screen = bg->screens; screen != NULL; screen = screen->next_screen) {
+        if (screen->next != NULL) {
+            if (screen->current != NULL) {
+                DestroyMagickWand(screen->current);
+            }
+            screen->current = screen->next;
+            screen->next = NULL;
+        }
+    }
+    bg->current_delay = bg->next_delay;
+}
+
+void* bg_init(const char* path, enum background_fill_t fill, const char* color, unsigned int interval,
+              const char* const* fbdevs, unsigned int fbdevs_size) {
+
+    struct stat st;
+
+    // Allocates the memory for the struct
+    struct bg* bg = calloc(1, sizeof(struct bg));
+    if (bg == NULL) {
+        perror("Cannot allocate memory for background image.");
+        return NULL;
+    }
+    bg->fill = fill;
+    bg->interval = interval * 1000;
+
+    // Initialize MagickWand if not done yet
+    if (!magick_wand_inited) {
+        MagickWandGenesis();
+        magick_wand_inited = true;
+    }
+
+    // Create the needed wands
     bg->p_wand = NewPixelWand();
     if (bg->p_wand == NULL) {
         fprintf(stderr, "Cannot allocate pixel wand.\n");
         goto error;
     }
-    PixelSetColor(bg->p_wand, "black");
-	MagickSetImageBackgroundColor(bg->m_wand, bg->p_wand);
+    if (PixelSetColor(bg->p_wand, color != NULL ? color : "black") == MagickFalse) {
+        fprintf(stderr, "Invalid background color %s.\n", color);
+        goto error;
+    }
+
+    if (path != NULL && stat(path, &st) == 0 && S_ISDIR(st.st_mode)) {
+
+        // Slideshow: images are loaded one at a time
+        if (!list_images(bg, path)) {
+            goto error;
+        }
+
+    } else if (path != NULL) {
+
+        // Load the image
+        bg->frames = NewMagickWand();
+        if (bg->frames == NULL) {
+            fprintf(stderr, "Cannot allocate magick wand.\n");
+            goto error;
+        }
+        if (MagickReadImage(bg->frames, path) == MagickFalse) {
+            fprintf(stderr, "Unable to load background image %s.\n", path);
+            goto error;
+        }
+
+        // Frames of animations might contain only what changed since the previous one
+        if (MagickGetNumberImages(bg->frames) > 1) {
+            MagickWand* coalesced = MagickCoalesceImages(bg->frames);
+            if (coalesced == NULL) {
+                fprintf(stderr, "Error manipulating image.\n");
+                goto error;
+            }
+            DestroyMagickWand(bg->frames);
+            bg->frames = coalesced;
+        }
+        bg->frames_size = MagickGetNumberImages(bg->frames);
+
+    }
 
-    // Load the image
-    if (MagickReadImage(bg->m_wand, path) == MagickFalse) {
-        fprintf(stderr, "Unable to load background image %s.\n", path);
+    // Devices that cannot be opened are skipped
+    struct screen* last = NULL;
+    for (int i = 0; i < fbdevs_size; i++) {
+        struct screen* screen = open_screen(fbdevs[i]);
+        if (screen == NULL) {
+            continue;
+        }
+        if (last == NULL) {
+            bg->screens = screen;
+        } else {
+            last->next_screen = screen;
+        }
+        last = screen;
+    }
+    if (bg->screens == NULL) {
         goto error;
     }
 
-    // Prepares the image so that it matches the screen size
-    if (!fill_image(bg, fill)) {
+    // Show the first frame, and decode the second one ahead of time
+    if (!prepare_next(bg)) {
+        goto error;
+    }
+    show_next(bg);
+    if (frames_count(bg) > 1 && !prepare_next(bg)) {
         goto error;
     }
 
@@ -224,42 +637,59 @@ error:
 void bg_paint(void* background) {
     struct bg* bg = (struct bg*)background;
 
-    // Just copy the pixels from the image to the framebuffer
-    MagickExportImagePixels(bg->m_wand, 0, 0, bg->width, bg->height, "BGRA", CharPixel, bg->fbmem);
+    // Just copy the pixels from the image to each framebuffer
+    for (struct screen* screen = bg->screens; screen != NULL; screen = screen->next_screen) {
+        if (screen->current != NULL) {
+            MagickExportImagePixels(screen->current, 0, 0, screen->width, screen->height, "BGRA", CharPixel, screen->fbmem);
+        }
+    }
+
+}
+
+unsigned int bg_frame_delay(void* background) {
+    struct bg* bg = (struct bg*)background;
+    return frames_count(bg) > 1 ? bg->current_delay : 0;
+}
+
+int bg_next_frame(void* background) {
+    struct bg* bg = (struct bg*)background;
 
+    if (frames_count(bg) <= 1) {
+        return 0;
+    }
+
+    show_next(bg);
+    return prepare_next(bg) ? 0 : -1;
 }
 
 void bg_free(void* background) {
     if (background != NULL) {
         struct bg* bg = (struct bg*)background;
-        
+
+        while (bg->screens != NULL) {
+            struct screen* screen = bg->screens;
+            bg->screens = screen->next_screen;
+            free_screen(screen);
+        }
+
         // Free MagickWand structures
-        if (bg->m_wand != NULL) {
-            DestroyMagickWand(bg->m_wand);
+        if (bg->frames != NULL) {
+            DestroyMagickWand(bg->frames);
         }
         if (bg->p_wand != NULL) {
             DestroyPixelWand(bg->p_wand);
         }
 
-        // Unmap framebuffer memory
-        if (bg->fbmem != NULL && (intptr_t)bg->fbmem != -1) {
-            munmap(bg->fbmem, bg->fbmem_len);
-        }
-
-        // Close framebuffer fd
-        if (bg->fbfd != -1) {
-
-            // Restore original bpp
-            struct fb_var_screeninfo vinfo;
-            if (ioctl(bg->fbfd, FBIOGET_VSCREENINFO, &vinfo) == 0) {
-                vinfo.bits_per_pixel = bg->original_bpp;
-                ioctl(bg->fbfd, FBIOPUT_VSCREENINFO, &vinfo);
-            }
-
-            close(bg->fbfd);
+        for (int i = 0; i < bg->files_size; i++) {
+            free(bg->files[i]);
         }
+        free(bg->files);
 
         free(bg);
 
     }
-}
\ No newline at end of file
+}
+
+const char* bg_library_version() {
+    return MagickGetVersion(NULL);
+}
@@ -11,15 +11,59 @@ enum background_fill_t {
     RESIZE_FILL // Resize the image to fill the whole screen, but keep proportions
 };
 
+/**
+ * Returns the name of a fill mode, as accepted by `--background-fill`.
+ */
+const char* bg_fill_name(enum background_fill_t fill);
+
+/**
+ * Checks that the background image can be loaded, without decoding it:
+ * the file must exist and be in a supported format.
+ * If `path` is a directory, at least one of the images it contains must be supported.
+ * The reason of a failure is printed on `stderr`.
+ *
+ * @param path Path of the image or of the directory.
+ * @return `0` if the background can be used, `-1` otherwise.
+ */
+int bg_check(const char* path);
+
+/**
+ * Maximum number of framebuffer devices (same as the kernel FB_MAX).
+ */
+#define BG_MAX_FBDEVS 32
+
+/**
+ * Size of the buffers holding the paths of the detected framebuffer devices.
+ */
+#define BG_FBDEV_PATH_SIZE 16
+
+/**
+ * Looks for the framebuffer devices from `/dev/fb0` to `/dev/fb31`.
+ * The devices are returned in ascending order.
+ *
+ * @param fbdevs Buffer that will receive the paths of the devices.
+ * @param max Maximum number of devices to store in `fbdevs`.
+ * @return Number of devices found.
+ */
+unsigned int bg_find_fbdevs(char fbdevs[][BG_FBDEV_PATH_SIZE], unsigned int max);
+
 /**
  * Initializes a new structure to draw a background image on a vty using the framebuffer.
+ * The image is prepared separately for each device, using its own resolution.
+ * Devices that cannot be initialized are skipped.
+ * If `path` is a directory, the images it contains are shown one after the other,
+ * and if it is an animated image, its frames are played.
  * 
- * @param path Path of the image to draw.
+ * @param path Path of the image to draw, or `NULL` to fill the screen with `color`.
  * @param fill Fill mode.
- * @param fbdev Path to the framebuffer device.
- * @return `NULL` in case of error, a pointer to an opaque structure otherwise.
+ * @param color Color of the parts of the screen not covered by the image, or `NULL` for black.
+ * @param interval Seconds each image of a directory is shown.
+ * @param fbdevs Paths of the framebuffer devices to draw on.
+ * @param fbdevs_size Number of elements in `fbdevs`.
+ * @return `NULL` in case of error or if no device could be used, a pointer to an opaque structure otherwise.
  */
-void* bg_init(const char* path, enum background_fill_t fill, const char* fbdev);
+void* bg_init(const char* path, enum background_fill_t fill, const char* color, unsigned int interval,
+              const char* const* fbdevs, unsigned int fbdevs_size);
 
 /**
  * Redraws the image stored in `bg` to the framebuffer.
@@ -28,9 +72,31 @@ void* bg_init(const char* path, enum background_fill_t fill, const char* fbdev);
  */
 void bg_paint(void* bg);
 
+/**
+ * Returns how long the current frame has to stay on screen before calling `bg_next_frame`.
+ *
+ * @param bg Opaque pointer returned by bg_init.
+ * @return Delay in milliseconds, or `0` if the background never changes.
+ */
+unsigned int bg_frame_delay(void* bg);
+
+/**
+ * Switches to the next frame of the background, and prepares the one that follows.
+ * The new frame is not drawn until `bg_paint` is called.
+ *
+ * @param bg Opaque pointer returned by bg_init.
+ * @return `0` in case of success, `-1` if the following frame could not be prepared.
+ */
+int bg_next_frame(void* bg);
+
 /**
  * Releases all the resources held by the given `bg`.
  */
 void bg_free(void* bg);
 
+/**
+ * Returns the version of the library used to load the images.
+ */
+const char* bg_library_version();
+
 #endif
\ No newline at end of file
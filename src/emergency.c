@@ -0,0 +1,185 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <limits.h>
+#include <time.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <sys/stat.h>
+#include <openssl/evp.h>
+#include <openssl/pem.h>
+#include "emergency.h"
+#include "log.h"
+
+#define TOKEN_PREFIX "simplylock-unlock "
+#define TOKEN_FILE_SIZE 1024
+#define SIGNATURE_SIZE 64 // Size of an Ed25519 signature
+
+// Public key the tokens are verified with, `NULL` if emergency unlock is not enabled
+static EVP_PKEY* key = NULL;
+
+/**
+ *    Opens `path`, refusing it unless it is a regular file owned by root and writable only by root.
+ *    Anyone else able to write it could replace the key, or the token.
+ */
+static FILE* open_root_file(const char* path) {
+    int fd = open(path, O_RDONLY | O_NOFOLLOW | O_CLOEXEC);
+    if (fd < 0) {
+        return NULL;
+    }
+
+    struct stat st;
+    if (fstat(fd, &st) < 0 || !S_ISREG(st.st_mode) || st.st_uid != 0 || (st.st_mode & (S_IWGRP | S_IWOTH)) != 0) {
+        close(fd);
+        errno = EPERM;
+        return NULL;
+    }
+
+    FILE* stream = fdopen(fd, "r");
+    if (stream == NULL) {
+        close(fd);
+    }
+    return stream;
+}
+
+int emergency_load_key(const struct options* options) {
+    if (options->emergency_key == NULL) {
+        return 0;
+    }
+
+    FILE* stream = open_root_file(options->emergency_key);
+    if (stream == NULL) {
+        if (errno == EPERM) {
+            fprintf(stderr, "Refusing to use %s: it must be a regular file owned by root and writable only by root.\n", options->emergency_key);
+        } else {
+            fprintf(stderr, "Cannot open %s: %s\n", options->emergency_key, strerror(errno));
+        }
+        return -1;
+    }
+
+    key = PEM_read_PUBKEY(stream, NULL, NULL, NULL);
+    fclose(stream);
+    if (key == NULL || EVP_PKEY_id(key) != EVP_PKEY_ED25519) {
+        fprintf(stderr, "%s does not contain an Ed25519 public key in PEM format.\n", options->emergency_key);
+        emergency_end();
+        return -1;
+    }
+    return 0;
+}
+
+/**
+ *    Decodes the base64 signature in `text` into `sig`.
+ *
+ *    @return `0` if `text` is the base64 of exactly `SIGNATURE_SIZE` bytes, `-1` otherwise.
+ */
+static int decode_signature(const char* text, unsigned char sig[SIGNATURE_SIZE]) {
+    size_t len = strlen(text);
+    if (len == 0 || len % 4 != 0 || len / 4 * 3 > SIGNATURE_SIZE + 2) {
+        return -1;
+    }
+
+    // `EVP_DecodeBlock` also counts the bytes of the padding
+    unsigned char buf[SIGNATURE_SIZE + 2];
+    int decoded = EVP_DecodeBlock(buf, (const unsigned char*)text, (int)len);
+    if (decoded < 0) {
+        return -1;
+    }
+    for (size_t i = len; i > 0 && text[i - 1] == '='; i--) {
+        decoded--;
+    }
+    if (decoded != SIGNATURE_SIZE) {
+        return -1;
+    }
+    memcpy(sig, buf, SIGNATURE_SIZE);
+    return 0;
+}
+
+/**
+ *    Tells whether the token is for this host and has not expired yet.
+ */
+static int token_applies(const char* token) {
+    if (strncmp(token, TOKEN_PREFIX, strlen(TOKEN_PREFIX)) != 0) {
+        log_debug("Emergency unlock token refused: malformed");
+        return 0;
+    }
+
+    const char* host = token + strlen(TOKEN_PREFIX);
+    const char* space = strchr(host, ' ');
+    char hostname[HOST_NAME_MAX + 1];
+    if (space == NULL || gethostname(hostname, sizeof(hostname)) < 0) {
+        log_debug("Emergency unlock token refused: malformed");
+        return 0;
+    }
+    hostname[HOST_NAME_MAX] = 0;
+    if (strlen(hostname) != (size_t)(space - host) || strncmp(host, hostname, space - host) != 0) {
+        log_debug("Emergency unlock token refused: it is for another host");
+        return 0;
+    }
+
+    char* end;
+    errno = 0;
+    long long expiration = strtoll(space + 1, &end, 10);
+    if (errno != 0 || end == space + 1 || *end != 0) {
+        log_debug("Emergency unlock token refused: malformed");
+        return 0;
+    }
+    if (expiration <= (long long)time(NULL)) {
+        log_debug("Emergency unlock token refused: expired");
+        return 0;
+    }
+    return 1;
+}
+
+int emergency_check_token(const struct options* options) {
+    if (key == NULL) {
+        return 0;
+    }
+
+    FILE* stream = open_root_file(options->emergency_file);
+    if (stream == NULL) {
+        if (errno == EPERM) {
+            log_debug("Emergency unlock token refused: %s must be owned by root and writable only by root", options->emergency_file);
+        }
+        return 0;
+    }
+
+    char buf[TOKEN_FILE_SIZE];
+    size_t len = fread(buf, 1, sizeof(buf) - 1, stream);
+    fclose(stream);
+    buf[len] = 0;
+
+    // The token on the first line, the signature on the second one
+    char* token = buf;
+    char* signature = strchr(buf, '\n');
+    if (signature == NULL) {
+        log_debug("Emergency unlock token refused: malformed");
+        return 0;
+    }
+    *signature++ = 0;
+    signature[strcspn(signature, "\r\n")] = 0;
+
+    unsigned char sig[SIGNATURE_SIZE];
+    if (decode_signature(signature, sig) < 0) {
+        log_debug("Emergency unlock token refused: malformed signature");
+        return 0;
+    }
+
+    // Ed25519 signs the whole message, without a separate digest
+    EVP_MD_CTX* ctx = EVP_MD_CTX_new();
+    int valid = ctx != NULL && EVP_DigestVerifyInit(ctx, NULL, NULL, NULL, key) == 1 &&
+                EVP_DigestVerify(ctx, sig, SIGNATURE_SIZE, (const unsigned char*)token, strlen(token)) == 1;
+    EVP_MD_CTX_free(ctx);
+    if (!valid) {
+        log_debug("Emergency unlock token refused: wrong signature");
+        return 0;
+    }
+
+    // Only the contents of a signed token are worth looking at
+    return token_applies(token);
+}
+
+void emergency_end() {
+    EVP_PKEY_free(key);
+    key = NULL;
+}
@@ -0,0 +1,35 @@
+#ifndef __EMERGENCY_H__
+#define __EMERGENCY_H__
+
+#include "options.h"
+
+/**
+ *    Loads the public key given with `--emergency-key`, used to verify the emergency unlock tokens.
+ *    The key must be an Ed25519 public key in PEM format, in a regular file owned by root
+ *    and writable only by root. Problems are reported on `stderr`.
+ *
+ *    @param  options SimplyLock options.
+ *    @return `0` in case of success or if emergency unlock is not enabled, `-1` otherwise.
+ */
+int emergency_load_key(const struct options* options);
+
+/**
+ *    Checks whether a valid emergency unlock token is in `--emergency-file`.
+ *
+ *    The file must be a regular file owned by root and writable only by root, with two lines:
+ *    the token, `simplylock-unlock <hostname> <expiration>`, where the expiration is a Unix timestamp,
+ *    and the base64 of its Ed25519 signature made with the private key matching `--emergency-key`.
+ *    The token is valid only on the named host and until it expires.
+ *    Why a token is refused is only logged with `log_debug`.
+ *
+ *    @param  options SimplyLock options.
+ *    @return `1` if the file contains a valid token, `0` otherwise.
+ */
+int emergency_check_token(const struct options* options);
+
+/**
+ *    Frees the key loaded with `emergency_load_key`.
+ */
+void emergency_end();
+
+#endif
@@ -0,0 +1,19 @@
+#ifndef __I18N_H__
+#define __I18N_H__
+
+#include <libintl.h>
+
+#define GETTEXT_PACKAGE "simplylock"
+
+// Where the message catalogs are installed, overridden by the Makefile
+#ifndef LOCALEDIR
+#define LOCALEDIR "/usr/share/locale"
+#endif
+
+// Marks a string to be translated, and translates it
+#define _(str) gettext(str)
+
+// Marks a string to be translated where it can't be translated yet (e.g. static initializers)
+#define N_(str) (str)
+
+#endif
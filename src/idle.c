@@ -0,0 +1,104 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <limits.h>
+#include <time.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <poll.h>
+#include <dirent.h>
+
+#include "idle.h"
+#include "log.h"
+
+#define INPUT_DEVICES_PATH "/dev/input"
+#define MAX_INPUT_DEVICES 64
+
+/**
+ *    Opens all the event devices in `/dev/input`, filling `fds`.
+ *    Returns the number of devices opened, or `-1` in case of error.
+ */
+static int open_input_devices(struct pollfd* fds) {
+    DIR* dir = opendir(INPUT_DEVICES_PATH);
+    if (dir == NULL) {
+        return -1;
+    }
+
+    int n = 0;
+    struct dirent* entry;
+    char path[1024];
+    while (n < MAX_INPUT_DEVICES && (entry = readdir(dir)) != NULL) {
+        if (strncmp(entry->d_name, "event", 5) != 0) {
+            continue;
+        }
+        snprintf(path, sizeof(path), INPUT_DEVICES_PATH "/%s", entry->d_name);
+        int fd;
+        while ((fd = open(path, O_RDONLY | O_NONBLOCK | O_CLOEXEC)) == -1 && errno == EINTR);
+        if (fd < 0) {
+            continue;
+        }
+        fds[n].fd = fd;
+        fds[n].events = POLLIN;
+        fds[n].revents = 0;
+        n++;
+    }
+
+    closedir(dir);
+    return n;
+}
+
+int idle_wait(unsigned int seconds) {
+    struct pollfd fds[MAX_INPUT_DEVICES];
+    int n = open_input_devices(fds);
+    if (n < 0) {
+        return -1;
+    } else if (n == 0) {
+        errno = ENODEV;
+        return -1;
+    }
+    log_debug("Watching %d input devices for inactivity", n);
+
+    int ret = 0;
+    time_t deadline = time(NULL) + seconds;
+    for (;;) {
+        time_t now = time(NULL);
+        if (now >= deadline) {
+            break;
+        }
+        int timeout = deadline - now > INT_MAX / 1000 ? INT_MAX : (deadline - now) * 1000;
+
+        int ready = poll(fds, n, timeout);
+        if (ready < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            ret = -1;
+            break;
+        } else if (ready == 0) {
+            continue;
+        }
+
+        // Some activity: discard the events and start counting again
+        char buf[1024];
+        for (int i = 0; i < n; i++) {
+            if (fds[i].revents & (POLLERR | POLLHUP | POLLNVAL)) {
+                // The device has been unplugged: stop watching it
+                close(fds[i].fd);
+                fds[i].fd = -1;
+            } else if (fds[i].revents & POLLIN) {
+                while (read(fds[i].fd, buf, sizeof(buf)) > 0);
+            }
+        }
+        deadline = time(NULL) + seconds;
+    }
+
+    int saved_errno = errno;
+    for (int i = 0; i < n; i++) {
+        if (fds[i].fd >= 0) {
+            close(fds[i].fd);
+        }
+    }
+    errno = saved_errno;
+    return ret;
+}
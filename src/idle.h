@@ -0,0 +1,17 @@
+#ifndef __IDLE_H__
+#define __IDLE_H__
+
+/**
+ *    Waits until no input is received from any of the input devices
+ *    (keyboards, mice, touchpads, ...) for the given number of seconds.
+ *
+ *    The devices are looked up in `/dev/input` when the function is called:
+ *    devices plugged in while waiting are not watched.
+ *
+ *    @param  seconds Number of seconds of inactivity to wait for.
+ *    @return `0` when the station has been idle for the given time,
+ *            `-1` in case of error and sets `errno`.
+ */
+int idle_wait(unsigned int seconds);
+
+#endif
@@ -0,0 +1,340 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <limits.h>
+#include <unistd.h>
+#include <poll.h>
+#include <termios.h>
+
+#include "input.h"
+
+#define INITIAL_BUFFER_SIZE 64
+#define REVEAL_TIME_MS 1000
+
+/**
+ *    Waits for input on `fd` for at most `timeout` milliseconds (`-1` to wait forever)
+ *    or until the deadline is reached.
+ *    Returns `1` if there's input to read, `0` on timeout, `-1` on error.
+ */
+static int wait_input(int fd, int timeout, time_t deadline, int* timed_out) {
+    struct pollfd pfd = { fd, POLLIN, 0 };
+    for (;;) {
+
+        // The deadline might come before the timeout
+        int deadline_first = 0;
+        if (deadline != 0) {
+            time_t now = time(NULL);
+            if (now >= deadline) {
+                *timed_out = 1;
+                return 0;
+            }
+            int remaining = deadline - now > INT_MAX / 1000 ? INT_MAX : (deadline - now) * 1000;
+            if (timeout < 0 || remaining < timeout) {
+                timeout = remaining;
+                deadline_first = 1;
+            }
+        }
+
+        int ret = poll(&pfd, 1, timeout);
+        if (ret > 0) {
+            return 1;
+        } else if (ret == 0 && !deadline_first) {
+            return 0;
+        } else if (ret < 0 && errno != EINTR) {
+            return -1;
+        }
+    }
+}
+
+static int set_term_flags(struct termios* old_term, tcflag_t enable, tcflag_t disable) {
+    if (tcgetattr(STDIN_FILENO, old_term) < 0) {
+        return -1;
+    }
+    struct termios term = *old_term;
+    term.c_lflag |= enable;
+    term.c_lflag &= ~disable;
+    term.c_cc[VMIN] = 1;
+    term.c_cc[VTIME] = 0;
+    return tcsetattr(STDIN_FILENO, TCSANOW, &term);
+}
+
+/**
+ *    Makes sure that `buf` has space for at least `needed` bytes.
+ *    The buffer is never `realloc`ated, so that no copy of its contents is left around in freed memory.
+ */
+static int ensure_capacity(char** buf, size_t* size, size_t needed) {
+    if (needed <= *size) {
+        return 0;
+    }
+    size_t new_size = *size * 2 > needed ? *size * 2 : needed;
+    char* new_buf = (char*)malloc(new_size);
+    if (new_buf == NULL) {
+        return -1;
+    }
+    memcpy(new_buf, *buf, *size);
+    memset(*buf, 0, *size);
+    free(*buf);
+    *buf = new_buf;
+    *size = new_size;
+    return 0;
+}
+
+/**
+ *    Cuts a line longer than `max_len` bytes, without leaving half of a UTF-8 character at the end.
+ */
+static void truncate_line(char* line, size_t len, size_t max_len) {
+    if (len <= max_len) {
+        return;
+    }
+    size_t cut = max_len;
+    while (cut > 0 && ((unsigned char)line[cut] & 0xC0) == 0x80) {
+        cut--;
+    }
+    memset(line + cut, 0, len - cut);
+}
+
+char* input_read_line(int echo, size_t max_len, time_t deadline, int* timed_out) {
+    struct termios old_term;
+    int has_term = set_term_flags(&old_term, echo ? ECHO : 0, echo ? 0 : ECHO) == 0;
+
+    char* line = NULL;
+    size_t n = 0;
+    ssize_t len = -1;
+
+    // Wait for a whole line to be available
+    if (wait_input(STDIN_FILENO, -1, deadline, timed_out) > 0) {
+        len = getline(&line, &n, stdin);
+    }
+
+    if (has_term) {
+        tcsetattr(STDIN_FILENO, TCSANOW, &old_term);
+    }
+
+    // The newline typed by the user was not echoed
+    if (!echo) {
+        fprintf(stdout, "\n");
+    }
+
+    // A line cut short by EOF (for example, if the terminal has been hung up) is never returned:
+    // whatever was read is discarded, instead of being passed on as a complete answer.
+    // The EOF flag of the stream is sticky, so clear it or every read after this one would fail.
+    if (len <= 0 || line[len - 1] != '\n') {
+        clearerr(stdin);
+        if (line != NULL) {
+            memset(line, 0, n);
+            free(line);
+        }
+        return NULL;
+    }
+    line[len - 1] = 0;
+    truncate_line(line, len - 1, max_len);
+    return line;
+}
+
+/**
+ *    Draws a fixed row of dots, the first `lit` of which are filled.
+ *    If `redraw` is set, the cursor is first moved back over the previous row.
+ */
+static void draw_dots(unsigned int lit, unsigned int dots, int redraw) {
+    if (redraw) {
+        for (unsigned int i = 0; i < dots; i++) {
+            fprintf(stdout, "\b");
+        }
+    }
+    for (unsigned int i = 0; i < dots; i++) {
+        fprintf(stdout, i < lit ? "\u25cf" : "\u25cb");
+    }
+}
+
+char* input_read_masked(unsigned int mask_timeout, unsigned int dots, int reveal, size_t max_len, int bell,
+                        time_t deadline, int* timed_out) {
+    struct termios old_term;
+    if (set_term_flags(&old_term, 0, ECHO | ICANON) < 0) {
+        return NULL;
+    }
+
+    size_t size = INITIAL_BUFFER_SIZE;
+    size_t len = 0;
+    char* buf = (char*)malloc(size);
+    if (buf == NULL) {
+        goto error;
+    }
+
+    // Number of `*` or lit dots currently on the screen
+    size_t shown = 0;
+
+    // Whether the character being typed is ignored, since the line is already too long
+    int ignoring = 0;
+    if (dots > 0) {
+        draw_dots(0, dots, 0);
+        reveal = 0;
+    }
+
+    // Whether the last character is shown instead of its `*`
+    int revealed = 0;
+
+    for (;;) {
+
+        // Wait for the next key, hiding the last character after a moment
+        // and collapsing the mask if the user stops typing for a while
+        int timeout = -1;
+        if (revealed) {
+            timeout = REVEAL_TIME_MS;
+        } else if (dots == 0 && mask_timeout > 0 && shown > 1) {
+            timeout = (int)mask_timeout;
+        }
+        int ret = wait_input(STDIN_FILENO, timeout, deadline, timed_out);
+        if (ret < 0 || *timed_out) {
+            goto error;
+        } else if (ret == 0) {
+            if (revealed) {
+                fprintf(stdout, "\b*");
+                revealed = 0;
+                continue;
+            }
+            for (; shown > 1; shown--) {
+                fprintf(stdout, "\b \b");
+            }
+            continue;
+        }
+
+        unsigned char c;
+        ssize_t n;
+        while ((n = read(STDIN_FILENO, &c, 1)) == -1 && errno == EINTR);
+        if (n <= 0) {
+            goto error;
+        }
+
+        // The last character stays on the screen only until the next key
+        int continuation = (c & 0xC0) == 0x80;
+        if (revealed && !continuation) {
+            fprintf(stdout, "\b*");
+            revealed = 0;
+        }
+
+        if (c == '\n' || c == '\r') {
+            break;
+        } else if (c == old_term.c_cc[VERASE] || c == '\b') {
+            if (len > 0) {
+
+                // Erase a whole UTF-8 character: the continuation bytes and the one that started it
+                while (len > 1 && ((unsigned char)buf[len - 1] & 0xC0) == 0x80) {
+                    buf[--len] = 0;
+                }
+                len--;
+                buf[len] = 0;
+                if (dots > 0) {
+                    if (shown > 0) {
+                        shown--;
+                        draw_dots(shown, dots, 1);
+                    }
+                } else if (shown > 1 || (shown == 1 && len == 0)) {
+                    fprintf(stdout, "\b \b");
+                    shown--;
+                }
+            }
+        } else if (c >= ' ' && c != 0x7f) {
+
+            // Past the maximum length, whole characters are ignored: their continuation bytes
+            // are only accepted after a byte that started a character within the limit
+            if (!continuation) {
+                ignoring = len >= max_len;
+                if (ignoring && bell) {
+                    fprintf(stdout, "\a");
+                }
+            }
+            if (ignoring) {
+                continue;
+            }
+
+            if (ensure_capacity(&buf, &size, len + 2) < 0) {
+                goto error;
+            }
+            buf[len++] = c;
+
+            // The continuation bytes of a UTF-8 character do not add anything to the mask,
+            // but complete the character being revealed
+            if (continuation) {
+                if (reveal) {
+                    fputc(c, stdout);
+                }
+                continue;
+            }
+            if (dots > 0) {
+                if (shown < dots) {
+                    shown++;
+                    draw_dots(shown, dots, 1);
+                }
+            } else if (reveal) {
+                fputc(c, stdout);
+                revealed = 1;
+                shown++;
+            } else {
+                fprintf(stdout, "*");
+                shown++;
+            }
+        }
+    }
+
+    buf[len] = 0;
+    tcsetattr(STDIN_FILENO, TCSANOW, &old_term);
+    fprintf(stdout, "\n");
+    return buf;
+
+error:
+    if (revealed) {
+        fprintf(stdout, "\b*");
+    }
+    if (buf != NULL) {
+        memset(buf, 0, size);
+        free(buf);
+    }
+    tcsetattr(STDIN_FILENO, TCSANOW, &old_term);
+    fprintf(stdout, "\n");
+    return NULL;
+}
+
+char* input_read_fd(int fd, size_t max_len, time_t deadline, int* timed_out) {
+    size_t size = INITIAL_BUFFER_SIZE;
+    size_t len = 0;
+    char* buf = (char*)malloc(size);
+    if (buf == NULL) {
+        return NULL;
+    }
+
+    for (;;) {
+        if (wait_input(fd, -1, deadline, timed_out) < 0 || *timed_out) {
+            goto error;
+        }
+
+        char c;
+        ssize_t n;
+        while ((n = read(fd, &c, 1)) == -1 && errno == EINTR);
+        if (n <= 0) {
+            goto error;
+        }
+
+        if (c == '\n') {
+            break;
+        }
+
+        // Whatever is past the maximum length is read and thrown away, up to the end of the line
+        if (len > max_len) {
+            continue;
+        }
+        if (ensure_capacity(&buf, &size, len + 2) < 0) {
+            goto error;
+        }
+        buf[len++] = c;
+    }
+
+    buf[len] = 0;
+    truncate_line(buf, len, max_len);
+    return buf;
+
+error:
+    memset(buf, 0, size);
+    free(buf);
+    return NULL;
+}
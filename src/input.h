@@ -0,0 +1,58 @@
+#ifndef __INPUT_H__
+#define __INPUT_H__
+
+#include <stddef.h>
+#include <time.h>
+
+/**
+ *    Reads a line from stdin, with or without echo.
+ *
+ *    @param  echo      `1` to echo the characters typed, `0` otherwise.
+ *    @param  max_len   Maximum length of the line in bytes: longer lines are cut.
+ *    @param  deadline  Time after which to stop waiting for input, or `0` to wait forever.
+ *    @param  timed_out Set to `1` if the deadline has been reached.
+ *    @return           Newly allocated string containing the line without the trailing newline,
+ *                      or `NULL` in case of error, EOF (even in the middle of a line) or timeout.
+ */
+char* input_read_line(int echo, size_t max_len, time_t deadline, int* timed_out);
+
+/**
+ *    Reads a line from stdin one key at a time, printing a `*` for every character typed.
+ *    The line is returned as typed: the mask affects only what is shown on the screen.
+ *
+ *    With `dots`, a fixed row of dots is shown instead: every key fills the next one,
+ *    up to the end of the row, and every backspace empties one, so that the length of the input
+ *    beyond the number of dots is never shown.
+ *
+ *    With `reveal`, every character is shown as typed, and replaced by its `*` as soon as
+ *    the next key is pressed, or after a moment without key presses.
+ *
+ *    @param  mask_timeout Number of milliseconds without key presses after which the mask
+ *                         is collapsed to a single `*`, so that the length of the input cannot be counted,
+ *                         or `0` to never collapse it. Ignored with `dots`.
+ *    @param  dots         Number of dots to show, or `0` to print a `*` for every character.
+ *    @param  reveal       `1` to briefly show every character before masking it. Ignored with `dots`.
+ *    @param  max_len      Maximum length of the line in bytes: once reached, the keys typed are ignored
+ *                         until some characters are erased.
+ *    @param  bell         `1` to ring the bell for every key ignored, `0` otherwise.
+ *    @param  deadline     Time after which to stop waiting for input, or `0` to wait forever.
+ *    @param  timed_out    Set to `1` if the deadline has been reached.
+ *    @return              Newly allocated string containing the line without the trailing newline,
+ *                         or `NULL` in case of error, EOF or timeout.
+ */
+char* input_read_masked(unsigned int mask_timeout, unsigned int dots, int reveal, size_t max_len, int bell,
+                        time_t deadline, int* timed_out);
+
+/**
+ *    Reads a line from the file descriptor `fd`, which is not a terminal.
+ *
+ *    @param  fd        File descriptor to read from.
+ *    @param  max_len   Maximum length of the line in bytes: longer lines are cut.
+ *    @param  deadline  Time after which to stop waiting for input, or `0` to wait forever.
+ *    @param  timed_out Set to `1` if the deadline has been reached.
+ *    @return           Newly allocated string containing the line without the trailing newline,
+ *                      or `NULL` in case of error, EOF (even in the middle of a line) or timeout.
+ */
+char* input_read_fd(int fd, size_t max_len, time_t deadline, int* timed_out);
+
+#endif
@@ -1,10 +1,22 @@
 #include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
 #include <ctype.h>
+#include <signal.h>
+#include <unistd.h>
+#include <fcntl.h>
 
 #include "lock.h"
+#include "log.h"
+#include "timings.h"
 
-#define SYSRQ_PATH "/proc/sys/kernel/sysrq"
-#define PRINTK_PATH "/proc/sys/kernel/printk"
+#define SYSRQ_HINT "Please, consider running with -s to keep sysrequests enabled."
+#define PRINTK_HINT "Please, consider running with -k to keep kernel messages visible."
+#define PRINTK_WARNING "Warning: kernel messages will not be muted."
+#define SYSRQ_FIELDS 1
+#define PRINTK_FIELDS 4 // Current, default, minimum and boot-time console log levels
+#define MAX_VT_TRIES 5 // How many new vts to try when someone else takes the one we allocated
 
 // This is where we save the state of the system before we try to do anything
 static char old_sysrq[100];
@@ -13,8 +25,15 @@ static FILE* sysrq_file = NULL;
 static FILE* printk_file = NULL;
 static int sysrq_blocked = 0;
 static int printk_blocked = 0;
+static const char* sysrq_path = NULL;
+static const char* printk_path = NULL;
+static int switch_locked = 0;
 static struct vt* old_vt = NULL;
-static struct vt* vt = NULL;
+static struct vt* lock_vt = NULL;
+static int screen_blanked = 0;
+
+// Signals sent when the process crashes
+static const int crash_signals[] = { SIGSEGV, SIGBUS, SIGILL, SIGFPE, SIGABRT };
 
 static int read_int(FILE* stream, char* val, size_t n) {
     for (int i = 0; i < n; i++) {
@@ -26,6 +45,8 @@ static int read_int(FILE* stream, char* val, size_t n) {
         if (isdigit(c)) {
             val[i] = c;
         } else {
+            // Leave the character for whoever reads what follows
+            ungetc(c, stream);
             val[i] = 0;
             return i > 0 ? 0 : -1;
         }
@@ -33,144 +54,502 @@ static int read_int(FILE* stream, char* val, size_t n) {
     return -1; // We tried to read more characted than we are allowed to
 }
 
-struct vt* lock(struct options* options) {
+/**
+ *    Reads up to `fields` integers separated by spaces or tabs, and stores them in `val` separated by single spaces.
+ *    Anything after them is ignored.
+ *
+ *    @return `0` if at least one integer has been read, `-1` otherwise.
+ */
+static int read_fields(FILE* stream, char* val, size_t n, int fields) {
+    size_t len = 0;
+    for (int i = 0; i < fields; i++) {
 
-    // Saves sysrq state, so that later can be restored
-    if (options->block_sysrequests) {
-        sysrq_file = fopen(SYSRQ_PATH, "r+");
-        if (sysrq_file == NULL) {
-            perror("Open " SYSRQ_PATH);
-            fprintf(stderr, "Please, consider running with -s to keep sysrequests enabled.\n");
-            return NULL;
+        // Skip the separators, stopping at the end of the line
+        int c;
+        do {
+            c = fgetc(stream);
+        } while (c == ' ' || c == '\t');
+        if (c == EOF || c == '\n') {
+            break;
         }
-        if (read_int(sysrq_file, old_sysrq, 100) < 0) {
-            perror("read_int " SYSRQ_PATH);
-            fprintf(stderr, "Please, consider running with -s to keep sysrequests enabled.\n");
-            return NULL;
+        ungetc(c, stream);
+
+        // Leave room for the space before the next field
+        if (i > 0) {
+            val[len++] = ' ';
         }
+        if (len >= n || read_int(stream, val + len, n - len) < 0) {
+            if (i == 0) {
+                return -1;
+            }
+
+            // Keep only the fields read in full
+            val[len - 1] = 0;
+            return 0;
+        }
+        len += strlen(val + len);
     }
+    val[len] = 0;
+    return len > 0 ? 0 : -1;
+}
 
-    // Saves the state of the printk, so that later can be restored
-    if (options->block_kernel_messages) {
-        printk_file = fopen(PRINTK_PATH, "r+");
-        if (printk_file == NULL) {
-            perror("Open " PRINTK_PATH);
-            fprintf(stderr, "Please, consider running with -k to keep kernel messages visible.\n");
-            return NULL;
+/**
+ *    Replaces the contents of the file with the given value.
+ *    The file is truncated after writing, so that overridden paths can point to regular files too
+ *    (the files in `/proc` don't support truncation, and they don't need it).
+ */
+static int write_value(FILE* stream, const char* val) {
+    rewind(stream);
+    if (fputs(val, stream) < 0 || fflush(stream) != 0) {
+        return -1;
+    }
+    long pos = ftell(stream);
+    if (pos >= 0) {
+        ftruncate(fileno(stream), pos);
+    }
+    return 0;
+}
+
+/**
+ *    Like `perror`, but includes the path of the file involved and a hint on how to avoid the problem.
+ */
+static void file_error(const char* op, const char* path, const char* hint) {
+    fprintf(stderr, "%s %s: %s\n%s\n", op, path, strerror(errno), hint);
+}
+
+/**
+ *    Opens one of the files in `/proc` controlling the kernel and reads its current value,
+ *    made of up to `fields` integers.
+ */
+static FILE* open_control_file(const char* path, char* val, int fields, const char* hint) {
+    FILE* file = fopen(path, "r+");
+    if (file == NULL) {
+        file_error("Open", path, hint);
+        return NULL;
+    }
+    if (read_fields(file, val, 100, fields) < 0) {
+        file_error("Read", path, hint);
+        fclose(file);
+        return NULL;
+    }
+    return file;
+}
+
+/**
+ *    Returns a closed `struct vt` for the given terminal, to switch to it.
+ */
+static struct vt* closed_vt(unsigned int number) {
+    struct vt* vt = (struct vt*)calloc(1, sizeof(struct vt));
+    if (vt != NULL) {
+        vt->number = number;
+    }
+    return vt;
+}
+
+int lock_check(struct options* options) {
+    int ret = 0;
+    char val[100];
+
+    if (options->block_sysrequests) {
+        FILE* file = open_control_file(options->sysrq_path, val, SYSRQ_FIELDS, SYSRQ_HINT);
+        if (file == NULL) {
+            ret = -1;
+        } else {
+            fclose(file);
         }
-        if (read_int(printk_file, old_printk, 100) < 0) {
-            perror("read_int " PRINTK_PATH);
-            fprintf(stderr, "Please, consider running with -k to keep kernel messages visible.\n");
-            return NULL;
+    }
+    if (options->block_kernel_messages) {
+        FILE* file = open_control_file(options->printk_path, val, PRINTK_FIELDS, PRINTK_HINT);
+        if (file == NULL && options->best_effort) {
+            fprintf(stderr, PRINTK_WARNING "\n");
+        } else if (file == NULL) {
+            ret = -1;
+        } else {
+            fclose(file);
         }
     }
 
-    // Save current vt
-    old_vt = vt_getcurrent();
-    if (old_vt == NULL) {
+    // Without the current terminal, we need to be told where to go back to
+    struct vt* current = vt_getcurrent();
+    if (current == NULL) {
         perror("vt_getcurrent");
-        return NULL;
+        if (options->switch_back_to > 0 || options->return_vt > 0) {
+            fprintf(stderr, "Warning: the terminal given with --switch-back-to or --return-vt will be used when unlocking.\n");
+        } else {
+            ret = -1;
+        }
+    } else {
+        vt_free(current);
     }
 
+    return ret;
+}
+
+/**
+ *    Activates the locked vt.
+ *    Between its allocation and its activation, the vt might have been taken by someone else (e.g. systemd-logind):
+ *    in this case, if the vt was allocated by us, a higher one is allocated and activated instead,
+ *    up to `MAX_VT_TRIES` vts in total.
+ */
+static int switch_to_lock_vt() {
+    for (int tries = 1; ; tries++) {
+        if (vt_switch(lock_vt) == 0) {
+            log_debug("Switched to VT %u after trying %d VT(s)", lock_vt->number, tries);
+            return 0;
+        }
+        if (!lock_vt->allocated || tries == MAX_VT_TRIES) {
+            int err = errno;
+            fprintf(stderr, "Could not switch to the new VT, tried %d VT(s).\n", tries);
+            errno = err;
+            return -1;
+        }
+        log_debug("Could not switch to VT %u (%s), allocating a higher one", lock_vt->number, strerror(errno));
+
+        struct vt* next = vt_createabove(lock_vt->number + 1);
+        if (next == NULL) {
+            int err = errno;
+            fprintf(stderr, "Could not allocate another VT, tried %d VT(s).\n", tries);
+            errno = err;
+            return -1;
+        }
+        vt_free(lock_vt);
+        lock_vt = next;
+    }
+}
+
+/**
+ *    Frees the locked vt and the one to go back to, switching to the latter first if requested.
+ */
+static void release_vts(int switch_back) {
+    if (switch_back && old_vt != NULL) {
+        if (vt_switch(old_vt) < 0) {
+            perror("vt_switch old vt");
+        } else {
+            log_debug("Switched back to VT %u", old_vt->number);
+        }
+    }
+    vt_free(old_vt);
+    vt_free(lock_vt);
+    old_vt = lock_vt = NULL;
+}
+
+/**
+ *    Closes the control files left open by a lock that failed midway,
+ *    and writes back the sysrq and printk values, if they have been changed.
+ */
+static void restore_control_files(struct options* options) {
+    if (sysrq_file != NULL) {
+        fclose(sysrq_file);
+        sysrq_file = NULL;
+    }
+    if (printk_file != NULL) {
+        fclose(printk_file);
+        printk_file = NULL;
+    }
+
+    if (options->block_sysrequests && sysrq_blocked) {
+        sysrq_file = fopen(options->sysrq_path, "r+");
+        if (sysrq_file == NULL) {
+            file_error("Open", options->sysrq_path, SYSRQ_HINT);
+        } else {
+            if (write_value(sysrq_file, old_sysrq) < 0) {
+                file_error("Write", options->sysrq_path, SYSRQ_HINT);
+            } else {
+                log_debug("Restored sysrq value: %s", old_sysrq);
+            }
+            fclose(sysrq_file);
+            sysrq_file = NULL;
+        }
+        sysrq_blocked = 0;
+        sysrq_path = NULL;
+    }
+    if (options->block_kernel_messages && printk_blocked) {
+        printk_file = fopen(options->printk_path, "r+");
+        if (printk_file == NULL) {
+            file_error("Open", options->printk_path, PRINTK_HINT);
+        } else {
+            if (write_value(printk_file, old_printk) < 0) {
+                file_error("Write", options->printk_path, PRINTK_HINT);
+            } else {
+                log_debug("Restored printk value: %s", old_printk);
+            }
+            fclose(printk_file);
+            printk_file = NULL;
+        }
+        printk_blocked = 0;
+        printk_path = NULL;
+    }
+}
+
+struct vt* lock(struct options* options) {
+
     // Create a new vt
-    vt = vt_createnew();
+    timings_start(TIMING_VT_ALLOCATION);
+    struct vt* vt = vt_createnew();
+    timings_stop(TIMING_VT_ALLOCATION);
     if (vt == NULL) {
         perror("vt_createnew");
         return NULL;
     }
 
-    // Block sysrq/printk
+    return lock_with_vt(options, vt);
+}
+
+struct vt* lock_with_vt(struct options* options, struct vt* vt) {
+    int switched = 0;
+
+    // From now on, the vt is ours to release
+    lock_vt = vt;
+
+    // Saves sysrq state, so that later can be restored
+    timings_start(TIMING_SAVE_STATE);
     if (options->block_sysrequests) {
-        rewind(sysrq_file);
-        if (fputs("0", sysrq_file) < 0) {
-            perror("fputs " SYSRQ_PATH);
-            fprintf(stderr, "Please, consider running with -s to keep sysrequests enabled.\n");
-            return NULL;
+        sysrq_file = open_control_file(options->sysrq_path, old_sysrq, SYSRQ_FIELDS, SYSRQ_HINT);
+        if (sysrq_file == NULL) {
+            goto error;
         }
-        fclose(sysrq_file);
-        sysrq_blocked = 1;
+        log_debug("Saved sysrq value: %s", old_sysrq);
     }
+
+    // Saves the state of the printk, so that later can be restored.
+    // Muting kernel messages is the only step the security of the lock does not depend on:
+    // with --best-effort, the station is locked anyway if it fails.
     if (options->block_kernel_messages) {
-        rewind(printk_file);
-        if (fputs("0", printk_file) < 0) {
-            perror("fputs " PRINTK_PATH);
-            fprintf(stderr, "Please, consider running with -k to keep kernel messages visible.\n");
-            return NULL;
+        printk_file = open_control_file(options->printk_path, old_printk, PRINTK_FIELDS, PRINTK_HINT);
+        if (printk_file == NULL) {
+            if (!options->best_effort) {
+                goto error;
+            }
+            fprintf(stderr, PRINTK_WARNING "\n");
+        } else {
+            log_debug("Saved printk value: %s", old_printk);
         }
-        fclose(printk_file);
+    }
+    timings_stop(TIMING_SAVE_STATE);
+
+    // Save the vt to switch back to on unlock: the current one,
+    // unless we are locking it in place and will never switch away from it, or unless told otherwise
+    if (options->switch_back_to > 0) {
+        old_vt = closed_vt(options->switch_back_to);
+        if (old_vt == NULL) {
+            perror("calloc");
+            goto error;
+        }
+    } else if (!options->in_place) {
+        old_vt = vt_getcurrent();
+        if (old_vt == NULL && options->return_vt > 0) {
+            log_debug("Cannot get the current VT (%s), will switch to VT %u on unlock", strerror(errno), options->return_vt);
+            old_vt = closed_vt(options->return_vt);
+        }
+        if (old_vt == NULL) {
+            perror("vt_getcurrent");
+            goto error;
+        }
+    }
+
+    // Block sysrq/printk. Sysrequests might be only partially blocked, keeping the functions in the mask.
+    if (options->block_sysrequests) {
+        char mask[16];
+        snprintf(mask, sizeof(mask), "%u", options->sysrq_mask);
+        sysrq_path = options->sysrq_path;
+        sysrq_blocked = 1;
+        if (write_value(sysrq_file, mask) < 0) {
+            file_error("Write", options->sysrq_path, SYSRQ_HINT);
+            goto error;
+        }
+        fclose(sysrq_file);
+        sysrq_file = NULL;
+    }
+    // Only the current log level is lowered: the default, minimum and boot-time ones are written back as they were.
+    if (options->block_kernel_messages && printk_file != NULL) {
+        char muted[sizeof(old_printk) + 1];
+        const char* others = strchr(old_printk, ' ');
+        snprintf(muted, sizeof(muted), "0%s", others != NULL ? others : "");
+        printk_path = options->printk_path;
         printk_blocked = 1;
+        if (write_value(printk_file, muted) < 0) {
+            file_error("Write", options->printk_path, PRINTK_HINT);
+            if (!options->best_effort) {
+                goto error;
+            }
+            fprintf(stderr, PRINTK_WARNING "\n");
+        }
+        fclose(printk_file);
+        printk_file = NULL;
     }
 
-    // Activate new vt
-    if (vt_switch(vt) < 0) {
-        perror("vt_switch new vt");
-        return NULL;
+    // Activate new vt, which might be replaced by a higher one
+    if (!options->in_place) {
+        timings_start(TIMING_SWITCH);
+        if (switch_to_lock_vt() < 0) {
+            perror("vt_switch new vt");
+            goto error;
+        }
+        timings_stop(TIMING_SWITCH);
+        switched = 1;
+        vt = lock_vt;
+    } else {
+        log_debug("Locking VT %u in place", vt->number);
     }
 
     // Lock vt switching
-    if (options->block_vt_switch && vt_lockswitch(1) < 0) {
-        perror("vt_lockswitch");
-        return NULL;
+    if (options->block_vt_switch) {
+        if (vt_lockswitch(1) < 0) {
+            perror("vt_lockswitch");
+            goto error;
+        }
+        switch_locked = 1;
+    }
+
+    // Hide the cursor, which is shown again only while typing
+    if (options->hide_cursor) {
+        vt_setcursor(vt, 0);
+    }
+
+    // Mute the bell rung by the modules or by anything else writing to the terminal
+    if (options->quiet && vt_mutebell(vt, 1) < 0) {
+        log_debug("Could not mute the bell: %s", strerror(errno));
     }
 
     // Switch the screen off
-    if (options->dark_mode) {
-        vt_blank(vt, 1);
+    timings_start(TIMING_BLANK);
+    if (lock_set_blank(options, 1)) {
+        timings_stop(TIMING_BLANK);
     }
 
     return vt;
-}
 
-void unlock(struct options* options) {
+error:
+    // Undo only the steps that succeeded, so that a failed lock never leaves
+    // sysrq or kernel messages disabled on the real console, nor touches what it did not change
+    release_vts(switched);
+    restore_control_files(options);
+    return NULL;
+}
 
-    // Switch the screen on
-    if (options->dark_mode) {
-        vt_blank(vt, 0);
+int lock_set_blank(struct options* options, int blank) {
+    if (!options->dark_mode || lock_vt == NULL || screen_blanked == blank) {
+        return 0;
+    }
+    if (vt_blank(lock_vt, blank) < 0) {
+        log_debug("Could not %s the screen: %s", blank ? "blank" : "unblank", strerror(errno));
     }
+    screen_blanked = blank;
+    return 1;
+}
 
-    // Re-enable vt switching
-    if (options->block_vt_switch && vt_lockswitch(0) < 0) {
-        perror("vt_lockswitch");
+void lock_enforce(struct options* options) {
+    if (lock_vt == NULL) {
+        return;
     }
 
-    // We switch back to the old vt
-    if (old_vt != NULL && vt_switch(old_vt) < 0) {
-        perror("vt_switch old vt");
+    // The kernel does not tell whether switching is locked, so the lock is simply set again
+    if (options->block_vt_switch && vt_lockswitch(1) < 0) {
+        log_debug("Could not lock terminal switching again: %s", strerror(errno));
     }
-    vt_free(old_vt);
-    vt_free(vt);
-    old_vt = vt = NULL;
 
-    // And now we restore the state of sysrq/printk
-    if (options->block_sysrequests && sysrq_blocked) {
-        sysrq_file = fopen(SYSRQ_PATH, "r+");
-        if (sysrq_file == NULL) {
-            perror("Open " SYSRQ_PATH);
-            fprintf(stderr, "Please, consider running with -s to keep sysrequests enabled.\n");
-        } else if (fputs(old_sysrq, sysrq_file) < 0) {
-            perror("fputs " SYSRQ_PATH);
-            fprintf(stderr, "Please, consider running with -s to keep sysrequests enabled.\n");
+    unsigned int active;
+    unsigned short in_use;
+    if (vt_getstate(&active, &in_use) < 0) {
+        log_debug("Could not get the active terminal: %s", strerror(errno));
+        return;
+    }
+    if (active != lock_vt->number) {
+        log_debug("VT %u became active while locked, switching back to VT %u", active, lock_vt->number);
+        if (vt_switch(lock_vt) < 0) {
+            log_debug("Could not switch back to VT %u: %s", lock_vt->number, strerror(errno));
         }
-        sysrq_blocked = 0;
     }
-    if (options->block_kernel_messages && printk_blocked) {
-        printk_file = fopen(PRINTK_PATH, "r+");
-        if (printk_file == NULL) {
-            perror("Open " PRINTK_PATH);
-            fprintf(stderr, "Please, consider running with -k to keep kernel messages visible.\n");
-        } else if (fputs(old_printk, printk_file) < 0) {
-            perror("fputs " PRINTK_PATH);
-            fprintf(stderr, "Please, consider running with -k to keep kernel messages visible.\n");
+}
+
+/**
+ *    Writes a saved value back to a control file from a signal handler, with async-signal-safe calls only.
+ *    Errors are ignored: there is nobody left to report them to.
+ */
+static void restore_control_file_on_signal(const char* path, const char* val) {
+    int fd;
+    while ((fd = open(path, O_WRONLY | O_TRUNC)) == -1 && errno == EINTR);
+    if (fd == -1) {
+        return;
+    }
+    ssize_t ret;
+    while ((ret = write(fd, val, strlen(val))) == -1 && errno == EINTR);
+    close(fd);
+}
+
+/**
+ *    Restores what the user would need to use the console again, then dies of the same signal:
+ *    the terminal attributes and text mode, vt switching, sysrq and kernel messages.
+ *    After a crash the state of the process cannot be trusted, and only async-signal-safe calls are allowed here.
+ *    The locked vt stays where it is, and nothing is authenticated: a crash must never unlock.
+ */
+static void on_crash(int sig) {
+    signal(sig, SIG_DFL);
+    if (lock_vt != NULL) {
+        vt_restoreonsignal(lock_vt);
+    }
+
+    // Only an ioctl on the console, which is safe here
+    if (switch_locked) {
+        vt_lockswitch(0);
+    }
+    if (sysrq_blocked) {
+        restore_control_file_on_signal(sysrq_path, old_sysrq);
+    }
+    if (printk_blocked) {
+        restore_control_file_on_signal(printk_path, old_printk);
+    }
+    raise(sig);
+}
+
+int lock_handle_crashes(int handle) {
+    struct sigaction action;
+    memset(&action, 0, sizeof(action));
+    action.sa_handler = handle ? on_crash : SIG_DFL;
+    for (int i = 0; i < sizeof(crash_signals) / sizeof(crash_signals[0]); i++) {
+        if (sigaction(crash_signals[i], &action, NULL) < 0) {
+            return -1;
         }
-        printk_blocked = 0;
     }
-    if (sysrq_file != NULL) {
-        fclose(sysrq_file);
-        sysrq_file = NULL;
+    return 0;
+}
+
+int lock_is_blanked() {
+    return screen_blanked;
+}
+
+int lock_vt_number() {
+    return lock_vt != NULL ? (int)lock_vt->number : -1;
+}
+
+int lock_original_vt_number() {
+    return old_vt != NULL ? (int)old_vt->number : -1;
+}
+
+void unlock(struct options* options) {
+
+    // Switch the screen on
+    lock_set_blank(options, 0);
+
+    // Leave the cursor visible for the next user of the terminal, and the bell audible
+    if (options->hide_cursor && lock_vt != NULL) {
+        vt_setcursor(lock_vt, 1);
     }
-    if (printk_file != NULL) {
-        fclose(printk_file);
-        printk_file = NULL;
+    if (lock_vt != NULL && lock_vt->bell_muted) {
+        vt_mutebell(lock_vt, 0);
+    }
+
+    // Re-enable vt switching, if we disabled it
+    if (switch_locked) {
+        if (vt_lockswitch(0) < 0) {
+            perror("vt_lockswitch");
+        }
+        switch_locked = 0;
     }
 
+    // We switch back to the old vt
+    release_vts(1);
+
+    // And now we restore the state of sysrq/printk
+    restore_control_files(options);
 }
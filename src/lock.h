@@ -4,18 +4,101 @@
 #include "options.h"
 #include "vt.h"
 
+/**
+ *    Checks that everything needed to lock the terminal is accessible,
+ *    without changing the state of the system.
+ *    Problems are reported on `stderr`.
+ *    The vt library must have already been initialized with `vt_init`.
+ *
+ *    @param  options SimplyLock options.
+ *    @return         `0` if the terminal can be locked, `-1` otherwise.
+ */
+int lock_check(struct options* options);
+
 /**
  *    Creates a new virtual terminal and locks it down.
  *    Do not `vt_free` the returned vt, but use `unlock` to clean everything up.
+ *    If locking fails midway, the state of the system is restored before returning.
+ *    The vt library must have already been initialized with `vt_init`.
  *
  *    @param  options SimplyLock options.
- *    @return         The new vt created.
+ *    @return         The new vt created, or `NULL` in case of error.
  */
 struct vt* lock(struct options* options);
 
+/**
+ *    Locks down the given virtual terminal instead of creating a new one.
+ *    The ownership of `vt` passes to the lock, even in case of error:
+ *    do not `vt_free` it, but use `unlock` to clean everything up.
+ *    If locking fails midway, the state of the system is restored before returning.
+ *
+ *    @param  options SimplyLock options.
+ *    @param  vt      Open virtual terminal to lock.
+ *    @return         The locked vt, or `NULL` in case of error. This is `vt`, unless it had been allocated
+ *                    by `vt_createnew` and someone else took it before it could be activated:
+ *                    in this case, a higher vt is allocated and locked in its place.
+ */
+struct vt* lock_with_vt(struct options* options, struct vt* vt);
+
+/**
+ *    Switches the screen of the locked terminal off or on in dark mode.
+ *    Nothing is done if the screen is already in the requested state, or if not in dark mode,
+ *    so that the screen never flickers when it is switched on or off more than needed.
+ *
+ *    @param  options SimplyLock options.
+ *    @param  blank   `1` to switch the screen off, `0` to switch it on.
+ *    @return         `1` if the state of the screen has changed, `0` otherwise.
+ */
+int lock_set_blank(struct options* options, int blank);
+
+/**
+ *    Makes sure that the terminal is still locked down, in case another process undid part of the lock:
+ *    blocks terminal switching again, and switches back to the locked terminal if it is not the active one.
+ *    Corrections are logged.
+ *
+ *    @param options SimplyLock options.
+ */
+void lock_enforce(struct options* options);
+
+/**
+ *    Tells whether the screen of the locked terminal is currently switched off by dark mode.
+ *
+ *    @return `1` if the screen is off, `0` otherwise.
+ */
+int lock_is_blanked();
+
+/**
+ *    Returns the number of the locked terminal.
+ *
+ *    @return The number of the terminal, or `-1` if not locked.
+ */
+int lock_vt_number();
+
+/**
+ *    Returns the number of the terminal that will be active again after unlocking:
+ *    the one that was active before locking, or the one given with `--switch-back-to`.
+ *
+ *    @return The number of the terminal, or `-1` if not locked or if no switch will happen
+ *            (the terminal has been locked in place).
+ */
+int lock_original_vt_number();
+
+/**
+ *    Installs or removes the handler that runs if the locking process crashes.
+ *    The handler gives the locked terminal back its attributes and text mode, enables vt switching again
+ *    and writes back the sysrq and printk values saved when locking, then the process dies of the same signal:
+ *    it never unlocks, and the locked terminal stays active until the user switches away from it.
+ *    Every child process must remove the handler, so that its crashes do not touch the terminal.
+ *
+ *    @param  handle `1` to install the handler, `0` to restore the default action of the signals.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int lock_handle_crashes(int handle);
+
 /**
  *    Unlocks the previously locked terminal and restores
  *    the state of the system before the call to `lock`.
+ *    It is safe to call it more than once, or after a failed `lock`.
  *
  *    @param options SimplyLock options.
  */
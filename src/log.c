@@ -0,0 +1,48 @@
+#include <stdio.h>
+#include <stdarg.h>
+#include <unistd.h>
+#include <fcntl.h>
+
+#include "log.h"
+
+static FILE* log_stream = NULL;
+
+int log_init(int enabled) {
+    if (!enabled) {
+        return 0;
+    }
+
+    // Keep our own copy of stderr, which must not leak to the processes we spawn
+    int fd = fcntl(STDERR_FILENO, F_DUPFD_CLOEXEC, 0);
+    if (fd < 0) {
+        return -1;
+    }
+    log_stream = fdopen(fd, "w");
+    if (log_stream == NULL) {
+        close(fd);
+        return -1;
+    }
+    setbuf(log_stream, NULL);
+
+    return 0;
+}
+
+void log_debug(const char* format, ...) {
+    if (log_stream == NULL) {
+        return;
+    }
+
+    va_list args;
+    va_start(args, format);
+    fprintf(log_stream, "simplylock[%d]: ", (int)getpid());
+    vfprintf(log_stream, format, args);
+    fprintf(log_stream, "\n");
+    va_end(args);
+}
+
+void log_end() {
+    if (log_stream != NULL) {
+        fclose(log_stream);
+        log_stream = NULL;
+    }
+}
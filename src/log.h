@@ -0,0 +1,31 @@
+#ifndef __LOG_H__
+#define __LOG_H__
+
+/**
+ *    Initializes the debug log.
+ *
+ *    Log messages are written to the standard error of the process at the time of the call,
+ *    so that they keep going to the terminal SimplyLock has been started from even after
+ *    the standard streams have been redirected to the locked terminal.
+ *
+ *    @param  enabled `1` to enable logging, `0` to discard all the messages.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int log_init(int enabled);
+
+/**
+ *    Writes a debug message to the log, if enabled.
+ *    A newline is automatically appended to the message.
+ *
+ *    Never log passwords or any other input typed by the user.
+ *
+ *    @param format `printf`-like format string.
+ */
+void log_debug(const char* format, ...);
+
+/**
+ *    Closes the debug log.
+ */
+void log_end();
+
+#endif
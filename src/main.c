@@ -1,21 +1,60 @@
 #define _DEFAULT_SOURCE
 #include <stdio.h>
 #include <unistd.h>
+#include <fcntl.h>
 #include <stdlib.h>
 #include <string.h>
 #include <signal.h>
 #include <errno.h>
+#include <limits.h>
+#include <time.h>
 #include <setjmp.h>
 #include <sys/wait.h>
+#include <sys/stat.h>
+#include <sys/file.h>
+#include <sys/select.h>
+#include <poll.h>
+#include <pwd.h>
+#include <locale.h>
 
 #include "options.h"
 #include "vt.h"
 #include "bg.h"
 #include "auth.h"
+#include "emergency.h"
+#include "input.h"
+#include "idle.h"
+#include "title.h"
+#include "selection.h"
 #include "lock.h"
+#include "smartcard.h"
+#include "log.h"
+#include "timings.h"
+#include "i18n.h"
 
-#define HIGHLIGHT "\033[1m\033[34m"
-#define RESET "\033[0m"
+#define FAILED_AUTH_DELAY 3
+#define LOCKOUT_TIME 60
+#define REDRAW_KEY '\f' // Ctrl+L
+#define GREETER_TIMEOUT 2
+#define GREETER_MAX_OUTPUT 4096
+#define WATCHDOG_INTERVAL 5
+#define EMERGENCY_CHECK_INTERVAL 5
+#define SMARTCARD_POLL_INTERVAL 500 // Milliseconds
+
+// What `wait_for_enter` has been waiting for
+#define WAIT_ENTER 0
+#define WAIT_EMERGENCY 1
+#define WAIT_CARD 2
+#define CHECK_DETAIL_SIZE 256
+#define DEFAULT_PATH "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+
+// Exit codes for the different failures, documented in the README.
+// Generic errors exit with 1, and if the locking process is killed by a signal, -D exits with 128 + the signal number.
+#define EXIT_NOT_ROOT 2
+#define EXIT_VT_ALLOCATION 3
+#define EXIT_PAM_SERVICE 4
+#define EXIT_NO_USABLE_USER 5
+#define EXIT_LOCK_TIMEOUT 6
 
 #define REDIRECT_STD_STREAM(s, f, mode) \
     do { \
@@ -34,111 +73,1831 @@
         } \
     } while (0)
 
-static int user_selection_enabled = 0;
-static sigjmp_buf user_selection_jmp;
+static int user_selection_enabled = 0;
+static sigjmp_buf user_selection_jmp;
+static int rescue_shell_enabled = 0;
+static sigjmp_buf rescue_shell_jmp;
+static volatile sig_atomic_t resized = 0;
+static volatile sig_atomic_t emergency_unlock = 0;
+static pid_t emergency_watcher = -1;
+static void* smartcard = NULL;
+static int card_present = -1; // Unknown until the readers are first polled
+static int pidfile_fd = -1;
+static unsigned int failed_attempts = 0;
+static unsigned int total_failed_attempts = 0;
+static time_t locked_since = 0;
+static time_t max_lock_deadline = 0;
+static time_t greeter_deadline = 0;
+static time_t watchdog_deadline = 0;
+static int status_fd = -1;
+static int seats_locked = 0;
+
+/**
+ *    Message sent by the locking process to its parent, once it knows whether the station has been locked.
+ */
+struct lock_status {
+    unsigned char locked;    // `1` if the station has been locked, `0` otherwise
+    unsigned char exit_code; // Exit code of the locking process, if locking failed
+    unsigned int vt;         // Number of the locked terminal, if locking succeeded
+};
+
+static void on_sigint(int sig) {
+    if (user_selection_enabled) {
+        siglongjmp(user_selection_jmp, 1);
+    }
+}
+
+static void on_sigquit(int sig) {
+    if (rescue_shell_enabled) {
+        siglongjmp(rescue_shell_jmp, 1);
+    }
+}
+
+static void on_sigwinch(int sig) {
+    resized = 1;
+}
+
+static void on_sigusr1(int sig) {
+    emergency_unlock = 1;
+}
+
+static inline int register_signal(int sig, void (*handler)(int)) {
+    struct sigaction action;
+    memset(&action, 0, sizeof(action));
+    action.sa_handler = handler;
+    return sigaction(sig, &action, NULL);
+}
+
+/**
+ *    Clears the screen before drawing on it.
+ *    With `--no-clear`, nothing is cleared, or only what is below `--no-clear-row`.
+ */
+static void clear_screen(struct options* options, struct vt* vt) {
+    if (options->no_clear) {
+        vt_clearbelow(vt, options->no_clear_row);
+    } else {
+        vt_clear(vt);
+    }
+}
+
+/**
+ *    Asks for the name of the user that wants to unlock, and checks that they are allowed to.
+ *
+ *    @return The name typed, to be freed by the caller, or `NULL` if no allowed user has been entered.
+ */
+static char* ask_username(struct options* options) {
+    int timed_out = 0;
+    time_t deadline = options->prompt_timeout > 0 ? time(NULL) + options->prompt_timeout : 0;
+
+    fprintf(stdout, "%s", _("Username: "));
+    char* name = input_read_line(1, options->max_input, deadline, &timed_out);
+    if (name == NULL || *name == 0) {
+        free(name);
+        return NULL;
+    }
+
+    if (!options_has_user(options, name)) {
+        fprintf(stdout, "\n%s", options->theme.error);
+        fprintf(stdout, _("User %s is not allowed to unlock."), name);
+        fprintf(stdout, RESET "\n");
+        free(name);
+        sleep(options->fail_delay);
+        return NULL;
+    }
+
+    return name;
+}
+
+static int user_selection(struct options* options, struct vt* vt, void* bg, char** user) {
+    int index;
+
+    // With --hide-users, the name has to be typed instead of chosen from the list
+    if (options->hide_users) {
+        vt_flush(vt);
+        clear_screen(options, vt);
+        lock_set_blank(options, 0);
+        if (bg != NULL) {
+            bg_paint(bg);
+        }
+        fprintf(stdout, "\n");
+        char* name = ask_username(options);
+        for (int i = 0; name != NULL && i < options->users_size; i++) {
+            if (strcmp(options->users[i], name) == 0) {
+                *user = options->users[i];
+            }
+        }
+        free(name);
+        return 0;
+    }
+
+    // With few enough users, a single key press is enough to choose one
+    int quick_select = options->users_size <= 9;
+
+    do {
+
+        vt_flush(vt);
+        clear_screen(options, vt);
+        
+        // Switch on the screen if in dark mode
+        lock_set_blank(options, 0);
+
+        // Background
+        if (bg != NULL) {
+            bg_paint(bg);
+        }
+
+        // Users list
+        selection_print_users(options, vt, *user);
+
+        // Wait for user selection
+        if (quick_select) {
+            fprintf(stdout, "\n%s", _("Press the number of the user that wants to unlock: "));
+            index = selection_read_key(vt);
+        } else {
+            fprintf(stdout, "\n%s", _("Insert the number of the user that wants to unlock and press enter: "));
+            index = selection_read_line(vt);
+        }
+        if (index == -1) {
+            return -1;
+        }
+
+    } while (index >= options->users_size);
+
+    *user = options->users[index];
+
+    return 0;
+}
+
+/**
+ *    Tells the parent process whether the station has been locked.
+ *    Only the first call has any effect.
+ */
+static void send_lock_status(int locked, int exit_code, unsigned int vt) {
+    if (status_fd < 0) {
+        return;
+    }
+
+    struct lock_status status;
+    memset(&status, 0, sizeof(status));
+    status.locked = locked;
+    status.exit_code = exit_code;
+    status.vt = vt;
+    while (write(status_fd, &status, sizeof(status)) == -1 && errno == EINTR);
+
+    close(status_fd);
+    status_fd = -1;
+}
+
+/**
+ *    Waits for the locking process to terminate.
+ *
+ *    @return Exit code for SimplyLock: the exit code of the child,
+ *            or 128 + the number of the signal that killed it.
+ */
+static int wait_child(pid_t childpid) {
+    int status;
+    pid_t wpid;
+    while ((wpid = waitpid(childpid, &status, 0)) == -1 && errno == EINTR);
+    if (wpid == -1) {
+        perror("waitpid");
+        return 1;
+    } else if (WIFSIGNALED(status)) {
+        fprintf(stderr, "The locking process has been killed by signal %d (%s).\n", WTERMSIG(status), strsignal(WTERMSIG(status)));
+        return 128 + WTERMSIG(status);
+    } else if (WIFEXITED(status)) {
+        return WEXITSTATUS(status);
+    }
+    return 1;
+}
+
+/**
+ *    Tells whether the last `--step-up-after` unlocks all happened within `--step-up-window` seconds,
+ *    so that the next one needs the `--step-up-service`.
+ *    `unlock_times` is a ring buffer of `--step-up-after` entries, filled with the times of the last `unlocks`.
+ */
+static int step_up_needed(struct options* options, const time_t* unlock_times, unsigned int unlocks) {
+    if (options->step_up_service == NULL || unlocks < options->step_up_after) {
+        return 0;
+    }
+
+    // The oldest of the last unlocks is the next entry to be overwritten
+    time_t oldest = unlock_times[unlocks % options->step_up_after];
+    return time(NULL) - oldest <= options->step_up_window;
+}
+
+/**
+ *    Restores the default handlers and mask of the signals we touch.
+ *    To be called in a child process before running another program.
+ */
+static void restore_default_signals() {
+    lock_handle_crashes(0);
+    register_signal(SIGINT, SIG_DFL);
+    register_signal(SIGQUIT, SIG_DFL);
+    register_signal(SIGTERM, SIG_DFL);
+    register_signal(SIGTSTP, SIG_DFL);
+    register_signal(SIGWINCH, SIG_DFL);
+    register_signal(SIGUSR1, SIG_DFL);
+    sigset_t mask;
+    sigemptyset(&mask);
+    sigprocmask(SIG_SETMASK, &mask, NULL);
+}
+
+/**
+ *    Starts the process that watches `--emergency-file` for a token signed with the `--emergency-key`,
+ *    and asks the locking process to unlock with SIGUSR1 when a valid one appears.
+ *    The token is removed, so that it cannot unlock again. The watcher exits when the locking process is gone.
+ */
+static void start_emergency_watcher(struct options* options) {
+    pid_t lockpid = getpid();
+    emergency_watcher = fork();
+    if (emergency_watcher == -1) {
+        perror("fork");
+        return;
+    } else if (emergency_watcher > 0) {
+        log_debug("Emergency unlock watcher started with PID %d", (int)emergency_watcher);
+        return;
+    }
+
+    // A crash of the watcher must not touch the locked terminal
+    lock_handle_crashes(0);
+
+    // Nor must the watcher keep the pidfile locked once the locking process is gone
+    if (pidfile_fd >= 0) {
+        close(pidfile_fd);
+    }
+
+    while (getppid() == lockpid) {
+        if (emergency_check_token(options)) {
+            if (unlink(options->emergency_file) < 0) {
+                log_debug("Cannot remove the emergency unlock token: %s", strerror(errno));
+            }
+            log_debug("Valid emergency unlock token found");
+            kill(lockpid, SIGUSR1);
+            break;
+        }
+        sleep(EMERGENCY_CHECK_INTERVAL);
+    }
+    _exit(0);
+}
+
+/**
+ *    Stops the process started by `start_emergency_watcher`, if any.
+ */
+static void stop_emergency_watcher() {
+    if (emergency_watcher > 0) {
+        kill(emergency_watcher, SIGKILL);
+        while (waitpid(emergency_watcher, NULL, 0) == -1 && errno == EINTR);
+        emergency_watcher = -1;
+    }
+}
+
+/**
+ *    Tells why an existing `--pidfile` cannot be used: another simplylock holds its lock,
+ *    or it has been left behind by one that is gone. The file is only read.
+ */
+static void report_existing_pidfile(struct options* options) {
+    int fd = open(options->pidfile, O_RDONLY | O_NOFOLLOW | O_CLOEXEC);
+    int ret = -1;
+    if (fd >= 0) {
+        while ((ret = flock(fd, LOCK_SH | LOCK_NB)) == -1 && errno == EINTR);
+    }
+    if (ret < 0 && errno == EWOULDBLOCK) {
+        char pid[32] = "";
+        ssize_t n;
+        while ((n = pread(fd, pid, sizeof(pid) - 1, 0)) == -1 && errno == EINTR);
+        pid[n > 0 ? n : 0] = 0;
+        pid[strcspn(pid, "\n")] = 0;
+        fprintf(stderr, "Another simplylock (PID %s) is using %s.\n", *pid != 0 ? pid : "unknown", options->pidfile);
+    } else {
+        fprintf(stderr, "%s already exists. If no simplylock is running, it has been left behind: remove it.\n", options->pidfile);
+    }
+    if (fd >= 0) {
+        close(fd);
+    }
+}
+
+/**
+ *    Opens and locks the `--pidfile`, so that two instances never use the same file.
+ *    The file must not exist: it is created here, and it is the only one ever written or removed.
+ *    The lock is inherited by the locking process, and is released when it exits.
+ *    Problems are reported on `stderr`.
+ *
+ *    @return `0` in case of success, `-1` otherwise.
+ */
+static int open_pidfile(struct options* options) {
+
+    // The file is always created by us, so that we never truncate or remove a file we did not create
+    pidfile_fd = open(options->pidfile, O_RDWR | O_CREAT | O_EXCL | O_NOFOLLOW | O_CLOEXEC, 0600);
+    if (pidfile_fd < 0 && errno == EEXIST) {
+        report_existing_pidfile(options);
+        return -1;
+    } else if (pidfile_fd < 0) {
+        fprintf(stderr, "Cannot create %s: %s\n", options->pidfile, strerror(errno));
+        return -1;
+    }
+
+    int ret;
+    while ((ret = flock(pidfile_fd, LOCK_EX | LOCK_NB)) == -1 && errno == EINTR);
+    if (ret < 0) {
+        fprintf(stderr, "Cannot lock %s: %s\n", options->pidfile, strerror(errno));
+        unlink(options->pidfile);
+        close(pidfile_fd);
+        pidfile_fd = -1;
+        return -1;
+    }
+    return 0;
+}
+
+/**
+ *    Writes the PID of this process and the number of the locked terminal to the `--pidfile`.
+ */
+static void write_pidfile(unsigned int vt) {
+    if (pidfile_fd < 0) {
+        return;
+    }
+    char buf[64];
+    int len = snprintf(buf, sizeof(buf), "%d\n%u\n", (int)getpid(), vt);
+    if (ftruncate(pidfile_fd, 0) < 0 || pwrite(pidfile_fd, buf, len, 0) != len) {
+        log_debug("Cannot write the pidfile: %s", strerror(errno));
+    }
+}
+
+/**
+ *    Removes the `--pidfile`, and releases its lock.
+ */
+static void remove_pidfile(struct options* options) {
+    if (pidfile_fd < 0) {
+        return;
+    }
+
+    // Removed while still locked, so that nobody else can have started using it in the meanwhile
+    unlink(options->pidfile);
+    close(pidfile_fd);
+    pidfile_fd = -1;
+}
+
+/**
+ *    Starts a shell command in background, detached from the terminal,
+ *    so that it keeps running after unlocking and never writes anything on the screen.
+ *    `env` must contain at least the `PATH`.
+ */
+static void run_in_background(const char* command, char* const env[]) {
+    pid_t childpid = fork();
+    if (childpid == 0) {
+        restore_default_signals();
+
+        // The command is orphaned by the intermediate child, so that nobody has to wait for it
+        if (fork() != 0) {
+            _exit(0);
+        }
+        setsid();
+        int null_fd = open("/dev/null", O_RDWR);
+        if (null_fd < 0 || dup2(null_fd, STDIN_FILENO) < 0 || dup2(null_fd, STDOUT_FILENO) < 0 || dup2(null_fd, STDERR_FILENO) < 0) {
+            _exit(127);
+        }
+        execle("/bin/sh", "sh", "-c", command, (char*)NULL, env);
+        _exit(127);
+    } else if (childpid > 0) {
+        while (waitpid(childpid, NULL, 0) == -1 && errno == EINTR);
+    }
+}
+
+/**
+ *    Starts the command given with `--duress-command`.
+ */
+static void run_duress_command(struct options* options) {
+    char* env[] = { "PATH=" DEFAULT_PATH, NULL };
+    run_in_background(options->duress_command, env);
+}
+
+/**
+ *    Starts the command given with `--on-fail`, telling it which attempt failed and for which user.
+ *    `user` is `NULL` if PAM asked for the user name itself.
+ */
+static void run_fail_command(struct options* options, unsigned int attempt, const char* user) {
+    char attempt_env[32];
+    char user_env[32 + LOGIN_NAME_MAX];
+    snprintf(attempt_env, sizeof(attempt_env), "SIMPLYLOCK_ATTEMPT=%u", attempt);
+    snprintf(user_env, sizeof(user_env), "SIMPLYLOCK_USER=%s", user != NULL ? user : "");
+    char* env[] = { "PATH=" DEFAULT_PATH, attempt_env, user_env, NULL };
+    log_debug("Running the --on-fail command for attempt %u", attempt);
+    run_in_background(options->on_fail_command, env);
+}
+
+/**
+ *    Counts a failed attempt, telling the user how many are left if a maximum number of attempts has been set,
+ *    and disabling unlocking for a while when they are over. Then waits before the next attempt.
+ */
+static void count_failed_attempt(struct options* options, struct vt* vt) {
+
+    // Make some noise if requested
+    if (options->bell) {
+        vt_bell(vt);
+    }
+    if (options->flash) {
+        vt_flash(vt);
+    }
+
+    // A custom message, the last attempt and the lockout are highlighted
+    failed_attempts++;
+    total_failed_attempts++;
+    int lockout = options->max_attempts > 0 && failed_attempts >= options->max_attempts;
+    unsigned int remaining = lockout ? 0 : options->max_attempts - failed_attempts;
+    int highlight = options->fail_message != NULL || lockout || (options->max_attempts > 0 && remaining == 1);
+    fprintf(stdout, "\n%s%s", highlight ? options->theme.error : "",
+        options->fail_message != NULL ? options->fail_message : _("Authentication failed."));
+    if (lockout) {
+        fprintf(stdout, " ");
+        fprintf(stdout, _("Too many failed attempts: unlocking is disabled for %d seconds."), LOCKOUT_TIME);
+    } else if (options->max_attempts > 0) {
+        fprintf(stdout, " ");
+        fprintf(stdout, ngettext("%u attempt remaining.", "%u attempts remaining.", remaining), remaining);
+    }
+    fprintf(stdout, RESET "\n");
+
+    if (lockout) {
+        sleep(LOCKOUT_TIME);
+        failed_attempts = 0;
+    } else {
+        sleep(options->fail_delay);
+    }
+}
+
+/**
+ *    Asks for the root password and, if correct, starts a root shell on the locked terminal.
+ *    When the shell exits, the terminal is locked again.
+ *    Root always authenticates with the default PAM service, and wrong passwords count
+ *    as failed attempts, exactly like the ones made to unlock.
+ */
+static void rescue_shell(struct options* options, struct vt* vt, void* bg) {
+    vt_flush(vt);
+    clear_screen(options, vt);
+
+    lock_set_blank(options, 0);
+    if (bg != NULL) {
+        bg_paint(bg);
+    }
+
+    // The cursor stays visible for the shell too
+    if (options->hide_cursor) {
+        vt_setcursor(vt, 1);
+    }
+
+    char root[THEME_STYLE_SIZE + 16];
+    snprintf(root, sizeof(root), "%sroot" RESET, options->theme.user);
+    fprintf(stdout, "\n");
+    fprintf(stdout, _("Rescue shell: authenticate as %s."), root);
+    fprintf(stdout, "\n\n");
+    // The duress password never opens the shell, but its command still has to run,
+    // and to an onlooker it must look like a wrong password
+    options->rescue_active = 1;
+    auth_result_t auth_result = auth_authenticate_user(options, "root");
+    options->rescue_active = 0;
+    if (auth_result == AUTH_DURESS) {
+        run_duress_command(options);
+        auth_result = AUTH_FAILURE;
+    }
+    if (auth_result == AUTH_FAILURE) {
+        if (options->on_fail_command != NULL) {
+            run_fail_command(options, total_failed_attempts + 1, "root");
+        }
+        count_failed_attempt(options, vt);
+    } else if (auth_result != AUTH_SUCCESS) {
+        fprintf(stdout, "\n%s\n", _("Authentication failed."));
+        sleep(options->fail_delay);
+    }
+    if (auth_result != AUTH_SUCCESS) {
+        if (options->hide_cursor) {
+            vt_setcursor(vt, 0);
+        }
+        return;
+    }
+
+    struct passwd* passwd = getpwnam("root");
+    const char* shell = passwd != NULL && passwd->pw_shell != NULL && *passwd->pw_shell != 0 ? passwd->pw_shell : "/bin/sh";
+
+    vt_clear(vt);
+    vt_setsane(vt, 1);
+
+    pid_t childpid = fork();
+    if (childpid == 0) {
+
+        // Give the shell a clean state: default signal handlers and a minimal environment
+        restore_default_signals();
+        setenv("HOME", "/root", 1);
+        setenv("SHELL", shell, 1);
+        setenv("TERM", "linux", 1);
+        setenv("PATH", DEFAULT_PATH, 1);
+
+        execl(shell, shell, (char*)NULL);
+        perror("execl");
+        _exit(127);
+
+    } else if (childpid == -1) {
+        perror("fork");
+        sleep(FAILED_AUTH_DELAY);
+    } else {
+        while (waitpid(childpid, NULL, 0) == -1 && errno == EINTR);
+    }
+
+    vt_setsane(vt, 0);
+    if (options->hide_cursor) {
+        vt_setcursor(vt, 0);
+    }
+}
+
+/**
+ *    Number of characters (not bytes) in the first `len` bytes of the UTF-8 string `str`.
+ */
+static int text_width(const char* str, size_t len) {
+    int width = 0;
+    for (size_t i = 0; i < len && str[i] != 0; i++) {
+        if ((str[i] & 0xC0) != 0x80) {
+            width++;
+        }
+    }
+    return width;
+}
+
+/**
+ *    Expands the placeholders in `template`:
+ *    - %H: host name.
+ *    - %u: user that will unlock.
+ *    - %n: number of the current attempt.
+ *    - %t: current time.
+ *    - %%: a literal %.
+ *    Unknown placeholders are left as they are.
+ *
+ *    Returns a newly allocated string, or `NULL` if out of memory.
+ */
+static char* expand_template(const char* template, const char* user) {
+    char* out = NULL;
+    size_t out_size = 0;
+    FILE* stream = open_memstream(&out, &out_size);
+    if (stream == NULL) {
+        return NULL;
+    }
+
+    for (const char* c = template; *c != 0; c++) {
+
+        // Control characters are dropped: escape sequences could move the cursor and break the layout
+        if ((unsigned char)*c < ' ' && *c != '\n') {
+            continue;
+        } else if (*c == 0x7f) {
+            continue;
+        } else if (*c != '%') {
+            fputc(*c, stream);
+            continue;
+        }
+        switch (c[1]) {
+            case 'H': {
+                char hostname[HOST_NAME_MAX + 1];
+                if (gethostname(hostname, sizeof(hostname)) == 0) {
+                    hostname[HOST_NAME_MAX] = 0;
+                    fputs(hostname, stream);
+                }
+                c++;
+                break;
+            }
+            case 'u':
+                fputs(user != NULL ? user : "", stream);
+                c++;
+                break;
+            case 'n':
+                fprintf(stream, "%u", failed_attempts + 1);
+                c++;
+                break;
+            case 't': {
+                char buf[16];
+                time_t now = time(NULL);
+                struct tm tm;
+                if (localtime_r(&now, &tm) != NULL && strftime(buf, sizeof(buf), "%H:%M", &tm) > 0) {
+                    fputs(buf, stream);
+                }
+                c++;
+                break;
+            }
+            case '%':
+                fputc('%', stream);
+                c++;
+                break;
+            default:
+                fputc('%', stream);
+                break;
+        }
+    }
+
+    fclose(stream);
+    return out;
+}
+
+/**
+ *    Runs the --greeter-command and returns what it printed on stdout,
+ *    without control characters and without the trailing newlines.
+ *    The command is killed if it does not complete within `GREETER_TIMEOUT` seconds,
+ *    and only the first `GREETER_MAX_OUTPUT` bytes are kept.
+ *
+ *    Returns a newly allocated string, or `NULL` in case of error.
+ */
+static char* run_greeter(struct options* options) {
+    int fds[2];
+    if (pipe(fds) < 0) {
+        log_debug("Cannot create the pipe for the greeter: %s", strerror(errno));
+        return NULL;
+    }
+    fcntl(fds[0], F_SETFD, FD_CLOEXEC);
+    fcntl(fds[1], F_SETFD, FD_CLOEXEC);
+
+    pid_t childpid = fork();
+    if (childpid == 0) {
+
+        // Only stdout goes back to us: stdin and stderr are /dev/null
+        restore_default_signals();
+        int null_fd = open("/dev/null", O_RDWR);
+        if (null_fd < 0 || dup2(null_fd, STDIN_FILENO) < 0 || dup2(fds[1], STDOUT_FILENO) < 0 || dup2(null_fd, STDERR_FILENO) < 0) {
+            _exit(127);
+        }
+        char* env[] = { "PATH=" DEFAULT_PATH, NULL };
+        execle("/bin/sh", "sh", "-c", options->greeter_command, (char*)NULL, env);
+        _exit(127);
+
+    } else if (childpid == -1) {
+        log_debug("Cannot fork the greeter: %s", strerror(errno));
+        close(fds[0]);
+        close(fds[1]);
+        return NULL;
+    }
+    close(fds[1]);
+
+    char* out = (char*)malloc(GREETER_MAX_OUTPUT + 1);
+    size_t len = 0;
+    time_t deadline = time(NULL) + GREETER_TIMEOUT;
+    struct pollfd pfd = { fds[0], POLLIN, 0 };
+    while (out != NULL) {
+        time_t now = time(NULL);
+        if (now >= deadline) {
+            log_debug("The greeter did not complete in %d seconds", GREETER_TIMEOUT);
+            break;
+        }
+        int ret = poll(&pfd, 1, (deadline - now) * 1000);
+        if (ret == 0 || (ret < 0 && errno == EINTR)) {
+            continue;
+        } else if (ret < 0) {
+            break;
+        }
+
+        char buf[256];
+        ssize_t n;
+        while ((n = read(fds[0], buf, sizeof(buf))) == -1 && errno == EINTR);
+        if (n <= 0) {
+            break;
+        }
+        for (ssize_t i = 0; i < n && len < GREETER_MAX_OUTPUT; i++) {
+            if (((unsigned char)buf[i] >= ' ' || buf[i] == '\n') && buf[i] != 0x7f) {
+                out[len++] = buf[i];
+            }
+        }
+    }
+    close(fds[0]);
+
+    // The command might still be running if it timed out, or if it closed its stdout early
+    kill(childpid, SIGKILL);
+    while (waitpid(childpid, NULL, 0) == -1 && errno == EINTR);
+
+    if (out == NULL) {
+        return NULL;
+    }
+    while (len > 0 && out[len - 1] == '\n') {
+        len--;
+    }
+    out[len] = 0;
+    return out;
+}
+
+/**
+ *    Returns the output of the --greeter-command, running it again if it has never been run
+ *    or if it is older than --greeter-interval.
+ *    The returned string is owned by this function.
+ */
+static const char* greeter_output(struct options* options) {
+    static char* output = NULL;
+    static int ran = 0;
+    if (!ran || (options->greeter_interval > 0 && time(NULL) >= greeter_deadline)) {
+        free(output);
+        output = run_greeter(options);
+        ran = 1;
+        greeter_deadline = time(NULL) + options->greeter_interval;
+    }
+    return output;
+}
+
+/**
+ *    Builds the text shown above the prompt: the message and, if requested, the lock statistics.
+ *    Returns a newly allocated string, or `NULL` if there is nothing to show.
+ */
+static char* build_message(struct options* options, const char* user) {
+    char* message = NULL;
+    if (options->greeter_command != NULL) {
+        const char* output = greeter_output(options);
+        if (output != NULL && *output != 0) {
+            message = strdup(output);
+        }
+    } else if (options->message != NULL) {
+        message = expand_template(options->message, options->hide_users ? NULL : user);
+    }
+    if (!options->show_last) {
+        return message;
+    }
+
+    char* out = NULL;
+    size_t out_size = 0;
+    FILE* stream = open_memstream(&out, &out_size);
+    if (stream == NULL) {
+        return message;
+    }
+
+    if (message != NULL) {
+        fprintf(stream, "%s\n\n", message);
+        free(message);
+    }
+
+    char buf[32];
+    struct tm tm;
+    if (localtime_r(&locked_since, &tm) != NULL && strftime(buf, sizeof(buf), "%Y-%m-%d %H:%M", &tm) > 0) {
+        fprintf(stream, _("Locked since %s."), buf);
+    }
+    if (total_failed_attempts > 0) {
+        fprintf(stream, " ");
+        fprintf(stream, ngettext("%u failed attempt since then.", "%u failed attempts since then.", total_failed_attempts),
+            total_failed_attempts);
+    }
+
+    fclose(stream);
+    return out;
+}
+
+/**
+ *    Prints a line of the message, translating the markup if enabled with --markup:
+ *    `*bold*`, `_underline_`, `{color}colored{/}`, and `\` to escape any of `\*_{`.
+ *    Styles never carry over to the next line.
+ *    If `out` is `NULL`, nothing is printed and the line is only measured.
+ *
+ *    @return The number of characters the line takes on the screen.
+ */
+static int print_message_line(struct options* options, const char* line, size_t len, FILE* out) {
+    if (!options->markup) {
+        if (out != NULL) {
+            fprintf(out, "%.*s", (int)len, line);
+        }
+        return text_width(line, len);
+    }
+
+    int width = 0;
+    int bold = 0;
+    int underline = 0;
+    for (size_t i = 0; i < len; i++) {
+        char c = line[i];
+        if (c == '\\' && i + 1 < len && strchr("\\*_{", line[i + 1]) != NULL) {
+            c = line[++i];
+        } else if (c == '*') {
+            bold = !bold;
+            if (out != NULL) {
+                fputs(bold ? "\033[1m" : "\033[22m", out);
+            }
+            continue;
+        } else if (c == '_') {
+            underline = !underline;
+            if (out != NULL) {
+                fputs(underline ? "\033[4m" : "\033[24m", out);
+            }
+            continue;
+        } else if (c == '{') {
+            // Unknown colors are printed as they are
+            const char* close = memchr(line + i, '}', len - i);
+            char name[THEME_STYLE_SIZE];
+            char style[THEME_STYLE_SIZE];
+            size_t name_len = close != NULL ? (size_t)(close - line) - i - 1 : 0;
+            if (close != NULL && name_len < sizeof(name)) {
+                memcpy(name, line + i + 1, name_len);
+                name[name_len] = 0;
+                if (strcmp(name, "/") == 0) {
+                    strcpy(style, "\033[39m");
+                } else if (options_parse_color(name, 0, style) < 0) {
+                    style[0] = 0;
+                    name_len = 0;
+                }
+                if (name_len > 0) {
+                    if (out != NULL) {
+                        fputs(style, out);
+                    }
+                    i += name_len + 1;
+                    continue;
+                }
+            }
+        }
+
+        if (out != NULL) {
+            fputc(c, out);
+        }
+        if ((c & 0xC0) != 0x80) {
+            width++;
+        }
+    }
+
+    if (out != NULL) {
+        fputs(RESET, out);
+    }
+    return width;
+}
+
+/**
+ *    Prints `text` in the given style, replacing its `%s` with `user` in the style of the user names.
+ *    If `out` is `NULL`, nothing is printed and the text is only measured.
+ *
+ *    @return The number of characters the text takes on the screen.
+ */
+static int print_with_user(struct options* options, const char* text, const char* style, const char* user, FILE* out) {
+    const char* placeholder = strstr(text, "%s");
+    if (placeholder == NULL) {
+        if (out != NULL) {
+            fprintf(out, "%s%s" RESET, style, text);
+        }
+        return text_width(text, strlen(text));
+    }
+
+    if (out != NULL) {
+        fprintf(out, "%s%.*s" RESET "%s%s" RESET "%s%s" RESET,
+            style, (int)(placeholder - text), text, options->theme.user, user, style, placeholder + 2);
+    }
+    return text_width(text, strlen(text)) - 2 + text_width(user, strlen(user));
+}
+
+/**
+ *    Prints the prompt asking to press enter to `out`, or just measures it if `out` is `NULL`.
+ *    The hint to change user is shown only if there is another user to change to.
+ *
+ *    @return The width of the prompt, in characters.
+ */
+static int print_prompt(struct options* options, const char* user, FILE* out) {
+    const char* style = options->theme.prompt;
+    int width;
+
+    if (options->prompt != NULL) {
+        if (options->prompt[0] == 0) {
+            return 0;
+        }
+        char* prompt = expand_template(options->prompt, options->hide_users ? NULL : user);
+        if (prompt == NULL) {
+            return 0;
+        }
+        width = text_width(prompt, strlen(prompt));
+        if (out != NULL) {
+            fprintf(out, "%s%s" RESET, style, prompt);
+        }
+        free(prompt);
+    } else if (user == NULL || options->hide_users) {
+        width = print_with_user(options, _("Press enter to unlock."), style, "", out);
+    } else {
+        width = print_with_user(options, _("Press enter to unlock as %s."), style, user, out);
+    }
+
+    if (user != NULL && options->users_size > 1) {
+        const char* hint = _("[Press Ctrl+C to change user]");
+        if (out != NULL) {
+            fprintf(out, " %s%s" RESET, style, hint);
+        }
+        width += 1 + text_width(hint, strlen(hint));
+    }
+
+    if (out != NULL) {
+        fprintf(out, " ");
+    }
+    return width + 1;
+}
+
+static void repeat(const char* str, int times) {
+    for (int i = 0; i < times; i++) {
+        fputs(str, stdout);
+    }
+}
+
+/**
+ *    Draws the `--title` at the top of the screen, in block letters if it fits in the width of the screen.
+ */
+static void print_title(struct options* options, struct vt* vt) {
+    unsigned int rows, cols;
+    if (vt_getsize(vt, &rows, &cols) < 0) {
+        cols = 80;
+    }
+
+    fprintf(stdout, "\n%s", options->theme.user);
+    int width = title_width(options->title);
+    if (width >= 0 && width <= (int)cols) {
+        for (int row = 0; row < TITLE_HEIGHT; row++) {
+            repeat(" ", ((int)cols - width) / 2);
+            title_print_row(options->title, row, stdout);
+            fprintf(stdout, "\n");
+        }
+    } else {
+        // Too wide, or with characters missing from the font: plain text will do
+        int len = (int)strlen(options->title);
+        repeat(" ", (int)cols > len ? ((int)cols - len) / 2 : 0);
+        fprintf(stdout, "%s\n", options->title);
+    }
+    fprintf(stdout, RESET);
+}
+
+/**
+ *    Draws the message and the prompt in the middle of the screen, optionally inside a box.
+ *    The cursor is left at the beginning of the line below.
+ */
+static void repaint_centered(struct options* options, struct vt* vt, const char* message, const char* user) {
+    unsigned int rows, cols;
+    if (vt_getsize(vt, &rows, &cols) < 0) {
+        rows = 25;
+        cols = 80;
+    }
+
+    // Measure the content: the lines of the message, an empty line and the prompt
+    int num_lines = 1;
+    int width = print_prompt(options, user, NULL);
+    if (message != NULL) {
+        const char* line = message;
+        for (;;) {
+            const char* end = strchr(line, '\n');
+            size_t len = end != NULL ? (size_t)(end - line) : strlen(line);
+            int w = print_message_line(options, line, len, NULL);
+            if (w > width) {
+                width = w;
+            }
+            num_lines++;
+            if (end == NULL) {
+                break;
+            }
+            line = end + 1;
+        }
+        num_lines++;
+    }
+
+    // The box adds a border and a space of padding on each side
+    int border = options->box ? 2 : 0;
+    int total_width = width + 2 * border;
+    int total_height = num_lines + (options->box ? 2 : 0);
+    int top = (int)rows > total_height ? ((int)rows - total_height) / 2 + 1 : 1;
+    int left = (int)cols > total_width ? ((int)cols - total_width) / 2 + 1 : 1;
+    int row = top;
+
+    if (options->box) {
+        fprintf(stdout, "\033[%d;%dH\u250c", row++, left);
+        repeat("\u2500", width + 2);
+        fprintf(stdout, "\u2510");
+    }
+
+    // Every line is padded to the full width, so that the right border is aligned
+    if (message != NULL) {
+        const char* line = message;
+        for (;;) {
+            const char* end = strchr(line, '\n');
+            size_t len = end != NULL ? (size_t)(end - line) : strlen(line);
+            fprintf(stdout, "\033[%d;%dH%s", row++, left, options->box ? "\u2502 " : "");
+            int w = print_message_line(options, line, len, stdout);
+            if (options->box) {
+                repeat(" ", width - w);
+                fprintf(stdout, " \u2502");
+            }
+            if (end == NULL) {
+                break;
+            }
+            line = end + 1;
+        }
+        fprintf(stdout, "\033[%d;%dH", row++, left);
+        if (options->box) {
+            fprintf(stdout, "\u2502");
+            repeat(" ", width + 2);
+            fprintf(stdout, "\u2502");
+        }
+    }
+
+    fprintf(stdout, "\033[%d;%dH%s", row++, left, options->box ? "\u2502 " : "");
+    int w = print_prompt(options, user, stdout);
+    if (options->box) {
+        repeat(" ", width - w);
+        fprintf(stdout, " \u2502");
+        fprintf(stdout, "\033[%d;%dH\u2514", row++, left);
+        repeat("\u2500", width + 2);
+        fprintf(stdout, "\u2518");
+    }
+
+    fprintf(stdout, "\033[%d;1H", row);
+}
+
+/**
+ *    Draws the number of the locked VT in the bottom right corner of the screen,
+ *    so that whoever switches to it knows which one they are looking at.
+ *    The cursor is left where it was.
+ */
+static void print_vt_number(struct options* options, struct vt* vt) {
+    unsigned int rows, cols;
+    if (vt_getsize(vt, &rows, &cols) < 0) {
+        rows = 25;
+        cols = 80;
+    }
+
+    char label[32];
+    int len = snprintf(label, sizeof(label), _("VT %u"), vt->number);
+    int col = (int)cols > len ? (int)cols - len + 1 : 1;
+    fprintf(stdout, "\0337\033[%u;%dH%s%s" RESET "\0338", rows, col, options->theme.user, label);
+}
+
+/**
+ *    Draws the background and the text over it, starting from the current cursor position.
+ */
+static void draw_console(struct options* options, struct vt* vt, void* bg, const char* user) {
+    if (bg != NULL) {
+        bg_paint(bg);
+    }
+
+    if (options->show_vt && !vt->stdio) {
+        print_vt_number(options, vt);
+    }
+
+    if (options->title != NULL) {
+        print_title(options, vt);
+    }
+
+    char* message = build_message(options, user);
+
+    if (options->center) {
+        repaint_centered(options, vt, message, user);
+    } else {
+        if (message != NULL) {
+            fprintf(stdout, "\n");
+            const char* line = message;
+            for (;;) {
+                const char* end = strchr(line, '\n');
+                size_t len = end != NULL ? (size_t)(end - line) : strlen(line);
+                print_message_line(options, line, len, stdout);
+                fprintf(stdout, "\n");
+                if (end == NULL) {
+                    break;
+                }
+                line = end + 1;
+            }
+        }
+        fprintf(stdout, "\n");
+        print_prompt(options, user, stdout);
+    }
+
+    free(message);
+}
+
+static void repaint_console(struct options* options, struct vt* vt, void* bg, const char* user) {
+    clear_screen(options, vt);
+    vt_flush(vt);
+    draw_console(options, vt, bg, user);
+}
+
+/**
+ *    Shows the current frame of the background and writes the text over it again.
+ *    The screen is not cleared first, so that it does not go blank between two frames.
+ */
+static void repaint_background(struct options* options, struct vt* vt, void* bg, const char* user) {
+    fprintf(stdout, "\033[0m\033[H");
+    draw_console(options, vt, bg, user);
+}
+
+/**
+ *    Returns the point in time `ms` milliseconds from now, on the monotonic clock.
+ */
+static struct timespec deadline_after(unsigned int ms) {
+    struct timespec t;
+    clock_gettime(CLOCK_MONOTONIC, &t);
+    t.tv_sec += ms / 1000;
+    t.tv_nsec += (ms % 1000) * 1000000L;
+    if (t.tv_nsec >= 1000000000L) {
+        t.tv_sec++;
+        t.tv_nsec -= 1000000000L;
+    }
+    return t;
+}
+
+/**
+ *    Returns the number of milliseconds left before `deadline`, negative if it has passed.
+ */
+static long ms_until(const struct timespec* deadline) {
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    return (deadline->tv_sec - now.tv_sec) * 1000L + (deadline->tv_nsec - now.tv_nsec) / 1000000L;
+}
+
+/**
+ *    Selects the framebuffer devices to draw the background on.
+ *    Returns the number of devices stored in `fbdevs`.
+ */
+static unsigned int select_fbdevs(struct options* options, const char* fbdevs[BG_MAX_FBDEVS]) {
+    static char detected[BG_MAX_FBDEVS][BG_FBDEV_PATH_SIZE];
+
+    if (options->fbdev != NULL) {
+        fbdevs[0] = options->fbdev;
+        return 1;
+    }
+
+    // Unless asked otherwise, use only the lowest numbered device:
+    // fbcon binds the consoles to fb0 by default
+    unsigned int found = bg_find_fbdevs(detected, BG_MAX_FBDEVS);
+    if (found > 1 && !options->all_fbdevs) {
+        found = 1;
+    }
+    for (int i = 0; i < found; i++) {
+        fbdevs[i] = detected[i];
+    }
+    return found;
+}
+
+/**
+ *    Loads the background image or color, if any.
+ *    Returns `NULL` if there's no background or in case of error.
+ */
+static void* load_background(struct options* options) {
+    const char* fbdevs[BG_MAX_FBDEVS];
+
+    if (options->background == NULL && options->background_color == NULL) {
+        return NULL;
+    }
+
+    unsigned int fbdevs_size = select_fbdevs(options, fbdevs);
+    if (fbdevs_size == 0) {
+        fprintf(stderr, "No framebuffer device found.\n");
+        return NULL;
+    }
+    for (int i = 0; i < fbdevs_size; i++) {
+        log_debug("Drawing the background on %s", fbdevs[i]);
+    }
+    if (options->background != NULL) {
+        log_debug("Background image %s, fill mode %s", options->background, bg_fill_name(options->background_fill));
+    }
+
+    return bg_init(options->background, options->background_fill, options->background_color, options->background_interval,
+                   fbdevs, fbdevs_size);
+}
+
+/**
+ *    Prints a string as a JSON string literal, quotes included.
+ */
+static void print_json_string(const char* str) {
+    fputc('"', stdout);
+    for (const unsigned char* c = (const unsigned char*)str; *c != 0; c++) {
+        if (*c == '"' || *c == '\\') {
+            fprintf(stdout, "\\%c", *c);
+        } else if (*c < 0x20) {
+            fprintf(stdout, "\\u%04x", *c);
+        } else {
+            fputc(*c, stdout);
+        }
+    }
+    fputc('"', stdout);
+}
+
+/**
+ *    Outcome of one of the checks made by `--check`.
+ */
+struct check_result {
+    const char* name;
+    int ok;
+    char detail[CHECK_DETAIL_SIZE]; // Empty if the problem has only been described on `stderr`
+};
+
+static void add_check_result(struct check_result* results, int* size, const char* name, int ok, const char* detail) {
+    results[*size].name = name;
+    results[*size].ok = ok;
+    snprintf(results[*size].detail, CHECK_DETAIL_SIZE, "%s", detail != NULL ? detail : "");
+    (*size)++;
+}
+
+/**
+ *    Checks that everything needed to lock the station is in place, without locking it.
+ *    Problems are reported on `stderr`, and the outcome on `stdout` in the format requested with `--format`.
+ *
+ *    @return Exit code: `0` if the station can be locked, `1` otherwise.
+ */
+static int check(struct options* options) {
+    struct check_result results[4 + BG_MAX_FBDEVS];
+    int results_size = 0;
+
+    // Users have already been validated while parsing the options
+    if (options->format == FORMAT_HUMAN) {
+        fprintf(stdout, "Users allowed to unlock:");
+        for (int i = 0; i < options->users_size; i++) {
+            fprintf(stdout, " %s", options->users[i]);
+        }
+        fprintf(stdout, "\n");
+    }
+
+    if (vt_init() < 0) {
+        add_check_result(results, &results_size, "console", 0, strerror(errno));
+        perror("vt_init");
+        add_check_result(results, &results_size, "lock", 0, "the console is not available");
+    } else {
+        add_check_result(results, &results_size, "console", 1, NULL);
+        add_check_result(results, &results_size, "lock", lock_check(options) == 0, NULL);
+        vt_end();
+    }
+
+    add_check_result(results, &results_size, "pam", auth_check(options) == 0, NULL);
+
+    if (options->background != NULL || options->background_color != NULL) {
+        const char* fbdevs[BG_MAX_FBDEVS];
+        unsigned int fbdevs_size = select_fbdevs(options, fbdevs);
+        if (fbdevs_size == 0) {
+            add_check_result(results, &results_size, "framebuffer", 0, "no framebuffer device found");
+            fprintf(stderr, "No framebuffer device found.\n");
+        }
+        for (int i = 0; i < fbdevs_size; i++) {
+            int ok = access(fbdevs[i], R_OK | W_OK) == 0;
+            add_check_result(results, &results_size, "framebuffer", ok, ok ? fbdevs[i] : strerror(errno));
+            if (!ok) {
+                fprintf(stderr, "Cannot access framebuffer device %s: %s\n", fbdevs[i], results[results_size - 1].detail);
+            }
+        }
+    }
+    if (options->background != NULL) {
+        add_check_result(results, &results_size, "background", bg_check(options->background) == 0, NULL);
+    }
+
+    int ok = 1;
+    for (int i = 0; i < results_size; i++) {
+        ok = ok && results[i].ok;
+    }
+
+    if (options->format == FORMAT_JSON) {
+        fprintf(stdout, "{\"users\":[");
+        for (int i = 0; i < options->users_size; i++) {
+            fprintf(stdout, i > 0 ? "," : "");
+            print_json_string(options->users[i]);
+        }
+        fprintf(stdout, "],\"checks\":[");
+        for (int i = 0; i < results_size; i++) {
+            fprintf(stdout, "%s{\"name\":\"%s\",\"ok\":%s", i > 0 ? "," : "", results[i].name, results[i].ok ? "true" : "false");
+            if (*results[i].detail != 0) {
+                fprintf(stdout, ",\"detail\":");
+                print_json_string(results[i].detail);
+            }
+            fprintf(stdout, "}");
+        }
+        fprintf(stdout, "],\"ok\":%s}\n", ok ? "true" : "false");
+    } else {
+        fprintf(stdout, ok ? "The station can be locked.\n" : "The station cannot be locked.\n");
+    }
+    return ok ? 0 : 1;
+}
+
+/**
+ *    Prints the state of the virtual terminals, one `key: value` pair per line, or as a JSON object.
+ *    Only the first 16 terminals are reported as in use, since that's all the kernel tells us cheaply.
+ *
+ *    @return Exit code: `0` in case of success, `1` otherwise.
+ */
+static int list_vts(struct options* options) {
+    unsigned int active;
+    unsigned short in_use;
+    if (vt_init() < 0) {
+        perror("vt_init");
+        return 1;
+    }
+    if (vt_getstate(&active, &in_use) < 0) {
+        perror("vt_getstate");
+        vt_end();
+        return 1;
+    }
+    int next = vt_querynew();
+    if (next < 0) {
+        perror("vt_querynew");
+        vt_end();
+        return 1;
+    }
+    vt_end();
+
+    if (options->format == FORMAT_JSON) {
+        fprintf(stdout, "{\"current\":%u,\"in_use\":[", active);
+        const char* separator = "";
+        for (int i = 1; i < 16; i++) {
+            if (in_use & (1 << i)) {
+                fprintf(stdout, "%s%d", separator, i);
+                separator = ",";
+            }
+        }
+        if (next > 0) {
+            fprintf(stdout, "],\"next\":%d}\n", next);
+        } else {
+            fprintf(stdout, "],\"next\":null}\n");
+        }
+        return 0;
+    }
+
+    fprintf(stdout, "current: %u\n", active);
+    fprintf(stdout, "in-use:");
+    for (int i = 1; i < 16; i++) {
+        if (in_use & (1 << i)) {
+            fprintf(stdout, " %d", i);
+        }
+    }
+    fprintf(stdout, "\n");
+    if (next > 0) {
+        fprintf(stdout, "next: %d\n", next);
+    } else {
+        fprintf(stdout, "next: unknown\n");
+    }
+    return 0;
+}
+
+/**
+ *    Prints the users allowed to unlock, in the order they are offered, with why each of them is allowed
+ *    and whether they can authenticate, for `--list-users`.
+ *    In human format every user is a line of tab separated fields: name, origin, `yes`, `no` or `unknown`,
+ *    and why they cannot authenticate, if they cannot.
+ */
+static int list_users(struct options* options) {
+    if (options->format == FORMAT_JSON) {
+        fprintf(stdout, "{\"users\":[");
+    }
+    for (int i = 0; i < options->users_size; i++) {
+
+        // With --password-hash, the password of the user is never asked
+        const char* reason = NULL;
+        int can_authenticate = options->password_hash != NULL ? 1 : options_user_can_authenticate(options->users[i], &reason);
+
+        if (options->format == FORMAT_JSON) {
+            fprintf(stdout, "%s{\"name\":", i > 0 ? "," : "");
+            print_json_string(options->users[i]);
+            fprintf(stdout, ",\"origin\":");
+            print_json_string(options->users_origins[i]);
+            fprintf(stdout, ",\"can_authenticate\":%s", can_authenticate < 0 ? "null" : can_authenticate ? "true" : "false");
+            if (can_authenticate == 0) {
+                fprintf(stdout, ",\"reason\":");
+                print_json_string(reason);
+            }
+            fprintf(stdout, "}");
+        } else {
+            fprintf(stdout, "%s\t%s\t%s", options->users[i], options->users_origins[i],
+                can_authenticate < 0 ? "unknown" : can_authenticate ? "yes" : "no");
+            if (can_authenticate == 0) {
+                fprintf(stdout, "\t%s", reason);
+            }
+            fprintf(stdout, "\n");
+        }
+    }
+    if (options->format == FORMAT_JSON) {
+        fprintf(stdout, "]}\n");
+    }
+    return 0;
+}
+
+/**
+ *    Runs a systemd tool (`systemctl`, `loginctl`) with the given command, and waits for it to complete.
+ *
+ *    @return `0` if the tool exited successfully, `-1` otherwise.
+ */
+static int run_systemd_tool(const char* tool, const char* command) {
+    log_debug("Running %s %s", tool, command);
+    pid_t childpid = fork();
+    if (childpid == 0) {
+        restore_default_signals();
+        setenv("PATH", DEFAULT_PATH, 1);
+        execlp(tool, tool, command, (char*)NULL);
+        perror(tool);
+        _exit(127);
+    } else if (childpid == -1) {
+        perror("fork");
+        return -1;
+    }
+
+    int status;
+    pid_t wpid;
+    while ((wpid = waitpid(childpid, &status, 0)) == -1 && errno == EINTR);
+    if (wpid == -1 || !WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+        log_debug("%s %s failed", tool, command);
+        return -1;
+    }
+    return 0;
+}
+
+/**
+ *    Unlocks the sessions on the other seats, if they have been locked with --all-seats.
+ *    Every path that unlocks the station after locking them must call this.
+ */
+static void unlock_seats() {
+    if (!seats_locked) {
+        return;
+    }
+    if (run_systemd_tool("loginctl", "unlock-sessions") < 0) {
+        fprintf(stderr, "Could not unlock the sessions on the other seats.\n");
+    }
+    seats_locked = 0;
+}
+
+/**
+ *    Takes the action requested with --max-lock-action, since the station has been locked for too long.
+ */
+static void max_lock_time_expired(struct options* options, struct vt* vt) {
+    log_debug("Locked for more than %u seconds", options->max_lock_time);
+    switch (options->max_lock_action) {
+        case MAX_LOCK_WARN:
+            fprintf(stdout, "\n%s%s" RESET "\n", options->theme.error, _("The station has been locked for too long."));
+            vt_bell(vt);
+            break;
+        case MAX_LOCK_SUSPEND:
+            run_systemd_tool("systemctl", "suspend");
+            break;
+        case MAX_LOCK_POWEROFF:
+            run_systemd_tool("systemctl", "poweroff");
+            break;
+    }
+}
+
+/**
+ *    Waits for enter to be pressed.
+ *    If the terminal is resized in the meanwhile, the background is reloaded and the console repainted.
+ *    Slideshows and animated backgrounds advance while waiting.
+ *    If the station stays locked for longer than --max-lock-time, the requested action is taken.
+ *    With --smartcard-service, the readers are polled: inserting a card ends the wait, and removing it
+ *    repaints the console.
+ *
+ *    SIGWINCH and SIGUSR1 must be blocked when calling this function: they are unblocked only while waiting for input,
+ *    so that they cannot interrupt any other operation.
+ *
+ *    @return `WAIT_ENTER` when enter has been pressed, `WAIT_EMERGENCY` when an emergency unlock has been requested,
+ *            `WAIT_CARD` when a smartcard has been inserted, `-1` on EOF or error.
+ */
+static int wait_for_enter(struct options* options, struct vt* vt, void** bg, const char* user) {
+    sigset_t wait_mask;
+    sigprocmask(SIG_SETMASK, NULL, &wait_mask);
+    sigdelset(&wait_mask, SIGWINCH);
+    sigdelset(&wait_mask, SIGUSR1);
+
+    struct timespec frame_deadline = deadline_after(*bg != NULL ? bg_frame_delay(*bg) : 0);
+    struct timespec card_deadline = deadline_after(0);
+
+    for (;;) {
+
+        if (emergency_unlock) {
+            return WAIT_EMERGENCY;
+        }
+
+        if (resized) {
+            resized = 0;
+            if (*bg != NULL) {
+                bg_free(*bg);
+                *bg = load_background(options);
+                frame_deadline = deadline_after(*bg != NULL ? bg_frame_delay(*bg) : 0);
+            }
+            repaint_console(options, vt, *bg, user);
+        }
 
-static void on_sigint(int sig) {
-    if (user_selection_enabled) {
-        siglongjmp(user_selection_jmp, 1);
+        // Wake up when the station has been locked for too long
+        struct timespec timeout;
+        struct timespec* timeout_ptr = NULL;
+        if (options->max_lock_time > 0) {
+            time_t now = time(NULL);
+            if (now >= max_lock_deadline) {
+                max_lock_time_expired(options, vt);
+                max_lock_deadline = time(NULL) + options->max_lock_time;
+                continue;
+            }
+            timeout.tv_sec = max_lock_deadline - now;
+            timeout.tv_nsec = 0;
+            timeout_ptr = &timeout;
+        }
+
+        // And when the output of the greeter has to be refreshed
+        if (options->greeter_command != NULL && options->greeter_interval > 0) {
+            time_t now = time(NULL);
+            if (now >= greeter_deadline) {
+                repaint_console(options, vt, *bg, user);
+                continue;
+            }
+            if (timeout_ptr == NULL || greeter_deadline - now < timeout.tv_sec) {
+                timeout.tv_sec = greeter_deadline - now;
+                timeout.tv_nsec = 0;
+                timeout_ptr = &timeout;
+            }
+        }
+
+        // And, in paranoid mode, when the lock has to be checked again
+        if (options->paranoid) {
+            time_t now = time(NULL);
+            if (now >= watchdog_deadline) {
+                lock_enforce(options);
+                watchdog_deadline = time(NULL) + WATCHDOG_INTERVAL;
+                continue;
+            }
+            if (timeout_ptr == NULL || watchdog_deadline - now < timeout.tv_sec) {
+                timeout.tv_sec = watchdog_deadline - now;
+                timeout.tv_nsec = 0;
+                timeout_ptr = &timeout;
+            }
+        }
+
+        // And when the next frame of the background has to be shown
+        if (*bg != NULL && bg_frame_delay(*bg) > 0) {
+            long left = ms_until(&frame_deadline);
+            if (left <= 0) {
+                // If the following frame cannot be prepared, the current one just stays on screen
+                bg_next_frame(*bg);
+                repaint_background(options, vt, *bg, user);
+                frame_deadline = deadline_after(bg_frame_delay(*bg));
+                continue;
+            }
+            if (timeout_ptr == NULL || left < timeout.tv_sec * 1000L) {
+                timeout.tv_sec = left / 1000;
+                timeout.tv_nsec = (left % 1000) * 1000000L;
+                timeout_ptr = &timeout;
+            }
+        }
+
+        // And when the smartcard readers have to be polled, since PC/SC cannot wake us up.
+        // A card already present when we start waiting must be removed and inserted again.
+        if (smartcard != NULL) {
+            long left = ms_until(&card_deadline);
+            if (left <= 0) {
+                int present = smartcard_present(smartcard);
+                card_deadline = deadline_after(SMARTCARD_POLL_INTERVAL);
+                if (present >= 0 && present != card_present) {
+                    int was_present = card_present;
+                    card_present = present;
+                    if (present && was_present == 0) {
+                        log_debug("Smartcard inserted");
+                        return WAIT_CARD;
+                    } else if (!present && was_present == 1) {
+                        log_debug("Smartcard removed");
+                        repaint_console(options, vt, *bg, user);
+                    }
+                }
+                continue;
+            }
+            if (timeout_ptr == NULL || left < timeout.tv_sec * 1000L + timeout.tv_nsec / 1000000L) {
+                timeout.tv_sec = left / 1000;
+                timeout.tv_nsec = (left % 1000) * 1000000L;
+                timeout_ptr = &timeout;
+            }
+        }
+
+        fd_set fds;
+        FD_ZERO(&fds);
+        FD_SET(STDIN_FILENO, &fds);
+        int ret = pselect(STDIN_FILENO + 1, &fds, NULL, NULL, timeout_ptr, &wait_mask);
+        if (ret < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            return -1;
+        } else if (ret == 0) {
+            continue;
+        }
+
+        // Since the terminal is in canonical mode, a whole line is ready to be read.
+        // A line ended by Ctrl+L asks to redraw the screen, e.g. after a kernel message.
+        int c;
+        do {
+            c = fgetc(stdin);
+        } while (c != EOF && c != '\n' && c != REDRAW_KEY);
+        if (c == REDRAW_KEY) {
+            repaint_console(options, vt, *bg, user);
+            continue;
+        }
+        return c == EOF ? -1 : WAIT_ENTER;
     }
 }
 
-static inline int register_signal(int sig, void (*handler)(int)) {
-    struct sigaction action;
-    memset(&action, 0, sizeof(action));
-    action.sa_handler = handler;
-    return sigaction(sig, &action, NULL);
+/**
+ *    Reports an unexpected error that happened while the station is locked.
+ *    Errors never unlock the station: after a short delay, the caller goes back to the initial screen.
+ */
+static void report_locked_error(const char* what) {
+    const char* reason = feof(stdin) ? _("end of file") : strerror(errno);
+    log_debug("%s: %s", what, reason);
+    fprintf(stdout, "\n%s: %s\n", what, reason);
+
+    // The EOF flag of stdin is sticky, and would make every following read fail
+    clearerr(stdin);
+    sleep(FAILED_AUTH_DELAY);
 }
 
-static int user_selection(struct options* options, struct vt* vt, void* bg, char** user) {
-    int index;
-    do {
+/**
+ *    Runs the auth loop on the locked terminal, until the station is unlocked by a successful authentication
+ *    or by an emergency unlock. Ctrl+C goes to the selection of the user, and Ctrl+\ to the rescue shell if enabled.
+ *    Every attempt is made with `authenticate`, which is `auth_session_authenticate` outside of the tests.
+ */
+static void run_loop(struct options* options, struct vt* vt, void** bg, struct auth_session* session, char* user,
+                     auth_result_t (*authenticate)(struct auth_session* session, char* user)) {
 
-        vt_flush(vt);
-        vt_clear(vt);
-        
-        // Switch on the screen if in dark mode
-        if (options->dark_mode) {
-            vt_blank(vt, 0);
+    // User selection: this code will be executed only when the user presses Ctrl+C
+    if (sigsetjmp(user_selection_jmp, 1) > 0) {
+        if (user_selection(options, vt, *bg, &user) < 0) {
+            report_locked_error(_("Cannot read the user"));
         }
+    }
 
-        // Background
-        if (bg != NULL) {
-            bg_paint(bg);
-        }
+    // Rescue shell: this code will be executed only when the user presses Ctrl+\ with --rescue-shell
+    if (sigsetjmp(rescue_shell_jmp, 1) > 0) {
+        user_selection_enabled = 0;
+        rescue_shell_enabled = 0;
+        rescue_shell(options, vt, *bg);
+    }
 
-        // Users list
-        fprintf(stdout, "\nThe following users are authorized to unlock:\n\n");
-        for (int i = 0; i < options->users_size; i++) {
-            char* format = "%d. %s\n";
-            if (options->users[i] == *user) {
-                format = "%d. " HIGHLIGHT "%s" RESET "\n";
+    for (;;) {
+
+        // Repaint the console
+        timings_start(TIMING_FIRST_REPAINT);
+        repaint_console(options, vt, *bg, user);
+        timings_stop(TIMING_FIRST_REPAINT);
+
+        // Wait for enter to be pressed if not in quick mode.
+        // If we are in quick mode, instead, jump directly to
+        // authentication, and disable quick mode, so that after
+        // a failed attempt, it will be requested to press enter.
+        //
+        // This way, if both quick mode and dark mode are enabled,
+        // the user will be able to make a first login attempt
+        // with the screen switched off, and then it will be turned on later.
+        if (!options->quick_mode) {
+            
+            // Wait for enter.
+            // When the user name is typed, there's no user to choose here.
+            user_selection_enabled = user != NULL;
+            rescue_shell_enabled = options->rescue_shell;
+            int ret = wait_for_enter(options, vt, bg, user);
+            user_selection_enabled = 0;
+            rescue_shell_enabled = 0;
+            if (ret == WAIT_EMERGENCY) {
+                log_debug("Emergency unlock requested");
+                break;
+            }
+
+            // The card in the reader is used to authenticate, without asking for enter
+            options->smartcard_active = ret == WAIT_CARD;
+            if (ret < 0) {
+                report_locked_error(_("Cannot read from the terminal"));
+                continue;
             }
-            fprintf(stdout, format, i + 1, options->users[i]);
+
+            // Switch the screen back on before authentication
+            lock_set_blank(options, 0);
+
+            // Repaint the whole console
+            repaint_console(options, vt, *bg, user);
+            fprintf(stdout, "\n");
+
+        } else {
+            options->quick_mode = 0;
+            fprintf(stdout, "\n");
         }
-        fprintf(stdout, "\nInsert the number of the user that wants to unlock and press enter: ");
 
-        // Wait for user selection
-        char* line = NULL;
-        size_t n = 0;
-        vt_setecho(vt, 1);
-        if (getline(&line, &n, vt->stream) < 0) {
-            return -1;
+        // The user needs to see where they are typing
+        if (options->hide_cursor) {
+            vt_setcursor(vt, 1);
         }
-        vt_setecho(vt, 0);
 
-        char* tmp;
-        index = strtol(line, &tmp, 10);
-        if (*tmp != '\n') {
-            index = -1;
+        // When we ask for the user name ourselves, only the allowed users ever reach PAM.
+        // If no allowed user has been entered, go back to the initial screen as after a timeout.
+        auth_result_t auth_result;
+        char* typed_user = NULL;
+        timings_start(TIMING_AUTH);
+        if (options->ask_username) {
+            typed_user = ask_username(options);
+            auth_result = typed_user != NULL ? authenticate(session, typed_user) : AUTH_TIMEOUT;
         } else {
-            index--;
+            auth_result = authenticate(session, user);
         }
+        timings_stop(TIMING_AUTH);
+        options->smartcard_active = 0;
 
-    } while (index < 0 || index >= options->users_size);
+        if (options->hide_cursor) {
+            vt_setcursor(vt, 0);
+        }
 
-    *user = options->users[index];
+        // After the duress password, everything goes on as after a successful or a failed attempt
+        if (auth_result == AUTH_DURESS) {
+            run_duress_command(options);
+            auth_result = options->duress_action == DURESS_UNLOCK ? AUTH_SUCCESS : AUTH_FAILURE;
+        }
+        if (auth_result == AUTH_FAILURE && options->on_fail_command != NULL) {
+            run_fail_command(options, total_failed_attempts + 1, typed_user != NULL ? typed_user : user);
+        }
+        free(typed_user);
+        log_debug("Authentication result: %d", auth_result);
+        if (auth_result == AUTH_SUCCESS) {
+            // The user is authenticated, so we can unlock everything
+            break;
+        }
 
-    return 0;
-}
+        // If the user did not complete the authentication in time, go back to the initial screen
+        // without counting this as a failed attempt. Repainting the console discards any pending input.
+        if (auth_result == AUTH_TIMEOUT) {
+            lock_set_blank(options, 1);
+            continue;
+        }
 
-static void repaint_console(struct options* options, struct vt* vt, void* bg, const char* user) {
-    vt_clear(vt);
-    vt_flush(vt);
+        // If the authentication could not be carried out (e.g. a module could not reach its server),
+        // the station stays locked and the user can try again, without counting this as a failed attempt.
+        if (auth_result == AUTH_ERROR) {
+            lock_set_blank(options, 0);
+            sleep(FAILED_AUTH_DELAY);
+            continue;
+        }
 
-    if (bg != NULL) {
-        bg_paint(bg);
+        // Switch the screen back on to be sure that the user knows
+        // the authentication failed, repainting the whole console if it was off.
+        if (lock_set_blank(options, 0)) {
+            repaint_console(options, vt, *bg, user);
+            fprintf(stdout, "\n");
+        }
+
+        count_failed_attempt(options, vt);
     }
+}
 
-    if (options->message != NULL) {
-        fprintf(stdout, "\n%s\n", options->message);
+/**
+ *    Prints the version of SimplyLock and of the libraries it uses, for bug reports.
+ */
+static void print_build_info(struct options* options) {
+    fprintf(stdout, "simplylock v" SIMPLYLOCK_VERSION "\n");
+    fprintf(stdout, "PAM implementation: %s\n", auth_library_name());
+    if (options->password_hash != NULL) {
+        fprintf(stdout, "Authentication: password hash in %s, PAM not used\n", options->password_hash);
+    } else {
+        fprintf(stdout, "PAM service: %s\n", options->pam_service);
+    }
+    if (options->step_up_service != NULL) {
+        fprintf(stdout, "PAM step-up service: %s\n", options->step_up_service);
+    }
+    if (options->smartcard_service != NULL) {
+        fprintf(stdout, "PAM smartcard service: %s\n", options->smartcard_service);
     }
-    fprintf(stdout, "\nPress enter to unlock as " HIGHLIGHT "%s" RESET ". [Press Ctrl+C to change user] ", user);
+    for (int i = 0; i < options->user_services_size; i++) {
+        fprintf(stdout, "PAM service for %s: %s\n", options->user_services[i].user, options->user_services[i].service);
+    }
+    fprintf(stdout, "MagickWand API: %d\n", MAGICKWAND_VERSION);
+    fprintf(stdout, "Image library: %s\n", bg_library_version());
+    fprintf(stdout, "Smartcard support: %s\n", smartcard_supported() ? "yes" : "no");
+    fprintf(stdout, "Console device: " VT_CONSOLE_DEVICE "\n");
+    const char* fbdevs[BG_MAX_FBDEVS];
+    unsigned int fbdevs_size = select_fbdevs(options, fbdevs);
+    if (fbdevs_size == 0) {
+        fprintf(stdout, "Framebuffer device: none found\n");
+    }
+    for (int i = 0; i < fbdevs_size; i++) {
+        fprintf(stdout, "Framebuffer device: %s\n", fbdevs[i]);
+    }
+    fprintf(stdout, "Sysrq control file: %s\n", options->sysrq_path);
+    fprintf(stdout, "Printk control file: %s\n", options->printk_path);
 }
 
 int main(int argc, char** argv) {
     struct options* options;
     struct vt* vt;
     void* bg = NULL;
+    struct auth_session* session = NULL;
     char* user;
-    int c;
-    int is_console_blanked = 0;
+    int exit_code = 1;
+
+    // The locale comes from the environment of the user who started us,
+    // and stays in effect after the environment is cleared
+    setlocale(LC_ALL, "");
+    bindtextdomain(GETTEXT_PACKAGE, LOCALEDIR);
+    textdomain(GETTEXT_PACKAGE);
 
     // Parses the options
     options = options_parse(argc, argv);
     if (options == NULL) {
-        return 1;
+        return errno == EPERM ? EXIT_NO_USABLE_USER : 1;
+    }
+    if (options->show_build_info) {
+        print_build_info(options);
+        options_free(options);
+        return 0;
     }
     if (options->show_help || options->show_version) {
         options_free(options);
         return 0;
     }
-    user = options->users[0];
+    user = options->pam_username || options->ask_username ? NULL : options->users[0];
+
+    // Debug messages keep going to the current terminal even after locking
+    if (log_init(options->verbose) < 0) {
+        perror("log_init");
+        options_free(options);
+        return 1;
+    }
+    if (timings_init(options->timings) < 0) {
+        perror("timings_init");
+        options_free(options);
+        return 1;
+    }
 
     // We need to run as root or setuid root
     if (geteuid() != 0) {
-        fprintf(stderr, "Please, run simplylock as root or setuid root.\n");
-        return 1;
+        fprintf(stderr, "%s\n", _("Please, run simplylock as root or setuid root."));
+        return EXIT_NOT_ROOT;
     }
 
     // Now we become fully root, in case we were started as setuid from another user
@@ -151,17 +1910,127 @@ int main(int argc, char** argv) {
         return 1;
     }
 
+    // Dry run: check that we could lock, and exit
+    if (options->check) {
+        int ret = check(options);
+        options_free(options);
+        return ret;
+    }
+    if (options->list_vts) {
+        int ret = list_vts(options);
+        options_free(options);
+        return ret;
+    }
+    if (options->list_users) {
+        int ret = list_users(options);
+        options_free(options);
+        return ret;
+    }
+
+    // Without its configuration, PAM falls back to the "other" service,
+    // which usually denies everything: nobody would be able to unlock
+    if (auth_check(options) < 0) {
+        fprintf(stderr, "The station will not be locked.\n");
+        options_free(options);
+        return EXIT_PAM_SERVICE;
+    }
+    if (auth_load_hashes(options) < 0) {
+        fprintf(stderr, "The station will not be locked.\n");
+        options_free(options);
+        return 1;
+    }
+    if (emergency_load_key(options) < 0) {
+        fprintf(stderr, "The station will not be locked.\n");
+        options_free(options);
+        return 1;
+    }
+
+    // A background that cannot be loaded is reported now, instead of silently missing once locked
+    if (options->background != NULL && bg_check(options->background) < 0) {
+        fprintf(stderr, "The station will not be locked.\n");
+        options_free(options);
+        return 1;
+    }
+
+    // Another instance using the same pidfile is reported now, while we can still return an error
+    if (options->pidfile != NULL && open_pidfile(options) < 0) {
+        fprintf(stderr, "The station will not be locked.\n");
+        options_free(options);
+        return 1;
+    }
+
+    // In idle mode we stay resident, and lock the station every time it is left idle.
+    // Every lock happens in a child process, which goes on with the usual locking sequence below.
+    if (options->idle > 0) {
+        if (!options->dont_detach) {
+            pid_t daemonpid = fork();
+            if (daemonpid == -1) {
+                perror("fork");
+                return 1;
+            } else if (daemonpid > 0) {
+                options_free(options);
+                return 0;
+            }
+            setsid();
+        }
+
+        // Times of the last unlocks, to know when --step-up-service is needed
+        time_t unlock_times[options->step_up_after > 0 ? options->step_up_after : 1];
+        unsigned int unlocks = 0;
+
+        for (;;) {
+            if (idle_wait(options->idle) < 0) {
+                perror("idle_wait");
+                return 1;
+            }
+            log_debug("Idle for %u seconds: locking", options->idle);
+
+            // The locking process inherits the choice of the service
+            options->step_up = step_up_needed(options, unlock_times, unlocks);
+            if (options->step_up) {
+                log_debug("%u unlocks in %u seconds: the %s service is required", options->step_up_after, options->step_up_window, options->step_up_service);
+            }
+
+            pid_t lockpid = fork();
+            if (lockpid == 0) {
+                break;
+            } else if (lockpid == -1) {
+                perror("fork");
+                return 1;
+            }
+
+            // Only unlocks with the usual services count, and one with the stricter service starts the count again
+            int ret = wait_child(lockpid);
+            if (ret == 0 && options->step_up) {
+                unlocks = 0;
+            } else if (ret == 0 && options->step_up_service != NULL) {
+                unlock_times[unlocks % options->step_up_after] = time(NULL);
+                unlocks++;
+            }
+            if (options->once) {
+                options_free(options);
+                return ret;
+            }
+        }
+
+        // The locking process must wait for the station to be unlocked,
+        // so that we know when to start watching again
+        options->dont_detach = 1;
+    }
+
     // Register signal handler for SIGINT
     if (register_signal(SIGINT, on_sigint) < 0) {
         perror("register_signal SIGINT");
         return 1;
     }
 
-    // Ignore all other termination signals
-    if (register_signal(SIGQUIT, SIG_IGN) < 0) {
+    // Ctrl+\ opens the rescue shell if enabled, otherwise SIGQUIT is ignored like all other termination signals
+    if (register_signal(SIGQUIT, options->rescue_shell ? on_sigquit : SIG_IGN) < 0) {
         perror("register_signal SIGQUIT");
         return 1;
     }
+
+    // Ignore all other termination signals
     if (register_signal(SIGTERM, SIG_IGN) < 0) {
         perror("register_signal SIGTERM");
         return 1;
@@ -171,65 +2040,162 @@ int main(int argc, char** argv) {
         return 1;
     }
 
+    // Repaint when the terminal is resized.
+    // SIGWINCH is kept blocked and is received only when waiting for input.
+    sigset_t winch_mask;
+    sigemptyset(&winch_mask);
+    sigaddset(&winch_mask, SIGWINCH);
+    if (register_signal(SIGWINCH, on_sigwinch) < 0 || sigprocmask(SIG_BLOCK, &winch_mask, NULL) < 0) {
+        perror("register_signal SIGWINCH");
+        return 1;
+    }
+
+    // The emergency unlock watcher asks to unlock with SIGUSR1, which, like SIGWINCH, is received only when waiting for input
+    if (options->emergency_key != NULL) {
+        sigset_t usr1_mask;
+        sigemptyset(&usr1_mask);
+        sigaddset(&usr1_mask, SIGUSR1);
+        if (register_signal(SIGUSR1, on_sigusr1) < 0 || sigprocmask(SIG_BLOCK, &usr1_mask, NULL) < 0) {
+            perror("register_signal SIGUSR1");
+            return 1;
+        }
+    }
+
+    // The child will tell us through this pipe whether it managed to lock the station
+    int status_pipe[2];
+    if (pipe(status_pipe) < 0) {
+        perror("pipe");
+        return 1;
+    }
+    fcntl(status_pipe[0], F_SETFD, FD_CLOEXEC);
+    fcntl(status_pipe[1], F_SETFD, FD_CLOEXEC);
+
     // Now we fork and move to a new session so that we can be the
     // foreground process for the new terminal to be created
     pid_t childpid;
     if ((childpid = fork()) == 0) {
+        close(status_pipe[0]);
+        status_fd = status_pipe[1];
         if (setsid() < 0) {
             perror("setsid");
+            send_lock_status(0, 1, 0);
             return 1;
         }
     } else if (childpid == -1) {
         perror("fork");
         return 1;
     } else {
-        // Wait for the child process to terminate.
-        if (options->dont_detach) {
-            int status;
-            pid_t wpid;
-            while ((wpid = waitpid(childpid, &status, 0)) == -1 && errno == EINTR);
-            if (wpid == -1) {
-                perror("waitpid");
-                return 1;
-            } else if (WIFEXITED(status)) {
-                return WEXITSTATUS(status);
-            } else if (WIFSIGNALED(status)) {
-                return 128 + WSTOPSIG(status);
+        log_debug("Locking process started with PID %d", (int)childpid);
+        close(status_pipe[1]);
+
+        // The pidfile, and its lock, belong to the locking process from now on
+        if (pidfile_fd >= 0) {
+            close(pidfile_fd);
+            pidfile_fd = -1;
+        }
+
+        // Wait for the station to be locked, but not forever: the child might be stuck, for example in PAM
+        struct pollfd pfd = { .fd = status_pipe[0], .events = POLLIN };
+        int timeout_ms = options->lock_timeout > 0 ? (int)options->lock_timeout * 1000 : -1;
+        int ret;
+        while ((ret = poll(&pfd, 1, timeout_ms)) == -1 && errno == EINTR);
+        if (ret == 0) {
+            close(status_pipe[0]);
+            fprintf(stderr, "The locking process did not lock the station within %u seconds.\n", options->lock_timeout);
+            if (options->kill_on_lock_timeout) {
+                log_debug("Killing the locking process");
+                kill(childpid, SIGKILL);
+                wait_child(childpid);
             }
+            return EXIT_LOCK_TIMEOUT;
         }
-        return 0;
+
+        struct lock_status ready;
+        ssize_t n;
+        while ((n = read(status_pipe[0], &ready, sizeof(ready))) == -1 && errno == EINTR);
+        close(status_pipe[0]);
+        if (n == sizeof(ready) && ready.locked) {
+            log_debug("Station locked on VT %u", ready.vt);
+        }
+
+        // If the pipe has been closed without a status, the child died before locking:
+        // it has already reported why on stderr, and we return its exit status,
+        // which must tell that locking failed even if the child exited cleanly.
+        if (n != sizeof(ready)) {
+            int ret = wait_child(childpid);
+            if (ret == 0) {
+                fprintf(stderr, "The locking process exited without locking the station.\n");
+                ret = 1;
+            }
+            return ret;
+        }
+
+        // Wait for the child process to terminate
+        if (options->dont_detach) {
+            return wait_child(childpid);
+        }
+        return ready.locked ? 0 : (ready.exit_code != 0 ? ready.exit_code : 1);
     }
 
-    // Initialize VT library
-    if (vt_init() < 0) {
-        perror("vt_init");
+    // From now on, a crash must not leave the terminal unusable
+    if (lock_handle_crashes(1) < 0) {
+        perror("lock_handle_crashes");
         goto error;
     }
-    is_console_blanked = options->dark_mode;
 
-    // Load the background image if requested
-    if (options->background != NULL) {
-        bg = bg_init(options->background, options->background_fill, options->fbdev);
-        // Don't check for errors: if there has been an error,
-        // just don't paint the background.
+    // Initialize VT library, not needed when testing on the standard streams
+    if (!options->io_stdio && vt_init() < 0) {
+        perror("vt_init");
+        goto error;
     }
 
-    // Locking of the terminal
-    vt = lock(options);
+    // Load the background image if requested.
+    // Don't check for errors: if there has been an error,
+    // just don't paint the background.
+    bg = load_background(options);
+
+    // Locking of the terminal: a new one, or the current one with --in-place
+    if (options->io_stdio) {
+        vt = vt_fromstdio();
+    } else if (options->in_place) {
+        unsigned int active;
+        unsigned short in_use;
+        vt = vt_getstate(&active, &in_use) == 0 ? vt_open(active) : NULL;
+    } else {
+        timings_start(TIMING_VT_ALLOCATION);
+        vt = vt_createnew();
+        timings_stop(TIMING_VT_ALLOCATION);
+    }
+    if (vt == NULL) {
+        perror(options->io_stdio ? "vt_fromstdio" : options->in_place ? "vt_open" : "vt_createnew");
+        exit_code = EXIT_VT_ALLOCATION;
+        goto error;
+    }
+    vt = lock_with_vt(options, vt);
     if (vt == NULL) {
         goto error;
     }
+    locked_since = time(NULL);
+    max_lock_deadline = locked_since + options->max_lock_time;
 
-    // Enable Ctrl+C on the terminal
-    if (vt_signals(vt, VT_SIGINT) < 0) {
+    // Enable Ctrl+C (and Ctrl+\ for the rescue shell) on the terminal
+    if (vt_signals(vt, VT_SIGINT | (options->rescue_shell ? VT_SIGQUIT : 0)) < 0) {
         perror("vt_signals");
         goto error;
     }
 
+    // Ctrl+L ends the line like enter does, so that we can redraw the screen while waiting for enter
+    if (vt_seteol(vt, REDRAW_KEY) < 0) {
+        perror("vt_seteol");
+        goto error;
+    }
+
     // We redirect all three standard streams to the new vt
-    REDIRECT_STD_STREAM(stdin, STDIN_FILENO, "r");
-    REDIRECT_STD_STREAM(stdout, STDOUT_FILENO, "w");
-    REDIRECT_STD_STREAM(stderr, STDERR_FILENO, "w");
+    if (!options->io_stdio) {
+        REDIRECT_STD_STREAM(stdin, STDIN_FILENO, "r");
+        REDIRECT_STD_STREAM(stdout, STDOUT_FILENO, "w");
+        REDIRECT_STD_STREAM(stderr, STDERR_FILENO, "w");
+    }
 
     // Disable buffering on std streams since this might cause problems with PAM stdio
     setbuf(stdin, NULL);
@@ -239,103 +2205,92 @@ int main(int argc, char** argv) {
     // We clear the environment to avoid any possible interaction with PAM modules
     clearenv();
 
-    // User selection: this code will be executed only when the user presses Ctrl+C
-    if (sigsetjmp(user_selection_jmp, 1) > 0) {
-        user_selection(options, vt, bg, &user);
+    // All the attempts share the same PAM transaction
+    session = auth_session_start(options);
+    if (session == NULL) {
+        perror("auth_session_start");
+        goto error;
     }
 
-    // The auth loop
-    for (;;) {
-        
-        // Repaint the console
-        repaint_console(options, vt, bg, user);
-
-        // Wait for enter to be pressed if not in quick mode.
-        // If we are in quick mode, instead, jump directly to
-        // authentication, and disable quick mode, so that after
-        // a failed attempt, it will be requested to press enter.
-        //
-        // This way, if both quick mode and dark mode are enabled,
-        // the user will be able to make a first login attempt
-        // with the screen switched off, and then it will be turned on later.
-        if (!options->quick_mode) {
-            
-            // Wait for enter
-            user_selection_enabled = 1;
-            c = fgetc(stdin);
-            while (c != EOF && c != '\n') {
-                c = fgetc(stdin);
-            }
-            if (c == EOF) {
-                perror("getchar");
-                goto error;
-            }
-            user_selection_enabled = 0;
-
-            // Switch the screen back on before authentication
-            if (options->dark_mode) {
-                vt_blank(vt, 0);
-                is_console_blanked = 0;
-            }
-
-            // Repaint the whole console
-            repaint_console(options, vt, bg, user);
-            fprintf(stdout, "\n");
+    // Everything is ready: the parent can return
+    write_pidfile(vt->number);
+    send_lock_status(1, 0, vt->number);
 
-        } else {
-            options->quick_mode = 0;
-            fprintf(stdout, "\n");
-        }
+    if (options->emergency_key != NULL) {
+        start_emergency_watcher(options);
+    }
 
-        if (auth_authenticate_user(user) == 0) {
-            // The user is authenticated, so we can unlock everything
-            break;
+    // Without the PC/SC daemon, the cards are simply not noticed, and unlocking works as usual
+    if (options->smartcard_service != NULL) {
+        smartcard = smartcard_init();
+        if (smartcard == NULL) {
+            log_debug("Cannot watch the smartcard readers: %s", strerror(errno));
         }
+    }
 
-        // Switch the screen back on to be sure that the user knows
-        // the authentication failed.
-        if (options->dark_mode) {
-            vt_blank(vt, 0);
-
-            // Repaint the whole console
-            if (is_console_blanked) {
-                repaint_console(options, vt, bg, user);
-                fprintf(stdout, "\n");
-            }
-
-            is_console_blanked = 0;
+    // Virtual terminals exist only on the first seat:
+    // the sessions on the other seats are locked by their own lockers, through logind
+    if (options->all_seats) {
+        if (run_systemd_tool("loginctl", "lock-sessions") == 0) {
+            seats_locked = 1;
+        } else {
+            log_debug("The sessions on the other seats are not locked");
         }
-
-        fprintf(stdout, "\nAuthentication failed.\n");
-        sleep(3);
     }
 
-    
+    // The auth loop, until the station is unlocked
+    run_loop(options, vt, &bg, session, user, auth_session_authenticate);
+
+    stop_emergency_watcher();
+    smartcard_free(smartcard);
+    auth_session_end(session);
     if (bg != NULL) {
         bg_free(bg);
     }
 
-    vt_clear(vt);
+    // Unlocking here unlocks every seat
+    unlock_seats();
+
+    clear_screen(options, vt);
+    timings_start(TIMING_UNLOCK);
     unlock(options);
+    timings_stop(TIMING_UNLOCK);
+    remove_pidfile(options);
+    timings_report();
 
     // Cleanup
     fclose(stdin);
     fclose(stdout);
     fclose(stderr);
     options_free(options);
+    emergency_end();
     vt_end();
+    timings_end();
+    log_debug("Unlocked");
+    log_end();
     return 0;
 
 error:
+    send_lock_status(0, exit_code, 0);
+    stop_emergency_watcher();
+    smartcard_free(smartcard);
+    auth_session_end(session);
     if (bg != NULL) {
         bg_free(bg);
     }
+    unlock_seats();
     unlock(options);
+    remove_pidfile(options);
+    timings_report();
     fclose(stdin);
     fclose(stdout);
     fclose(stderr);
     options_free(options);
+    emergency_end();
     vt_end();
-    return 1;
+    timings_end();
+    log_debug("Exiting after an error");
+    log_end();
+    return exit_code;
 
 }
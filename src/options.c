@@ -1,15 +1,28 @@
+#define _DEFAULT_SOURCE
 #include <stdlib.h>
 #include <stdio.h>
 #include <string.h>
 #include <unistd.h>
 #include <pwd.h>
+#include <grp.h>
 #include <ctype.h>
+#include <errno.h>
+#include <limits.h>
 #include <shadow.h>
+#include <time.h>
 #include <getopt.h>
 
 #include "options.h"
+#include "vt.h"
+#include "smartcard.h"
+#include "i18n.h"
 
-#define SIMPLYLOCK_VERSION "0.6.0"
+#define MAX_MASK_DOTS 32
+#define MAX_FAIL_DELAY 300
+#define MAX_VT_NUMBER 63
+#define MAX_STEP_UP_AFTER 100
+#define MAX_SYSRQ_MASK 511
+#define MAX_LOCK_TIMEOUT 86400
 
 static char* root_username = "root";
 
@@ -17,21 +30,104 @@ static struct option long_options[] = {
     { "no-sysreq",               no_argument,       NULL, 's' },
     { "no-lock",                 no_argument,       NULL, 'l' },
     { "no-kernel-messages",      no_argument,       NULL, 'k' },
+    { "best-effort",             no_argument,       NULL,  0  },
+    { "sysrq-path",              required_argument, NULL,  0  },
+    { "sysrq-mask",              required_argument, NULL,  0  },
+    { "printk-path",             required_argument, NULL,  0  },
     { "users",                   required_argument, NULL, 'u' },
+    { "groups",                  required_argument, NULL,  0  },
     { "allow-passwordless-root", no_argument,       NULL,  0  },
+    { "ignore-unknown-users",    no_argument,       NULL,  0  },
+    { "force",                   no_argument,       NULL,  0  },
+    { "no-root",                 no_argument,       NULL,  0  },
+    { "pam-username",            no_argument,       NULL,  0  },
+    { "ask-username",            no_argument,       NULL,  0  },
+    { "hide-users",              no_argument,       NULL,  0  },
+    { "rescue-shell",            no_argument,       NULL,  0  },
     { "message",                 required_argument, NULL, 'm' },
+    { "title",                   required_argument, NULL,  0  },
+    { "selection-message",       required_argument, NULL,  0  },
+    { "prompt",                  required_argument, NULL,  0  },
+    { "busy-message",            required_argument, NULL,  0  },
+    { "markup",                  no_argument,       NULL,  0  },
+    { "greeter-command",         required_argument, NULL,  0  },
+    { "greeter-interval",        required_argument, NULL,  0  },
+    { "no-clear",                no_argument,       NULL,  0  },
+    { "no-clear-row",            required_argument, NULL,  0  },
+    { "center",                  no_argument,       NULL,  0  },
+    { "box",                     no_argument,       NULL,  0  },
+    { "show-last",               no_argument,       NULL,  0  },
+    { "show-vt",                 no_argument,       NULL,  0  },
+    { "color-prompt",            required_argument, NULL,  0  },
+    { "color-user",              required_argument, NULL,  0  },
+    { "color-error",             required_argument, NULL,  0  },
     { "dark",                    no_argument,       NULL, 'd' },
     { "quick",                   no_argument,       NULL, 'q' },
+    { "echo-mode",               required_argument, NULL,  0  },
+    { "mask",                    no_argument,       NULL,  0  },
+    { "mask-timeout",            required_argument, NULL,  0  },
+    { "mask-style",              required_argument, NULL,  0  },
+    { "max-input",               required_argument, NULL,  0  },
+    { "max-attempts",            required_argument, NULL,  0  },
+    { "fail-delay",              required_argument, NULL,  0  },
+    { "fail-message",            required_argument, NULL,  0  },
+    { "timeout",                 required_argument, NULL,  0  },
+    { "bell",                    no_argument,       NULL,  0  },
+    { "quiet",                   no_argument,       NULL,  0  },
+    { "flash",                   no_argument,       NULL,  0  },
+    { "pam-timeout",             required_argument, NULL,  0  },
+    { "pam-session",             no_argument,       NULL,  0  },
+    { "pam-service",             required_argument, NULL,  0  },
+    { "smartcard-service",       required_argument, NULL,  0  },
+    { "step-up-service",         required_argument, NULL,  0  },
+    { "step-up-after",           required_argument, NULL,  0  },
+    { "step-up-window",          required_argument, NULL,  0  },
+    { "password-fifo",           required_argument, NULL,  0  },
+    { "password-hash",           required_argument, NULL,  0  },
+    { "duress-file",             required_argument, NULL,  0  },
+    { "emergency-file",          required_argument, NULL,  0  },
+    { "emergency-key",           required_argument, NULL,  0  },
+    { "duress-command",          required_argument, NULL,  0  },
+    { "duress-action",           required_argument, NULL,  0  },
+    { "on-fail",                 required_argument, NULL,  0  },
+    { "max-lock-time",           required_argument, NULL,  0  },
+    { "max-lock-action",         required_argument, NULL,  0  },
     { "background",              required_argument, NULL, 'b' },
     { "background-fill",         required_argument, NULL,  0  },
+    { "background-interval",     required_argument, NULL,  0  },
+    { "bg-color",                required_argument, NULL,  0  },
     { "fbdev",                   required_argument, NULL,  0  },
+    { "all-fbdevs",              no_argument,       NULL,  0  },
+    { "no-hide-cursor",          no_argument,       NULL,  0  },
     { "no-detach",               no_argument,       NULL, 'D' },
+    { "lock-timeout",            required_argument, NULL,  0  },
+    { "kill-on-lock-timeout",    no_argument,       NULL,  0  },
+    { "in-place",                no_argument,       NULL,  0  },
+#ifdef SIMPLYLOCK_TESTING
+    { "io-stdio",                no_argument,       NULL,  0  }, // Testing only, not documented
+#endif
+    { "all-seats",               no_argument,       NULL,  0  },
+    { "switch-back-to",          required_argument, NULL,  0  },
+    { "return-vt",               required_argument, NULL,  0  },
+    { "paranoid",                no_argument,       NULL,  0  },
+    { "idle",                    required_argument, NULL,  0  },
+    { "once",                    no_argument,       NULL,  0  },
+    { "pidfile",                 required_argument, NULL,  0  },
+    { "verbose",                 no_argument,       NULL,  0  },
+    { "timings",                 no_argument,       NULL,  0  },
+    { "check",                   no_argument,       NULL,  0  },
+    { "list-users",              no_argument,       NULL,  0  },
+    { "list-vts",                no_argument,       NULL,  0  },
+    { "format",                  required_argument, NULL,  0  },
     { "help",                    no_argument,       NULL, 'h' },
     { "version",                 no_argument,       NULL, 'v' },
+    { "build-info",              no_argument,       NULL,  0  },
     { 0, 0, 0, 0 }
 };
 
 static void print_usage(int argc, char** argv) {
+
+    // The help text is split in sections, to keep each string within the length supported by every compiler
     fprintf(
         stderr,
         "Usage: %s [-slkdqhv] [-u users] [-m message] [-b path]\n"
@@ -39,25 +135,215 @@ static void print_usage(int argc, char** argv) {
         "-s, --no-sysreq              Keep sysrequests enabled.\n"
         "-l, --no-lock                Do not lock terminal switching.\n"
         "-k, --no-kernel-messages     Do not mute kernel messages while the console is locked.\n"
-        "-u, --users users            Comma separated list of users allowed to unlock.\n"
-        "                             Note that the root user will always be able to unlock.\n"
-        "-m, --message message        Display the given message instead of the default one.\n"
+        "    --best-effort            Lock even if kernel messages cannot be muted, with a warning.\n"
+        "                             Failing to block sysrequests or terminal switching still prevents locking.\n"
+        "    --sysrq-path path        Path of the file controlling sysrequests. (default: /proc/sys/kernel/sysrq)\n"
+        "                             Only root can use this option.\n"
+        "    --sysrq-mask mask        Keep the sysrequests in the given mask enabled instead of disabling all of them,\n"
+        "                             for example 48 for sync and remount read-only. See the kernel documentation\n"
+        "                             of /proc/sys/kernel/sysrq for the values. (default: 0)\n"
+        "    --printk-path path       Path of the file controlling kernel messages. (default: /proc/sys/kernel/printk)\n"
+        "                             Only root can use this option.\n"
         "-d, --dark                   Dark mode: switch off the screen after locking.\n"
         "-q, --quick                  Quick mode: do not wait for enter to be pressed to unlock.\n"
+        "\n",
+        argv[0]
+    );
+    fprintf(
+        stderr,
+        "-u, --users users            Comma separated list of users (names or UIDs) allowed to unlock.\n"
+        "                             Note that the root user will always be able to unlock, unless --no-root is passed.\n"
+        "    --groups groups          Comma separated list of groups (names or GIDs) whose members are allowed\n"
+        "                             to unlock, in addition to --users. Members are looked up when locking.\n"
+        "    --no-root                Do not allow the root user to unlock if not explicitly listed with --users.\n"
+        "    --ignore-unknown-users   Do not refuse to lock if some of the users do not exist,\n"
+        "                             just leave them out.\n"
+        "    --force                  Lock even if none of the users allowed to unlock can authenticate\n"
+        "                             (for example, because their passwords are locked), or their passwords\n"
+        "                             cannot be read from the shadow file.\n"
+        "    --pam-username           Let PAM ask for the name of the user that wants to unlock,\n"
+        "                             instead of choosing it from the list of allowed users.\n"
+        "    --ask-username           Ask for the name of the user that wants to unlock, and check that\n"
+        "                             it is allowed to before starting the authentication.\n"
+        "    --hide-users             Never show the names of the users allowed to unlock: the prompt\n"
+        "                             does not name the user, and Ctrl+C asks to type the name instead of listing them.\n"
+        "    --rescue-shell           Allow root to open a shell on the locked terminal by pressing Ctrl+\\.\n"
+        "                             Read the documentation before enabling this.\n"
+        "\n"
+    );
+    fprintf(
+        stderr,
+        "    --echo-mode mode         What to show while a password is typed. Supported modes:\n"
+        "                             - hidden: nothing. (default)\n"
+        "                             - asterisk: a * for every character.\n"
+        "                             - dots:n: n dots, filled one per character up to the last one.\n"
+        "                             - reveal: every character for a moment, then a * in its place.\n"
+        "    --mask                   Same as --echo-mode asterisk.\n"
+        "    --mask-style style       Same as --echo-mode, with stars for asterisk.\n"
+        "    --mask-timeout ms        With --echo-mode asterisk or reveal, collapse the *s to a single one after\n"
+        "                             the given number of milliseconds without typing, to hide the password length.\n"
+        "    --max-input bytes        Maximum length of what can be typed at a prompt: with an --echo-mode other\n"
+        "                             than hidden, keys past it are ignored (ringing the bell with --bell),\n"
+        "                             otherwise the answer is cut. (default: 512)\n"
+        "    --max-attempts n         Maximum number of consecutive failed attempts allowed before\n"
+        "                             unlocking is disabled for 60 seconds. (default: unlimited)\n"
+        "    --fail-delay secs        Seconds to wait after a failed attempt, up to 300. (default: 3)\n"
+        "    --fail-message msg       Message displayed after a failed attempt, in the error color.\n"
+        "    --bell                   Ring the terminal bell when an authentication fails.\n"
+        "    --quiet                  Mute the terminal bell while the station is locked, for example when rung\n"
+        "                             by PAM modules. The bell of --bell and --max-lock-action warn still rings.\n"
+        "    --flash                  Flash the screen when an authentication fails.\n"
+        "    --timeout secs           Abandon an authentication attempt if not completed\n"
+        "                             within the given number of seconds. (default: no timeout)\n"
+    );
+    fprintf(
+        stderr,
+        "    --pam-timeout secs       Abort the authentication if PAM does not complete within the given\n"
+        "                             number of seconds, counting it as a failed attempt. (default: no timeout)\n"
+        "    --pam-session            After a successful authentication, also open and close a PAM session,\n"
+        "                             for the modules that do their work there.\n"
+        "    --pam-service services   Comma separated list of PAM services to authenticate with: user=service\n"
+        "                             entries choose the service of a user, and a plain service name replaces\n"
+        "                             the default one for everybody else. (default: simplylock)\n"
+        "                             Only root can use this option.\n"
+        "    --password-fifo path     Read the answers to the authentication prompts from the given FIFO,\n"
+        "                             for example written by an on-screen keyboard, instead of the keyboard.\n"
+        "                             The FIFO must be owned by root and writable only by root. Use with -q on\n"
+        "                             stations without a keyboard.\n"
+        "    --password-hash path     Do not use PAM: unlock with the password whose hash, as generated by mkpasswd\n"
+        "                             or openssl passwd, is in the given file, owned by root and readable only by root.\n"
+        "                             Reduced security, for systems without PAM. Read the documentation first.\n"
+        "    --smartcard-service name Authenticate with the given PAM service as soon as a smartcard is inserted\n"
+        "                             while the initial screen is shown, without waiting for enter.\n"
+        "                             Removing the card shows the initial screen again. Needs smartcard support.\n"
+        "                             Only root can use this option.\n"
+        "    --step-up-service name   With --idle, authenticate with the given PAM service instead of the usual\n"
+        "                             ones after --step-up-after unlocks within --step-up-window seconds\n"
+        "                             (for example, to require a smartcard). The count starts again after\n"
+        "                             an unlock with this service. Only root can use this option.\n"
+        "    --step-up-after n        Number of unlocks after which --step-up-service is required.\n"
+        "    --step-up-window secs    Only count the unlocks within the given number of seconds. (default: 3600)\n"
+    );
+    fprintf(
+        stderr,
+        "    --duress-file path       File with the hash of a duress password, as generated by mkpasswd or\n"
+        "                             openssl passwd. The file must be owned by root and readable only by root.\n"
+        "                             Read the documentation before enabling this.\n"
+        "    --duress-command cmd     Shell command started in background when the duress password is entered.\n"
+        "                             Only root can use this option.\n"
+        "    --duress-action action   What happens after the duress password. Supported actions:\n"
+        "                             - unlock: unlock the station. (default)\n"
+        "                             - fail: stay locked, as after a wrong password.\n"
+        "    --emergency-file path    Unlock when a token signed with the --emergency-key appears in the given file,\n"
+        "                             which must be owned by root and writable only by root. Read the documentation first.\n"
+        "    --emergency-key path     Ed25519 public key in PEM format, to verify the tokens of --emergency-file.\n"
+        "    --on-fail cmd            Shell command started in background after every failed attempt.\n"
+        "                             The attempt number and the user are passed in the SIMPLYLOCK_ATTEMPT\n"
+        "                             and SIMPLYLOCK_USER environment variables. Only root can use this option.\n"
+        "    --max-lock-time secs     Take an action when the station stays locked for more than the given\n"
+        "                             number of seconds, and again every time that much time passes. (default: never)\n"
+        "    --max-lock-action action Action to take after --max-lock-time. Supported actions:\n"
+        "                             - warn: ring the bell and print a warning. (default)\n"
+        "                             - suspend: suspend the machine with systemctl.\n"
+        "                             - poweroff: power the machine off with systemctl.\n"
+        "                             Only root can use suspend and poweroff.\n"
+        "\n"
+    );
+    fprintf(
+        stderr,
+        "-m, --message message        Display the given message instead of the default one.\n"
+        "                             The following placeholders are replaced: %%H (host name), %%u (user),\n"
+        "                             %%n (attempt number), %%t (time), %%%% (a literal %%).\n"
+        "    --title text             Display the given title in big block letters at the top of the screen,\n"
+        "                             in the color of the user names. Only letters, digits, spaces and -.,:!?\n"
+        "                             are drawn as block letters: otherwise, or if too wide, the title is plain text.\n"
+        "    --markup                 Style the message with *bold*, _underline_ and {color}colored{/} text.\n"
+        "                             Use \\ to escape any of \\*_{.\n"
+        "    --greeter-command cmd    Display what the given shell command prints instead of the message.\n"
+        "                             The command runs as root, and is killed if it takes more than 2 seconds.\n"
+        "                             Only root can use this option.\n"
+        "    --greeter-interval secs  Run the --greeter-command again every given number of seconds.\n"
+        "                             (default: run it only once)\n"
+        "    --selection-message msg  Header displayed above the list of users allowed to unlock.\n"
+        "    --prompt text            Text asking to press enter, instead of \"Press enter to unlock as %%u.\".\n"
+        "                             Supports the same placeholders as --message. Pass an empty string to show no text.\n"
+        "    --busy-message text      Text displayed while the authentication modules are working, instead of\n"
+        "                             \"Authenticating...\". Pass an empty string to show no text.\n"
+        "    --no-clear               Do not clear the console, for example to keep a splash screen drawn by\n"
+        "                             another tool with --in-place: draw from the top of the screen, over what\n"
+        "                             is already there. Cannot be used with --center, --box or a background.\n"
+        "    --no-clear-row row       With --no-clear, draw from the given row down, clearing only the rows\n"
+        "                             below it. Ctrl+L redraws from the same row.\n"
+        "    --center                 Display the message and the prompt in the middle of the screen.\n"
+        "    --box                    Like --center, but draw a box around the message and the prompt.\n"
+        "    --show-last              Display when the station has been locked and how many attempts failed since then.\n"
+        "    --show-vt                Display the number of the locked VT in the bottom right corner of the screen.\n"
+        "    --color-prompt color     Color of the prompt. (default: default)\n"
+        "    --color-user color       Color of the user names. (default: blue)\n"
+        "    --color-error color      Color of the error messages. (default: red)\n"
+        "                             Available colors: default, black, red, green, yellow, blue, magenta,\n"
+        "                             cyan, white, their light- variants (e.g. light-red),\n"
+        "                             or a number between 0 and 255.\n"
         "\n"
-        "-b, --background             Set background image.\n"
+    );
+    fprintf(
+        stderr,
+        "-b, --background             Set background image. Animated images are played, and if a directory is given,\n"
+        "                             the images it contains are shown one after the other.\n"
         "    --background-fill        Background fill mode. Available values:\n"
         "                             - center: center the image without resizing it.\n"
         "                             - stretch: stretch the image to fill all the available space.\n"
         "                             - resize: like stretch, but keeps image proportions.\n"
         "                             - resize-fill: resize the image to fill the screen but keep proportions. (default)\n"
+        "    --background-interval s  Seconds each image of a --background directory is shown (default: 60).\n"
+        "    --bg-color color         Fill the background with the given color (name or #rrggbb).\n"
+        "                             When used with -b, fills the parts of the screen not covered by the image.\n"
         "    --fbdev                  Path to the framebuffer device to use to draw the background.\n"
+        "                             If not given, the lowest numbered /dev/fbN device is used.\n"
+        "    --all-fbdevs             Draw the background on all the framebuffer devices found.\n"
+        "    --no-hide-cursor         Keep the cursor visible over the background while not typing.\n"
         "\n"
+    );
+    fprintf(
+        stderr,
         "-D, --no-detach              Dont't detach: waits for the screen to be unlocked before returning.\n"
+        "    --lock-timeout secs      Give up if the station has not been locked within the given number of seconds,\n"
+        "                             or 0 to wait forever. (default: 30)\n"
+        "    --kill-on-lock-timeout   After --lock-timeout, kill the locking process instead of leaving it running.\n"
+        "    --pidfile path           While locked, write the PID of the locking process and the number of the locked\n"
+        "                             terminal to the given file, one per line, and remove it when unlocking.\n"
+        "                             The file must not exist. Only root can use this option.\n"
+        "    --in-place               Lock the current virtual terminal instead of switching to a new one.\n"
+        "                             Only for text consoles: whatever runs on the terminal keeps running and\n"
+        "                             can still write to the screen. Implies -D.\n"
+        "    --switch-back-to vt      Switch to the given virtual terminal when unlocking, instead of\n"
+        "                             the one that was active when the station has been locked.\n"
+        "    --return-vt vt           Like --switch-back-to, but only if the active terminal cannot be found\n"
+        "                             when locking, instead of refusing to lock.\n"
+        "    --paranoid               While waiting for enter, check every 5 seconds that terminal switching\n"
+        "                             is still blocked and that the locked terminal is still the active one,\n"
+        "                             restoring the lock if another process changed it.\n"
+        "    --all-seats              On multi-seat machines, also lock the sessions on all the other seats\n"
+        "                             through logind, and unlock them all when the station is unlocked.\n"
+        "                             Only root can use this option.\n"
+        "    --idle secs              Stay in background, and lock the station every time no input is received\n"
+        "                             from keyboards, mice and other input devices for the given number of seconds.\n"
+        "                             With -D, stay in foreground instead.\n"
+        "    --once                   With --idle, exit after the station has been locked and unlocked once,\n"
+        "                             with the same exit code simplylock would have without --idle.\n"
+        "    --verbose                Print debug messages to the terminal simplylock has been started from.\n"
+        "    --timings                When unlocking, print to the same terminal how long each phase of the lock took.\n"
+        "    --check                  Check that the station can be locked, without locking it, and exit.\n"
+        "    --list-vts               Print which terminals are in use and which one would be used to lock, and exit.\n"
+        "    --list-users             Print the users allowed to unlock, why, and whether they can authenticate, and exit.\n"
+        "    --format format          Format of the output of --check, --list-vts and --list-users. Supported formats:\n"
+        "                             - human: text meant to be read. (default)\n"
+        "                             - json: a JSON object, for provisioning tools. Problems are still\n"
+        "                               described on stderr.\n"
         "\n"
         "-h, --help                   Display this help text.\n"
-        "-v, --version                Display version information.\n",
-        argv[0]
+        "-v, --version                Display version information.\n"
+        "    --build-info             Display version information and details about how simplylock has been built.\n"
     );
 }
 
@@ -90,46 +376,466 @@ static char* trim(char* str, size_t len, size_t* outLen) {
     return begin;
 }
 
+static int parse_uint(const char* str, unsigned int* out) {
+    char* end;
+    errno = 0;
+    unsigned long val = strtoul(str, &end, 10);
+    if (errno != 0 || end == str || *end != 0 || val > UINT_MAX || strchr(str, '-') != NULL) {
+        return -1;
+    }
+    *out = (unsigned int)val;
+    return 0;
+}
+
+static const char* color_names[] = {
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"
+};
+
+int options_parse_color(const char* str, int bold, char* style) {
+    const char* prefix = bold ? "\033[1m" : "";
+
+    if (strcmp(str, "default") == 0) {
+        snprintf(style, THEME_STYLE_SIZE, "%s", prefix);
+        return 0;
+    }
+
+    unsigned int index;
+    if (isdigit((unsigned char)*str) && parse_uint(str, &index) == 0) {
+        if (index > 255) {
+            return -1;
+        }
+        snprintf(style, THEME_STYLE_SIZE, "%s\033[38;5;%um", prefix, index);
+        return 0;
+    }
+
+    int light = strncmp(str, "light-", 6) == 0;
+    const char* name = light ? str + 6 : str;
+    for (int i = 0; i < sizeof(color_names) / sizeof(color_names[0]); i++) {
+        if (strcmp(name, color_names[i]) == 0) {
+            snprintf(style, THEME_STYLE_SIZE, "%s\033[%dm", prefix, (light ? 90 : 30) + i);
+            return 0;
+        }
+    }
+
+    return -1;
+}
+
+
+/**
+ *    Refuses the options that make simplylock run commands or use files chosen by the caller,
+ *    unless the caller is root: simplylock is setuid root, and would do it with root privileges for anybody.
+ */
+static int require_root(const char* opt_name) {
+    if (getuid() == 0) {
+        return 0;
+    }
+    fprintf(stderr, "Only root can use --%s.\n", opt_name);
+    errno = EACCES;
+    return -1;
+}
+
+static void free_users(struct options* options) {
+    if (options->users != NULL) {
+        for (int i = 0; i < options->users_size; i++) {
+            free(options->users[i]);
+            free(options->users_origins[i]);
+        }
+        free(options->users);
+        free(options->users_origins);
+        options->users = NULL;
+        options->users_origins = NULL;
+        options->users_size = 0;
+    }
+}
+
+/**
+ *    Makes room for `size` users in the list, keeping the ones already there.
+ */
+static int resize_users(struct options* options, unsigned int size) {
+    char** users = (char**)realloc(options->users, size * sizeof(char*));
+    if (users == NULL) {
+        return -1;
+    }
+    options->users = users;
+    char** origins = (char**)realloc(options->users_origins, size * sizeof(char*));
+    if (origins == NULL) {
+        return -1;
+    }
+    options->users_origins = origins;
+    return 0;
+}
+
+/**
+ *    Adds a user to the list, which must have room for it.
+ *    `origin` tells why the user is allowed to unlock, for --list-users.
+ */
+static int add_user(struct options* options, const char* name, const char* origin) {
+    char* copy = strdup(name);
+    char* origin_copy = strdup(origin);
+    if (copy == NULL || origin_copy == NULL) {
+        free(copy);
+        free(origin_copy);
+        return -1;
+    }
+    options->users[options->users_size] = copy;
+    options->users_origins[options->users_size] = origin_copy;
+    options->users_size++;
+    return 0;
+}
+
+int options_has_user(const struct options* options, const char* name) {
+    for (int i = 0; i < options->users_size; i++) {
+        if (strcmp(options->users[i], name) == 0) {
+            return 1;
+        }
+    }
+    return 0;
+}
 
 static int split_users(struct options* options, char* users) {
 
+    // Only the last list passed on the command line counts
+    free_users(options);
+
     // To know how much names we have, we count how many "," are in the string.
     // We may allocate more memory than the necessary if the string is malformed.
-    // We begin with two users for sure, which are root and at least one specified on the command line.
+    // We begin with two users for sure, which are root (added at the end of the parsing)
+    // and at least one specified on the command line.
     unsigned int num_users = 2;
     for (char* c = users; *c != 0; c++) {
         if (*c == ',') {
             num_users++;
         }
     }
-    options->users = (char**)malloc(num_users * sizeof(char*));
-    if (options->users == NULL) {
+    if (resize_users(options, num_users) < 0) {
         return -1;
     }
 
     // Now we tokenize the string
-    int i = 0;
     char* strtok_state;
     char* token = strtok_r(users, ",", &strtok_state);
     while (token != NULL) {
         size_t token_len;
         token = trim(token, strlen(token), &token_len);
         if (token_len > 0) {
-            options->users[i] = token;
-            i++;
+
+            // Numeric entries are UIDs: store the corresponding user name,
+            // since authentication works with names.
+            unsigned int uid;
+            const char* origin = "users";
+            if (isdigit((unsigned char)*token) && parse_uint(token, &uid) == 0) {
+                origin = "users-uid";
+                errno = 0;
+                struct passwd* passwd = getpwuid((uid_t)uid);
+                if (passwd == NULL) {
+                    fprintf(stderr, "No user with UID %s. The station will not be locked.\n", token);
+                    if (errno == 0) {
+                        errno = ENOENT;
+                    }
+                    return -1;
+                }
+                token = passwd->pw_name;
+            }
+
+            if (add_user(options, token, origin) < 0) {
+                return -1;
+            }
+        }
+        token = strtok_r(NULL, ",", &strtok_state);
+    }
+
+    return 0;
+}
+
+static void free_user_services(struct options* options) {
+    free(options->user_services);
+    options->user_services = NULL;
+    options->user_services_size = 0;
+}
+
+/**
+ *    Checks that a PAM service name is made only of letters, digits, dots, dashes and underscores,
+ *    and does not start with a dot, like the names of the files in the PAM configuration directories:
+ *    a service can never name a hidden file, or a file outside those directories.
+ */
+static int is_valid_service(const char* service) {
+    if (*service == 0 || *service == '.') {
+        return 0;
+    }
+    for (const char* c = service; *c != 0; c++) {
+        if (!isalnum((unsigned char)*c) && *c != '.' && *c != '-' && *c != '_') {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+static int split_user_services(struct options* options, char* services) {
+
+    // Only the last list passed on the command line counts
+    free_user_services(options);
+    options->pam_service = PAM_SERVICE;
+
+    // Like for the users, we might allocate more entries than the needed ones
+    unsigned int num_services = 1;
+    for (char* c = services; *c != 0; c++) {
+        if (*c == ',') {
+            num_services++;
+        }
+    }
+    options->user_services = (struct user_service*)malloc(num_services * sizeof(struct user_service));
+    if (options->user_services == NULL) {
+        return -1;
+    }
+
+    // The entries point into the command line, which stays around as long as the options
+    char* strtok_state;
+    char* token = strtok_r(services, ",", &strtok_state);
+    while (token != NULL) {
+        size_t token_len;
+        token = trim(token, strlen(token), &token_len);
+        if (token_len > 0) {
+            char* equals = strchr(token, '=');
+            if (equals == NULL) {
+                if (!is_valid_service(token)) {
+                    goto invalid;
+                }
+                options->pam_service = token;
+            } else {
+                *equals = 0;
+                char* user = trim(token, equals - token, &token_len);
+                char* service = trim(equals + 1, strlen(equals + 1), &token_len);
+                if (*user == 0 || !is_valid_service(service)) {
+                    goto invalid;
+                }
+                options->user_services[options->user_services_size].user = user;
+                options->user_services[options->user_services_size].service = service;
+                options->user_services_size++;
+            }
+        }
+        token = strtok_r(NULL, ",", &strtok_state);
+    }
+
+    return 0;
+
+invalid:
+    fprintf(stderr, "Invalid PAM service for --pam-service: %s\n", token);
+    errno = EINVAL;
+    return -1;
+}
+
+const char* options_pam_service(const struct options* options, const char* user) {
+    if (options->rescue_active) {
+        return PAM_SERVICE;
+    }
+    if (options->smartcard_active) {
+        return options->smartcard_service;
+    }
+    if (options->step_up) {
+        return options->step_up_service;
+    }
+    if (user != NULL) {
+        for (int i = 0; i < options->user_services_size; i++) {
+            if (strcmp(options->user_services[i].user, user) == 0) {
+                return options->user_services[i].service;
+            }
+        }
+    }
+    return options->pam_service;
+}
+
+/**
+ *    Adds a member of one of the `--groups` to the users allowed to unlock, unless already there.
+ *    Unlike `split_users`, we cannot know in advance how many users there will be, so the list grows as needed.
+ */
+static int add_group_member(struct options* options, const char* name, const char* group) {
+    if (options_has_user(options, name)) {
+        return 0;
+    }
+
+    // Keep room for root, which is added at the end of the parsing
+    if (resize_users(options, options->users_size + 2) < 0) {
+        return -1;
+    }
+    char origin[strlen("group:") + strlen(group) + 1];
+    snprintf(origin, sizeof(origin), "group:%s", group);
+    return add_user(options, name, origin);
+}
+
+static int expand_groups(struct options* options) {
+
+    // The list of users might still be empty, and must have room for root anyway
+    if (options->users == NULL && resize_users(options, 1) < 0) {
+        return -1;
+    }
+
+    char* strtok_state;
+    char* token = strtok_r(options->groups, ",", &strtok_state);
+    while (token != NULL) {
+        size_t token_len;
+        token = trim(token, strlen(token), &token_len);
+        if (token_len > 0) {
+
+            // Like for users, numeric entries are GIDs
+            unsigned int gid;
+            errno = 0;
+            struct group* group = isdigit((unsigned char)*token) && parse_uint(token, &gid) == 0
+                                ? getgrgid((gid_t)gid)
+                                : getgrnam(token);
+            if (group == NULL) {
+                if (!options->ignore_unknown_users) {
+                    fprintf(stderr,
+                        "Group %s does not exist. The station will not be locked.\n"
+                        "To ignore unknown groups, pass --ignore-unknown-users.\n",
+                        token
+                    );
+                    if (errno == 0) {
+                        errno = ENOENT;
+                    }
+                    return -1;
+                }
+                fprintf(stderr, "Warning: group %s does not exist, ignoring it.\n", token);
+                token = strtok_r(NULL, ",", &strtok_state);
+                continue;
+            }
+
+            // Supplementary members are listed in the group, leaving out the members that no longer exist
+            gid = group->gr_gid;
+            for (char** member = group->gr_mem; *member != NULL; member++) {
+                if (getpwnam(*member) != NULL && add_group_member(options, *member, group->gr_name) < 0) {
+                    return -1;
+                }
+            }
+
+            // while users whose primary group it is have to be looked for
+            setpwent();
+            struct passwd* passwd;
+            while ((passwd = getpwent()) != NULL) {
+                if (passwd->pw_gid == gid && add_group_member(options, passwd->pw_name, group->gr_name) < 0) {
+                    endpwent();
+                    return -1;
+                }
+            }
+            endpwent();
         }
         token = strtok_r(NULL, ",", &strtok_state);
     }
 
-    // At the end of the list we add the root user
-    options->users[i] = root_username;
-    i++;
+    return 0;
+}
+
+static int validate_users(struct options* options) {
+    int i = 0;
+    while (i < options->users_size) {
+
+        // Root is added by us, no need to check it
+        if (strcmp(options->users[i], root_username) == 0 || getpwnam(options->users[i]) != NULL) {
+            i++;
+            continue;
+        }
 
-    options->users_size = i;
+        if (!options->ignore_unknown_users) {
+            fprintf(stderr,
+                "User %s does not exist. The station will not be locked.\n"
+                "To ignore unknown users, pass --ignore-unknown-users.\n",
+                options->users[i]
+            );
+            errno = ENOENT;
+            return -1;
+        }
 
+        // Drop the user from the list
+        fprintf(stderr, "Warning: user %s does not exist, ignoring it.\n", options->users[i]);
+        free(options->users[i]);
+        free(options->users_origins[i]);
+        memmove(&options->users[i], &options->users[i + 1], (options->users_size - i - 1) * sizeof(char*));
+        memmove(&options->users_origins[i], &options->users_origins[i + 1], (options->users_size - i - 1) * sizeof(char*));
+        options->users_size--;
+    }
     return 0;
 }
 
+/**
+ *    Tells why `user` cannot be used to unlock the station.
+ *
+ *    @return A description of the problem, or `NULL` if the user can unlock
+ *            or if we cannot tell (for example, the password is not stored in the shadow file).
+ */
+static const char* unusable_reason(const char* user) {
+    struct spwd* shadow_entry = getspnam(user);
+    if (shadow_entry == NULL || shadow_entry->sp_pwdp == NULL) {
+        return NULL;
+    }
+
+    // Check that the password exists and that it's not locked
+    const char* pwd = shadow_entry->sp_pwdp;
+    if (strlen(pwd) == 0) {
+        return "it does not have a password";
+    }
+    if (pwd[0] == '!' || pwd[0] == '*') {
+        return "its password is locked";
+    }
+
+    // Expiration date is in days since the epoch
+    if (shadow_entry->sp_expire >= 0 && shadow_entry->sp_expire <= time(NULL) / (60 * 60 * 24)) {
+        return "its account has expired";
+    }
+
+    return NULL;
+}
+
+int options_user_can_authenticate(const char* user, const char** reason) {
+    struct spwd* shadow_entry = getspnam(user);
+    if (shadow_entry == NULL || shadow_entry->sp_pwdp == NULL) {
+        return -1;
+    }
+    *reason = unusable_reason(user);
+    return *reason == NULL;
+}
+
+static int check_users_can_unlock(struct options* options) {
+    const char* reasons[options->users_size];
+    int lookup_failed = 0;
+    for (int i = 0; i < options->users_size; i++) {
+        int can_authenticate = options_user_can_authenticate(options->users[i], &reasons[i]);
+        if (can_authenticate > 0) {
+            return 0;
+        }
+
+        // A password we cannot check is not assumed to work
+        if (can_authenticate < 0) {
+            reasons[i] = "its password cannot be read from the shadow file";
+            lookup_failed = 1;
+        }
+    }
+
+    // Nobody can authenticate: passwordless root used to be the only case we knew of,
+    // so --allow-passwordless-root still overrides it, but only if the password of root could be checked
+    int only_root = options->users_size == 1 && strcmp(options->users[0], root_username) == 0;
+    int passwordless_root = only_root && !lookup_failed;
+    int overridden = options->force || (passwordless_root && options->allow_passwordless_root);
+
+    fputs(
+        overridden ? "Warning: none of the users allowed to unlock can authenticate:\n"
+                   : "None of the users allowed to unlock can authenticate:\n",
+        stderr
+    );
+    for (int i = 0; i < options->users_size; i++) {
+        fprintf(stderr, "  - %s: %s\n", options->users[i], reasons[i]);
+    }
+    if (overridden) {
+        return 0;
+    }
+
+    fprintf(stderr,
+        "The station will not be locked.\n"
+        "To override this security measure, pass --force%s.\n",
+        passwordless_root ? " or --allow-passwordless-root" : ""
+    );
+    errno = EPERM;
+    return -1;
+}
+
 struct options* options_parse(int argc, char** argv) {
 
     // Allocates the sturcture
@@ -142,17 +848,104 @@ struct options* options_parse(int argc, char** argv) {
     options->block_sysrequests = 1;
     options->block_vt_switch = 1;
     options->block_kernel_messages = 1;
+    options->best_effort = 0;
+    options->sysrq_path = "/proc/sys/kernel/sysrq";
+    options->sysrq_mask = 0;
+    options->printk_path = "/proc/sys/kernel/printk";
     options->users = NULL;
+    options->users_origins = NULL;
+    options->users_size = 0;
+    options->groups = NULL;
+    options->ignore_unknown_users = 0;
+    options->no_root = 0;
+    options->pam_username = 0;
+    options->ask_username = 0;
+    options->hide_users = 0;
+    options->rescue_shell = 0;
     options->allow_passwordless_root = 0;
+    options->force = 0;
+    options->title = NULL;
     options->message = NULL;
+    options->markup = 0;
+    options->greeter_command = NULL;
+    options->greeter_interval = 0;
+    options->selection_message = _("The following users are authorized to unlock:");
+    options->prompt = NULL;
+    options->busy_message = _("Authenticating...");
+    options->no_clear = 0;
+    options->no_clear_row = 0;
+    options->center = 0;
+    options->box = 0;
+    options->show_last = 0;
+    options->show_vt = 0;
+    strcpy(options->theme.prompt, "");
+    strcpy(options->theme.user, HIGHLIGHT);
+    strcpy(options->theme.error, ERROR_HIGHLIGHT);
     options->dark_mode = 0;
     options->quick_mode = 0;
+    options->echo_mode = ECHO_HIDDEN;
+    options->mask_timeout = 0;
+    options->mask_dots = 0;
+    options->max_input = 512;
+    options->max_attempts = 0;
+    options->fail_delay = 3;
+    options->fail_message = NULL;
+    options->bell = 0;
+    options->quiet = 0;
+    options->flash = 0;
+    options->prompt_timeout = 0;
+    options->pam_timeout = 0;
+    options->pam_session = 0;
+    options->pam_service = PAM_SERVICE;
+    options->user_services = NULL;
+    options->user_services_size = 0;
+    options->step_up_service = NULL;
+    options->smartcard_service = NULL;
+    options->smartcard_active = 0;
+    options->rescue_active = 0;
+    options->step_up_after = 0;
+    options->step_up_window = 3600;
+    options->step_up = 0;
+    options->password_fifo = NULL;
+    options->password_hash = NULL;
+    options->duress_file = NULL;
+    options->emergency_file = NULL;
+    options->emergency_key = NULL;
+    options->duress_command = NULL;
+    options->duress_action = DURESS_UNLOCK;
+    options->on_fail_command = NULL;
+    options->max_lock_time = 0;
+    options->max_lock_action = MAX_LOCK_WARN;
     options->background = NULL;
     options->background_fill = RESIZE_FILL;
-    options->fbdev = "/dev/fb0";
+    options->background_interval = 60;
+    options->background_color = NULL;
+    options->fbdev = NULL;
+    options->all_fbdevs = 0;
+    options->hide_cursor = 0;
     options->dont_detach = 0;
+    options->lock_timeout = 30;
+    options->kill_on_lock_timeout = 0;
+    options->in_place = 0;
+    options->io_stdio = 0;
+    options->all_seats = 0;
+    options->switch_back_to = 0;
+    options->return_vt = 0;
+    options->paranoid = 0;
+    options->idle = 0;
+    options->once = 0;
+    options->pidfile = NULL;
+    options->verbose = 0;
+    options->timings = 0;
+    options->check = 0;
+    options->list_vts = 0;
+    options->list_users = 0;
+    options->format = FORMAT_HUMAN;
     options->show_help = 0;
     options->show_version = 0;
+    options->show_build_info = 0;
+
+    int no_hide_cursor = 0;
 
     // Args parsing
     int opt;
@@ -202,9 +995,377 @@ struct options* options_parse(int argc, char** argv) {
                 if (strcmp("allow-passwordless-root", opt_name) == 0) {
                     options->allow_passwordless_root = 1;
                     break;
+                } else if (strcmp("groups", opt_name) == 0) {
+                    options->groups = optarg;
+                    break;
+                } else if (strcmp("rescue-shell", opt_name) == 0) {
+                    options->rescue_shell = 1;
+                    break;
+                } else if (strcmp("ask-username", opt_name) == 0) {
+                    options->ask_username = 1;
+                    break;
+                } else if (strcmp("hide-users", opt_name) == 0) {
+                    options->hide_users = 1;
+                    break;
+                } else if (strcmp("pam-username", opt_name) == 0) {
+                    options->pam_username = 1;
+                    break;
+                } else if (strcmp("no-root", opt_name) == 0) {
+                    options->no_root = 1;
+                    break;
+                } else if (strcmp("ignore-unknown-users", opt_name) == 0) {
+                    options->ignore_unknown_users = 1;
+                    break;
+                } else if (strcmp("force", opt_name) == 0) {
+                    options->force = 1;
+                    break;
+                } else if (strcmp("no-clear", opt_name) == 0) {
+                    options->no_clear = 1;
+                    break;
+                } else if (strcmp("no-clear-row", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->no_clear_row) == 0 && options->no_clear_row > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --no-clear-row: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("center", opt_name) == 0) {
+                    options->center = 1;
+                    break;
+                } else if (strcmp("box", opt_name) == 0) {
+                    options->center = 1;
+                    options->box = 1;
+                    break;
+                } else if (strncmp("color-", opt_name, 6) == 0) {
+                    int is_prompt = strcmp("color-prompt", opt_name) == 0;
+                    char* style = is_prompt ? options->theme.prompt :
+                                  strcmp("color-user", opt_name) == 0 ? options->theme.user : options->theme.error;
+                    if (options_parse_color(optarg, !is_prompt, style) == 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --%s: %s\n", opt_name, optarg);
+                    // Fall to default
+                } else if (strcmp("sysrq-path", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->sysrq_path = optarg;
+                    break;
+                } else if (strcmp("best-effort", opt_name) == 0) {
+                    options->best_effort = 1;
+                    break;
+                } else if (strcmp("sysrq-mask", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->sysrq_mask) == 0 && options->sysrq_mask <= MAX_SYSRQ_MASK) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --sysrq-mask: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("printk-path", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->printk_path = optarg;
+                    break;
+                } else if (strcmp("idle", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->idle) == 0 && options->idle > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --idle: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("once", opt_name) == 0) {
+                    options->once = 1;
+                    break;
+                } else if (strcmp("pidfile", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->pidfile = optarg;
+                    break;
+                } else if (strcmp("build-info", opt_name) == 0) {
+                    options->show_build_info = 1;
+                    break;
+                } else if (strcmp("verbose", opt_name) == 0) {
+                    options->verbose = 1;
+                    break;
+                } else if (strcmp("timings", opt_name) == 0) {
+                    options->timings = 1;
+                    break;
+                } else if (strcmp("check", opt_name) == 0) {
+                    options->check = 1;
+                    break;
+                } else if (strcmp("list-users", opt_name) == 0) {
+                    options->list_users = 1;
+                    break;
+                } else if (strcmp("list-vts", opt_name) == 0) {
+                    options->list_vts = 1;
+                    break;
+                } else if (strcmp("format", opt_name) == 0) {
+                    if (strcmp("human", optarg) == 0) {
+                        options->format = FORMAT_HUMAN;
+                        break;
+                    } else if (strcmp("json", optarg) == 0) {
+                        options->format = FORMAT_JSON;
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --format: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("show-last", opt_name) == 0) {
+                    options->show_last = 1;
+                    break;
+                } else if (strcmp("show-vt", opt_name) == 0) {
+                    options->show_vt = 1;
+                    break;
+                } else if (strcmp("title", opt_name) == 0) {
+                    options->title = optarg;
+                    break;
+                } else if (strcmp("markup", opt_name) == 0) {
+                    options->markup = 1;
+                    break;
+                } else if (strcmp("selection-message", opt_name) == 0) {
+                    options->selection_message = optarg;
+                    break;
+                } else if (strcmp("prompt", opt_name) == 0) {
+                    options->prompt = optarg;
+                    break;
+                } else if (strcmp("busy-message", opt_name) == 0) {
+                    options->busy_message = optarg;
+                    break;
+                } else if (strcmp("mask", opt_name) == 0) {
+                    options->echo_mode = ECHO_ASTERISK;
+                    break;
+                } else if (strcmp("echo-mode", opt_name) == 0 || strcmp("mask-style", opt_name) == 0) {
+                    // --mask-style is the older name, which only knew about stars and dots
+                    int mask_style = strcmp("mask-style", opt_name) == 0;
+                    if (strcmp(mask_style ? "stars" : "asterisk", optarg) == 0) {
+                        options->echo_mode = ECHO_ASTERISK;
+                        break;
+                    } else if (!mask_style && strcmp("hidden", optarg) == 0) {
+                        options->echo_mode = ECHO_HIDDEN;
+                        break;
+                    } else if (!mask_style && strcmp("reveal", optarg) == 0) {
+                        options->echo_mode = ECHO_REVEAL;
+                        break;
+                    } else if (strncmp("dots:", optarg, 5) == 0 && parse_uint(optarg + 5, &options->mask_dots) == 0 &&
+                            options->mask_dots > 0 && options->mask_dots <= MAX_MASK_DOTS) {
+                        options->echo_mode = ECHO_DOTS;
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --%s: %s\n", opt_name, optarg);
+                    // Fall to default
+                } else if (strcmp("max-input", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->max_input) == 0 && options->max_input > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --max-input: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("mask-timeout", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->mask_timeout) == 0 && options->mask_timeout > 0 && options->mask_timeout <= INT_MAX) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --mask-timeout: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("bell", opt_name) == 0) {
+                    options->bell = 1;
+                    break;
+                } else if (strcmp("quiet", opt_name) == 0) {
+                    options->quiet = 1;
+                    break;
+                } else if (strcmp("flash", opt_name) == 0) {
+                    options->flash = 1;
+                    break;
+                } else if (strcmp("greeter-command", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->greeter_command = optarg;
+                    break;
+                } else if (strcmp("greeter-interval", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->greeter_interval) == 0 && options->greeter_interval > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --greeter-interval: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("fail-delay", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->fail_delay) == 0 && options->fail_delay <= MAX_FAIL_DELAY) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --fail-delay: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("fail-message", opt_name) == 0) {
+                    options->fail_message = optarg;
+                    break;
+                } else if (strcmp("max-attempts", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->max_attempts) == 0 && options->max_attempts > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --max-attempts: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("timeout", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->prompt_timeout) == 0 && options->prompt_timeout > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --timeout: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("max-lock-time", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->max_lock_time) == 0 && options->max_lock_time > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --max-lock-time: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("max-lock-action", opt_name) == 0) {
+                    if (strcmp("warn", optarg) == 0) {
+                        options->max_lock_action = MAX_LOCK_WARN;
+                        break;
+                    } else if (strcmp("suspend", optarg) == 0 || strcmp("poweroff", optarg) == 0) {
+
+                        // systemctl runs as root, whoever started us
+                        if (require_root("max-lock-action suspend and poweroff") < 0) {
+                            goto error;
+                        }
+                        options->max_lock_action = strcmp("suspend", optarg) == 0 ? MAX_LOCK_SUSPEND : MAX_LOCK_POWEROFF;
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --max-lock-action: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("password-hash", opt_name) == 0) {
+                    options->password_hash = optarg;
+                    break;
+                } else if (strcmp("password-fifo", opt_name) == 0) {
+                    options->password_fifo = optarg;
+                    break;
+                } else if (strcmp("emergency-file", opt_name) == 0) {
+                    options->emergency_file = optarg;
+                    break;
+                } else if (strcmp("emergency-key", opt_name) == 0) {
+                    options->emergency_key = optarg;
+                    break;
+                } else if (strcmp("duress-file", opt_name) == 0) {
+                    options->duress_file = optarg;
+                    break;
+                } else if (strcmp("duress-command", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->duress_command = optarg;
+                    break;
+                } else if (strcmp("on-fail", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->on_fail_command = optarg;
+                    break;
+                } else if (strcmp("duress-action", opt_name) == 0) {
+                    if (strcmp("unlock", optarg) == 0) {
+                        options->duress_action = DURESS_UNLOCK;
+                        break;
+                    } else if (strcmp("fail", optarg) == 0) {
+                        options->duress_action = DURESS_FAIL;
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --duress-action: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("pam-session", opt_name) == 0) {
+                    options->pam_session = 1;
+                    break;
+                } else if (strcmp("pam-service", opt_name) == 0) {
+                    if (require_root(opt_name) < 0 || split_user_services(options, optarg) < 0) {
+                        goto error;
+                    }
+                    break;
+                } else if (strcmp("step-up-service", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    if (is_valid_service(optarg)) {
+                        options->step_up_service = optarg;
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --step-up-service: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("smartcard-service", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    if (is_valid_service(optarg)) {
+                        options->smartcard_service = optarg;
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --smartcard-service: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("step-up-after", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->step_up_after) == 0 && options->step_up_after > 0 && options->step_up_after <= MAX_STEP_UP_AFTER) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --step-up-after: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("step-up-window", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->step_up_window) == 0 && options->step_up_window > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --step-up-window: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("pam-timeout", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->pam_timeout) == 0 && options->pam_timeout > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --pam-timeout: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("bg-color", opt_name) == 0) {
+                    options->background_color = optarg;
+                    break;
+                } else if (strcmp("switch-back-to", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->switch_back_to) == 0 && options->switch_back_to > 0 && options->switch_back_to <= MAX_VT_NUMBER) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --switch-back-to: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("return-vt", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->return_vt) == 0 && options->return_vt > 0 && options->return_vt <= MAX_VT_NUMBER) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --return-vt: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("paranoid", opt_name) == 0) {
+                    options->paranoid = 1;
+                    break;
+                } else if (strcmp("all-seats", opt_name) == 0) {
+                    if (require_root(opt_name) < 0) {
+                        goto error;
+                    }
+                    options->all_seats = 1;
+                    break;
+                } else if (strcmp("lock-timeout", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->lock_timeout) == 0 && options->lock_timeout <= MAX_LOCK_TIMEOUT) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --lock-timeout: %s\n", optarg);
+                    // Fall to default
+                } else if (strcmp("kill-on-lock-timeout", opt_name) == 0) {
+                    options->kill_on_lock_timeout = 1;
+                    break;
+#ifdef SIMPLYLOCK_TESTING
+                // It disables the whole lock: the installed binary, which is setuid root, must never accept it
+                } else if (strcmp("io-stdio", opt_name) == 0) {
+                    options->io_stdio = 1;
+                    break;
+#endif
+                } else if (strcmp("in-place", opt_name) == 0) {
+                    options->in_place = 1;
+                    break;
+                } else if (strcmp("no-hide-cursor", opt_name) == 0) {
+                    no_hide_cursor = 1;
+                    break;
                 } else if (strcmp("fbdev", opt_name) == 0) {
                     options->fbdev = optarg;
                     break;
+                } else if (strcmp("all-fbdevs", opt_name) == 0) {
+                    options->all_fbdevs = 1;
+                    break;
+                } else if (strcmp("background-interval", opt_name) == 0) {
+                    if (parse_uint(optarg, &options->background_interval) == 0 && options->background_interval > 0) {
+                        break;
+                    }
+                    fprintf(stderr, "Invalid value for --background-interval: %s\n", optarg);
+                    // Fall to default
                 } else if (strcmp("background-fill", opt_name) == 0) {
                     if (strcmp("center", optarg) == 0) {
                         options->background_fill = CENTER;
@@ -226,57 +1387,179 @@ struct options* options_parse(int argc, char** argv) {
 
             default:
                 print_usage(argc, argv);
+                errno = EINVAL;
                 goto error;
         }
     }
 
-    // If no user was manually provided, we use the user that started the application
+    if (options->pam_username && options->ask_username) {
+        fprintf(stderr, "--pam-username and --ask-username cannot be used together.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    if (options->smartcard_service != NULL && !smartcard_supported()) {
+        fprintf(stderr, "--smartcard-service is not available: simplylock has been built without smartcard support.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // The resident process of idle mode would hold the file, while every lock happens in another process
+    if (options->pidfile != NULL && options->idle > 0) {
+        fprintf(stderr, "--pidfile cannot be used with --idle.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // Unlocks can only be counted by the resident process of idle mode
+    if (options->step_up_service != NULL && (options->step_up_after == 0 || options->idle == 0)) {
+        fprintf(stderr, "--step-up-service requires --step-up-after and --idle.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // Without PAM, there is nothing for the PAM options to configure
+    if (options->password_hash != NULL && (options->pam_username || options->pam_session || options->pam_timeout > 0 ||
+            options->user_services_size > 0 || strcmp(options->pam_service, PAM_SERVICE) != 0 ||
+            options->step_up_service != NULL || options->smartcard_service != NULL || options->rescue_shell)) {
+        fprintf(stderr, "--password-hash cannot be used with --rescue-shell, --step-up-service, --smartcard-service or the --pam-* options.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // A duress password is only useful to trigger the command
+    if ((options->duress_file == NULL) != (options->duress_command == NULL)) {
+        fprintf(stderr, "--duress-file and --duress-command must be used together.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // A file touch must never be enough to unlock
+    if ((options->emergency_file == NULL) != (options->emergency_key == NULL)) {
+        fprintf(stderr, "--emergency-file and --emergency-key must be used together.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    if (options->fbdev != NULL && options->all_fbdevs) {
+        fprintf(stderr, "--fbdev and --all-fbdevs cannot be used together.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // Centered text and backgrounds take the whole screen, which has to be cleared
+    if (options->no_clear_row > 0 && !options->no_clear) {
+        fprintf(stderr, "--no-clear-row requires --no-clear.\n");
+        errno = EINVAL;
+        goto error;
+    }
+    if (options->no_clear && (options->center || options->background != NULL || options->background_color != NULL)) {
+        fprintf(stderr, "--no-clear cannot be used with --center, --box or a background.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // For tests only: nothing is locked, and the authentication happens on the standard streams
+    // as on a terminal locked in place, so that the whole process can be driven through pipes
+    if (options->io_stdio) {
+        if (options->idle > 0 || options->background != NULL || options->background_color != NULL) {
+            fprintf(stderr, "--io-stdio cannot be used with --idle or a background.\n");
+            errno = EINVAL;
+            goto error;
+        }
+        options->block_sysrequests = 0;
+        options->block_vt_switch = 0;
+        options->block_kernel_messages = 0;
+        options->dark_mode = 0;
+        options->paranoid = 0;
+        options->in_place = 1;
+    }
+
+    // The shell we have been started from must not read from the terminal while it is locked
+    if (options->in_place) {
+        options->dont_detach = 1;
+    }
+
+    // The cursor would be drawn over the background
+    options->hide_cursor = !no_hide_cursor && (options->background != NULL || options->background_color != NULL);
+
+    // Members of the groups are allowed to unlock, as they are at the time of locking
+    if (options->groups != NULL && expand_groups(options) < 0) {
+        goto error;
+    }
+
+    // If no user or group was manually provided, we use the user that started the application
     if (options->users == NULL) {
+        if (resize_users(options, 2) < 0) {
+            goto error;
+        }
         uid_t uid = getuid();
         if (uid != 0) {
+            errno = 0;
             struct passwd* passwd = getpwuid(uid);
             if (passwd == NULL) {
+                if (errno == 0) {
+                    errno = ENOENT;
+                }
+                perror("getpwuid");
+                goto error;
+            }
+            if (add_user(options, passwd->pw_name, "current") < 0) {
                 goto error;
             }
-            options->users = (char**)malloc(2 * sizeof(char*));
-            options->users[0] = passwd->pw_name;
-            options->users[1] = root_username;
-            options->users_size = 2;
-        } else {
-            options->users = (char**)malloc(sizeof(char*));
-            options->users[0] = root_username;
-            options->users_size = 1;
         }
     }
 
-    // Special check for the root user:
-    // If only root can unlock the pc, check that it has a password.
-    // Ubuntu, for example, has a passwordless root user by default.
-    if (options->users_size == 1 && memcmp(options->users[0], root_username, 5) == 0) {
-        struct spwd* shadow_entry = getspnam(root_username);
-        if (shadow_entry == NULL || shadow_entry->sp_pwdp == NULL) {
+    // At the end of the list we add the root user, unless explicitly asked not to.
+    // There's always space for it, since it has been taken into account when allocating the list.
+    if (!options->no_root && !options_has_user(options, root_username) && add_user(options, root_username, "root") < 0) {
+        goto error;
+    }
+
+    // Check that all the users allowed to unlock actually exist
+    if (validate_users(options) < 0) {
+        goto error;
+    }
+
+    // A service mapped to somebody who cannot unlock is most likely a typo in the user name
+    for (int i = 0; i < options->user_services_size; i++) {
+        if (!options_has_user(options, options->user_services[i].user)) {
+            fprintf(stderr,
+                "User %s has a PAM service, but is not allowed to unlock. The station will not be locked.\n",
+                options->user_services[i].user
+            );
+            errno = EINVAL;
             goto error;
         }
+    }
 
-        // Check that a password exists for the root user and that it's not locked
-        char* pwd = shadow_entry->sp_pwdp;
-        if (strlen(pwd) == 0 || pwd[0] == '!' || pwd[0] == '*') {
-            if (!options->allow_passwordless_root) {
-                fprintf(stderr,
-                    "Only root user can unlock, and it does not have a valid password. The station will not be locked.\n"
-                    "To override this security measure, pass --allow-passwordless-root.\n"
-                );
-                goto error;
-            }
-        }
+    // Without root as a fallback, someone must be able to unlock
+    if (options->users_size == 0) {
+        fprintf(stderr, "No user is allowed to unlock. The station will not be locked.\n");
+        errno = EINVAL;
+        goto error;
+    }
+
+    // Make sure that at least one of the users can actually authenticate,
+    // otherwise the station could never be unlocked.
+    // Ubuntu, for example, has a passwordless root user by default.
+    // With --password-hash, the passwords of the users are never used,
+    // and --list-users shows who cannot authenticate instead of refusing.
+    if (options->password_hash == NULL && !options->list_users && check_users_can_unlock(options) < 0) {
+        goto error;
     }
 
     return options;
 
 error:
 
+    // Preserve the errno of the original error while cleaning up
     if (options != NULL) {
+        int saved_errno = errno;
+        free_users(options);
+        free_user_services(options);
         free(options);
+        errno = saved_errno;
     }
 
     return NULL;
@@ -284,8 +1567,7 @@ error:
 }
 
 void options_free(struct options* options) {
-    // We do not free the single user names because they are pointers
-    // to static strings or the arg vector.
-    free(options->users);
+    free_users(options);
+    free_user_services(options);
     free(options);
 }
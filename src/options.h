@@ -3,6 +3,62 @@
 
 #include "bg.h"
 
+#define SIMPLYLOCK_VERSION "0.6.0"
+#define THEME_STYLE_SIZE 32
+#define PAM_SERVICE "simplylock"
+
+/**
+ *    Escape sequences used to style the different parts of the text.
+ */
+struct theme {
+    char prompt[THEME_STYLE_SIZE];
+    char user[THEME_STYLE_SIZE];
+    char error[THEME_STYLE_SIZE];
+};
+
+/**
+ *    What to do when the station has been locked for too long.
+ */
+enum max_lock_action_t {
+    MAX_LOCK_WARN,     // Ring the bell and print a warning
+    MAX_LOCK_SUSPEND,  // Suspend the machine
+    MAX_LOCK_POWEROFF  // Power the machine off
+};
+
+/**
+ *    PAM service used to authenticate a specific user.
+ */
+struct user_service {
+    char* user;
+    char* service;
+};
+
+/**
+ *    What is shown while a password is typed.
+ */
+enum echo_mode_t {
+    ECHO_HIDDEN,   // Nothing
+    ECHO_ASTERISK, // A `*` for every character
+    ECHO_DOTS,     // A fixed row of dots, filled one per character
+    ECHO_REVEAL    // Every character for a moment, then a `*` in its place
+};
+
+/**
+ *    What happens after the duress password has been entered, besides running `--duress-command`.
+ */
+enum duress_action_t {
+    DURESS_UNLOCK, // Unlock the station as after a successful authentication
+    DURESS_FAIL    // Stay locked as after a failed authentication
+};
+
+/**
+ *    Format of the output of `--check` and `--list-vts`.
+ */
+enum output_format_t {
+    FORMAT_HUMAN,
+    FORMAT_JSON
+};
+
 /**
  *    Structure containing all the SimplyLock options.
  */
@@ -10,18 +66,100 @@ struct options {
     unsigned int block_sysrequests;
     unsigned int block_vt_switch;
     unsigned int block_kernel_messages;
+    unsigned int best_effort;
+    char* sysrq_path;
+    unsigned int sysrq_mask;
+    char* printk_path;
     char** users;
+    char** users_origins;
     unsigned int users_size;
+    char* groups;
+    unsigned int ignore_unknown_users;
+    unsigned int no_root;
+    unsigned int pam_username;
+    unsigned int ask_username;
+    unsigned int hide_users;
+    unsigned int rescue_shell;
     unsigned int allow_passwordless_root;
+    unsigned int force;
+    char* title;
     char* message;
+    unsigned int markup;
+    char* greeter_command;
+    unsigned int greeter_interval;
+    char* selection_message;
+    char* prompt;
+    char* busy_message;
+    unsigned int no_clear;
+    unsigned int no_clear_row;
+    unsigned int center;
+    unsigned int box;
+    struct theme theme;
+    unsigned int show_last;
+    unsigned int show_vt;
     unsigned int dark_mode;
     unsigned int quick_mode;
+    enum echo_mode_t echo_mode;
+    unsigned int mask_timeout;
+    unsigned int mask_dots;
+    unsigned int max_input;
+    unsigned int max_attempts;
+    unsigned int fail_delay;
+    char* fail_message;
+    unsigned int bell;
+    unsigned int quiet;
+    unsigned int flash;
+    unsigned int prompt_timeout;
+    unsigned int pam_timeout;
+    unsigned int pam_session;
+    char* pam_service;
+    struct user_service* user_services;
+    unsigned int user_services_size;
+    char* step_up_service;
+    unsigned int step_up_after;
+    unsigned int step_up_window;
+    unsigned int step_up;
+    char* smartcard_service;
+    unsigned int smartcard_active;
+    unsigned int rescue_active;
+    char* password_fifo;
+    char* password_hash;
+    char* duress_file;
+    char* emergency_file;
+    char* emergency_key;
+    char* duress_command;
+    enum duress_action_t duress_action;
+    char* on_fail_command;
+    unsigned int max_lock_time;
+    enum max_lock_action_t max_lock_action;
     char* background;
     enum background_fill_t background_fill;
+    unsigned int background_interval;
+    char* background_color;
     char* fbdev;
+    unsigned int all_fbdevs;
+    unsigned int hide_cursor;
     unsigned int dont_detach;
+    unsigned int lock_timeout;
+    unsigned int kill_on_lock_timeout;
+    unsigned int in_place;
+    unsigned int io_stdio;
+    unsigned int all_seats;
+    unsigned int switch_back_to;
+    unsigned int return_vt;
+    unsigned int paranoid;
+    unsigned int idle;
+    unsigned int once;
+    char* pidfile;
+    unsigned int verbose;
+    unsigned int timings;
+    unsigned int check;
+    unsigned int list_vts;
+    unsigned int list_users;
+    enum output_format_t format;
     unsigned int show_help;
     unsigned int show_version;
+    unsigned int show_build_info;
 };
 
 /**
@@ -34,6 +172,47 @@ struct options {
  */
 struct options* options_parse(int argc, char** argv);
 
+/**
+ *    Checks whether a user is allowed to unlock.
+ *
+ *    @param  options SimplyLock options.
+ *    @param  name    Name of the user.
+ *    @return `1` if the user is one of `options->users`, `0` otherwise.
+ */
+int options_has_user(const struct options* options, const char* name);
+
+/**
+ *    Tells whether a user can authenticate with their password, as far as the shadow file tells.
+ *
+ *    @param  user   Name of the user.
+ *    @param  reason Filled with a description of the problem if the user cannot authenticate.
+ *    @return `1` if the user can authenticate, `0` if not, `-1` if it cannot be told
+ *            (for example, the shadow file cannot be read without being root).
+ */
+int options_user_can_authenticate(const char* user, const char** reason);
+
+/**
+ *    Tells which PAM service authenticates a user.
+ *
+ *    @param  options SimplyLock options.
+ *    @param  user    Name of the user, or `NULL` if PAM will ask for it.
+ *    @return The default service if `options->rescue_active` is set, the --smartcard-service
+ *            if `options->smartcard_active` is set, the --step-up-service if `options->step_up` is set,
+ *            otherwise the service mapped to the user with --pam-service, or the default one.
+ */
+const char* options_pam_service(const struct options* options, const char* user);
+
+/**
+ *    Converts a color name or a 256-color index into the escape sequence to select it,
+ *    optionally making the text bold too.
+ *
+ *    @param  str   Color name (e.g. `red`, `light-blue`, `default`) or index between 0 and 255.
+ *    @param  bold  `1` to make the text bold too.
+ *    @param  style Buffer of `THEME_STYLE_SIZE` bytes filled with the escape sequence.
+ *    @return `0` in case of success, `-1` if the color is not valid.
+ */
+int options_parse_color(const char* str, int bold, char* style);
+
 /**
  *    Releases all the resources allocated for a `struct options`.
  *
@@ -0,0 +1,138 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <limits.h>
+#include <unistd.h>
+
+#include "selection.h"
+#include "i18n.h"
+
+#define USER_LIST_RESERVED_ROWS 6
+
+int selection_read_key(struct vt* vt) {
+    unsigned char c;
+    ssize_t n;
+
+    vt_setcanonical(vt, 0);
+    while ((n = read(vt->fd, &c, 1)) == -1 && errno == EINTR);
+    vt_setcanonical(vt, 1);
+    if (n <= 0) {
+        return -1;
+    }
+
+    // Discard the rest of escape sequences (arrows, function keys...),
+    // otherwise the digits they contain would be mistaken for a selection.
+    if (c == '\033') {
+        vt_flush(vt);
+    }
+
+    return c >= '1' && c <= '9' ? c - '1' : INT_MAX;
+}
+
+int selection_read_line(struct vt* vt) {
+    char* line = NULL;
+    size_t n = 0;
+    vt_setecho(vt, 1);
+    if (getline(&line, &n, vt->stream) < 0) {
+        vt_setecho(vt, 0);
+        free(line);
+        return -1;
+    }
+    vt_setecho(vt, 0);
+
+    char* tmp;
+    int index = strtol(line, &tmp, 10);
+    if (tmp == line || *tmp != '\n' || index <= 0) {
+        index = INT_MAX;
+    } else {
+        index--;
+    }
+    free(line);
+
+    return index;
+}
+
+static void print_user(struct options* options, int i, const char* user, int width) {
+    int num_width = snprintf(NULL, 0, "%d", options->users_size);
+    if (options->users[i] == user) {
+        fprintf(stdout, "%*d. %s%-*s" RESET, num_width, i + 1, options->theme.user, width, options->users[i]);
+    } else {
+        fprintf(stdout, "%*d. %-*s", num_width, i + 1, width, options->users[i]);
+    }
+}
+
+void selection_print_users(struct options* options, struct vt* vt, const char* user) {
+    int n = options->users_size;
+    fprintf(stdout, "\n%s\n\n", options->selection_message);
+
+    // Space available for the list: leave room for the header and the prompt
+    unsigned int rows, cols;
+    if (vt_getsize(vt, &rows, &cols) < 0 || rows <= USER_LIST_RESERVED_ROWS + 2) {
+        rows = n;
+        cols = 0;
+    } else {
+        rows -= USER_LIST_RESERVED_ROWS;
+    }
+
+    // Short lists are printed in a single column
+    if (n <= rows) {
+        for (int i = 0; i < n; i++) {
+            print_user(options, i, user, 0);
+            fprintf(stdout, "\n");
+        }
+        return;
+    }
+
+    // Try to lay out the users in columns
+    int name_width = 0;
+    for (int i = 0; i < n; i++) {
+        int len = strlen(options->users[i]);
+        if (len > name_width) {
+            name_width = len;
+        }
+    }
+    int cell_width = snprintf(NULL, 0, "%d", n) + 2 + name_width + 2;
+    int num_rows = rows;
+    int num_cols = (n + num_rows - 1) / num_rows;
+    if (num_cols * cell_width <= cols) {
+        num_rows = (n + num_cols - 1) / num_cols;
+        for (int r = 0; r < num_rows; r++) {
+            for (int c = 0; c < num_cols && c * num_rows + r < n; c++) {
+                print_user(options, c * num_rows + r, user, name_width + 2);
+            }
+            fprintf(stdout, "\n");
+        }
+        return;
+    }
+
+    // Too many users even for columns: show a window around the highlighted one,
+    // keeping two rows for the indicators of the hidden entries.
+    int window = rows - 2;
+    int current = 0;
+    for (int i = 0; i < n; i++) {
+        if (options->users[i] == user) {
+            current = i;
+            break;
+        }
+    }
+    int first = current - window / 2;
+    if (first > n - window) {
+        first = n - window;
+    }
+    if (first < 0) {
+        first = 0;
+    }
+    if (first > 0) {
+        fprintf(stdout, "\u25b2 %s", _("more"));
+    }
+    fprintf(stdout, "\n");
+    for (int i = first; i < first + window; i++) {
+        print_user(options, i, user, 0);
+        fprintf(stdout, "\n");
+    }
+    if (first + window < n) {
+        fprintf(stdout, "\u25bc %s", _("more"));
+    }
+    fprintf(stdout, "\n");
+}
@@ -0,0 +1,37 @@
+#ifndef __SELECTION_H__
+#define __SELECTION_H__
+
+#include "options.h"
+#include "vt.h"
+
+/**
+ *    Prints the `--selection-message` and the numbered list of users allowed to unlock, highlighting `user`.
+ *
+ *    If the list does not fit the screen, the users are laid out in columns,
+ *    and if not even the columns are enough, only the portion of the list
+ *    around the highlighted user is shown.
+ *
+ *    @param options SimplyLock options.
+ *    @param vt      Terminal the list is printed on, to know its size.
+ *    @param user    User to highlight, or `NULL`.
+ */
+void selection_print_users(struct options* options, struct vt* vt, const char* user);
+
+/**
+ *    Reads the number of the selected user as a single key press.
+ *    Escape sequences (arrows, function keys...) are discarded as a whole.
+ *
+ *    @param  vt Terminal to read from.
+ *    @return The 0-based index of the user (which might be out of range), or `-1` on EOF.
+ */
+int selection_read_key(struct vt* vt);
+
+/**
+ *    Reads the number of the selected user as a whole line terminated by enter.
+ *
+ *    @param  vt Terminal to read from.
+ *    @return The 0-based index of the user (which might be out of range), or `-1` on EOF.
+ */
+int selection_read_line(struct vt* vt);
+
+#endif
@@ -0,0 +1,106 @@
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include "smartcard.h"
+
+#ifdef WITH_PCSC
+
+#include <winscard.h>
+
+#define MAX_READERS 16
+
+struct smartcard {
+    SCARDCONTEXT context;
+};
+
+int smartcard_supported() {
+    return 1;
+}
+
+void* smartcard_init() {
+    struct smartcard* sc = (struct smartcard*)calloc(1, sizeof(struct smartcard));
+    if (sc == NULL) {
+        return NULL;
+    }
+    if (SCardEstablishContext(SCARD_SCOPE_SYSTEM, NULL, NULL, &sc->context) != SCARD_S_SUCCESS) {
+        free(sc);
+        errno = ECONNREFUSED;
+        return NULL;
+    }
+    return sc;
+}
+
+int smartcard_present(void* ptr) {
+    struct smartcard* sc = (struct smartcard*)ptr;
+
+    // The readers are listed every time, since they can be plugged in while locked
+    DWORD len = 0;
+    LONG ret = SCardListReaders(sc->context, NULL, NULL, &len);
+    if (ret == SCARD_E_NO_READERS_AVAILABLE) {
+        return 0;
+    } else if (ret != SCARD_S_SUCCESS) {
+        return -1;
+    }
+    char* readers = (char*)malloc(len);
+    if (readers == NULL) {
+        return -1;
+    }
+    ret = SCardListReaders(sc->context, NULL, readers, &len);
+    if (ret != SCARD_S_SUCCESS) {
+        free(readers);
+        return ret == SCARD_E_NO_READERS_AVAILABLE ? 0 : -1;
+    }
+
+    // The names of the readers are one after the other, and the list ends with an empty name
+    SCARD_READERSTATE states[MAX_READERS];
+    DWORD states_size = 0;
+    for (char* reader = readers; *reader != 0 && states_size < MAX_READERS; reader += strlen(reader) + 1) {
+        memset(&states[states_size], 0, sizeof(SCARD_READERSTATE));
+        states[states_size].szReader = reader;
+        states[states_size].dwCurrentState = SCARD_STATE_UNAWARE;
+        states_size++;
+    }
+
+    // With a timeout of 0, the current state is returned right away
+    int present = 0;
+    ret = SCardGetStatusChange(sc->context, 0, states, states_size);
+    if (ret != SCARD_S_SUCCESS && ret != SCARD_E_TIMEOUT) {
+        present = -1;
+    } else {
+        for (DWORD i = 0; i < states_size; i++) {
+            if (states[i].dwEventState & SCARD_STATE_PRESENT) {
+                present = 1;
+            }
+        }
+    }
+    free(readers);
+    return present;
+}
+
+void smartcard_free(void* ptr) {
+    struct smartcard* sc = (struct smartcard*)ptr;
+    if (sc != NULL) {
+        SCardReleaseContext(sc->context);
+        free(sc);
+    }
+}
+
+#else
+
+int smartcard_supported() {
+    return 0;
+}
+
+void* smartcard_init() {
+    errno = ENOTSUP;
+    return NULL;
+}
+
+int smartcard_present(void* sc) {
+    return 0;
+}
+
+void smartcard_free(void* sc) {
+}
+
+#endif
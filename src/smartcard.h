@@ -0,0 +1,35 @@
+#ifndef __SMARTCARD_H__
+#define __SMARTCARD_H__
+
+/**
+ *    Tells whether SimplyLock has been built with smartcard support (`make SMARTCARD=1`).
+ *
+ *    @return `1` if smartcards are supported, `0` otherwise.
+ */
+int smartcard_supported();
+
+/**
+ *    Connects to the PC/SC daemon, to watch the smartcard readers.
+ *
+ *    @return An opaque handle to be passed to the other functions, or `NULL` in case of error and sets `errno`
+ *            (`ENOTSUP` if SimplyLock has been built without smartcard support).
+ */
+void* smartcard_init();
+
+/**
+ *    Tells whether a card is in any of the readers, without waiting.
+ *    Readers plugged in after `smartcard_init` are watched too.
+ *
+ *    @param  sc Handle returned by `smartcard_init`.
+ *    @return `1` if a card is present, `0` if not (or if there are no readers), `-1` in case of error.
+ */
+int smartcard_present(void* sc);
+
+/**
+ *    Disconnects from the PC/SC daemon.
+ *
+ *    @param sc Handle returned by `smartcard_init`. Can be `NULL`.
+ */
+void smartcard_free(void* sc);
+
+#endif
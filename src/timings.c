@@ -0,0 +1,93 @@
+#include <stdio.h>
+#include <time.h>
+#include <unistd.h>
+#include <fcntl.h>
+
+#include "timings.h"
+
+struct timing {
+    const char* name;
+    struct timespec start;
+    long long total_us;
+    unsigned int count;
+};
+
+static FILE* timings_stream = NULL;
+static struct timing timings[TIMING_PHASES] = {
+    [TIMING_VT_ALLOCATION] = { .name = "VT allocation" },
+    [TIMING_SAVE_STATE]    = { .name = "sysrq/printk save" },
+    [TIMING_SWITCH]        = { .name = "VT switch" },
+    [TIMING_BLANK]         = { .name = "screen blank" },
+    [TIMING_FIRST_REPAINT] = { .name = "first repaint" },
+    [TIMING_AUTH]          = { .name = "authentication" },
+    [TIMING_UNLOCK]        = { .name = "unlock" }
+};
+
+int timings_init(int enabled) {
+    if (!enabled) {
+        return 0;
+    }
+
+    // Keep our own copy of stderr, which must not leak to the processes we spawn
+    int fd = fcntl(STDERR_FILENO, F_DUPFD_CLOEXEC, 0);
+    if (fd < 0) {
+        return -1;
+    }
+    timings_stream = fdopen(fd, "w");
+    if (timings_stream == NULL) {
+        close(fd);
+        return -1;
+    }
+
+    return 0;
+}
+
+void timings_start(timing_phase_t phase) {
+    if (timings_stream == NULL) {
+        return;
+    }
+    clock_gettime(CLOCK_MONOTONIC, &timings[phase].start);
+}
+
+void timings_stop(timing_phase_t phase) {
+    if (timings_stream == NULL) {
+        return;
+    }
+
+    struct timing* t = &timings[phase];
+    if (phase == TIMING_FIRST_REPAINT && t->count > 0) {
+        return;
+    }
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    t->total_us += (now.tv_sec - t->start.tv_sec) * 1000000LL + (now.tv_nsec - t->start.tv_nsec) / 1000;
+    t->count++;
+}
+
+void timings_report() {
+    if (timings_stream == NULL) {
+        return;
+    }
+
+    // Phases that never happened (e.g. the switch when locking in place) are left out
+    fprintf(timings_stream, "simplylock[%d]: timings:\n", (int)getpid());
+    for (int i = 0; i < TIMING_PHASES; i++) {
+        const struct timing* t = &timings[i];
+        if (t->count == 0) {
+            continue;
+        }
+        fprintf(timings_stream, "    %-20s %6lld.%03lld ms", t->name, t->total_us / 1000, t->total_us % 1000);
+        if (t->count > 1) {
+            fprintf(timings_stream, " (%u times)", t->count);
+        }
+        fprintf(timings_stream, "\n");
+    }
+    fflush(timings_stream);
+}
+
+void timings_end() {
+    if (timings_stream != NULL) {
+        fclose(timings_stream);
+        timings_stream = NULL;
+    }
+}
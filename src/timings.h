@@ -0,0 +1,55 @@
+#ifndef __TIMINGS_H__
+#define __TIMINGS_H__
+
+/**
+ *    Phases of the lock/unlock sequence that are timed with `--timings`.
+ */
+typedef enum {
+    TIMING_VT_ALLOCATION,  // Allocation of the new terminal
+    TIMING_SAVE_STATE,     // Saving the sysrq and printk state
+    TIMING_SWITCH,         // Switching to the locked terminal
+    TIMING_BLANK,          // Switching the screen off with --dark
+    TIMING_FIRST_REPAINT,  // First time the console is drawn
+    TIMING_AUTH,           // Authentication attempts, including the time spent typing
+    TIMING_UNLOCK,         // Restoring the state of the system
+    TIMING_PHASES          // Number of phases, not a phase
+} timing_phase_t;
+
+/**
+ *    Initializes the timings.
+ *
+ *    Like the debug log, the report is written to the standard error of the process at the time of the call.
+ *    When disabled, all the other functions return immediately.
+ *
+ *    @param  enabled `1` to time the phases, `0` otherwise.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int timings_init(int enabled);
+
+/**
+ *    Marks the beginning of a phase.
+ *
+ *    @param phase Phase starting.
+ */
+void timings_start(timing_phase_t phase);
+
+/**
+ *    Marks the end of a phase started with `timings_start`.
+ *    Phases that happen more than once are added up, except for `TIMING_FIRST_REPAINT`,
+ *    which only counts once.
+ *
+ *    @param phase Phase ending.
+ */
+void timings_stop(timing_phase_t phase);
+
+/**
+ *    Writes how long each phase took, with millisecond accuracy.
+ */
+void timings_report();
+
+/**
+ *    Closes the timings.
+ */
+void timings_end();
+
+#endif
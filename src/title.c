@@ -0,0 +1,98 @@
+#include <stdio.h>
+#include <string.h>
+#include <ctype.h>
+
+#include "title.h"
+
+#define PIXEL "█"
+
+/**
+ *    A character of the font: every row is a string of the same length,
+ *    with a `#` for every pixel drawn.
+ */
+struct glyph {
+    char c;
+    const char* rows[TITLE_HEIGHT];
+};
+
+static const struct glyph font[] = {
+    { 'A', { " ### ", "#   #", "#####", "#   #", "#   #" } },
+    { 'B', { "#### ", "#   #", "#### ", "#   #", "#### " } },
+    { 'C', { " ####", "#    ", "#    ", "#    ", " ####" } },
+    { 'D', { "#### ", "#   #", "#   #", "#   #", "#### " } },
+    { 'E', { "#####", "#    ", "#### ", "#    ", "#####" } },
+    { 'F', { "#####", "#    ", "#### ", "#    ", "#    " } },
+    { 'G', { " ####", "#    ", "#  ##", "#   #", " ####" } },
+    { 'H', { "#   #", "#   #", "#####", "#   #", "#   #" } },
+    { 'I', { "#####", "  #  ", "  #  ", "  #  ", "#####" } },
+    { 'J', { "#####", "   # ", "   # ", "#  # ", " ##  " } },
+    { 'K', { "#   #", "#  # ", "###  ", "#  # ", "#   #" } },
+    { 'L', { "#    ", "#    ", "#    ", "#    ", "#####" } },
+    { 'M', { "#   #", "## ##", "# # #", "#   #", "#   #" } },
+    { 'N', { "#   #", "##  #", "# # #", "#  ##", "#   #" } },
+    { 'O', { " ### ", "#   #", "#   #", "#   #", " ### " } },
+    { 'P', { "#### ", "#   #", "#### ", "#    ", "#    " } },
+    { 'Q', { " ### ", "#   #", "# # #", "#  # ", " ## #" } },
+    { 'R', { "#### ", "#   #", "#### ", "#  # ", "#   #" } },
+    { 'S', { " ####", "#    ", " ### ", "    #", "#### " } },
+    { 'T', { "#####", "  #  ", "  #  ", "  #  ", "  #  " } },
+    { 'U', { "#   #", "#   #", "#   #", "#   #", " ### " } },
+    { 'V', { "#   #", "#   #", "#   #", " # # ", "  #  " } },
+    { 'W', { "#   #", "#   #", "# # #", "## ##", "#   #" } },
+    { 'X', { "#   #", " # # ", "  #  ", " # # ", "#   #" } },
+    { 'Y', { "#   #", " # # ", "  #  ", "  #  ", "  #  " } },
+    { 'Z', { "#####", "   # ", "  #  ", " #   ", "#####" } },
+    { '0', { " ### ", "#  ##", "# # #", "##  #", " ### " } },
+    { '1', { "  #  ", " ##  ", "  #  ", "  #  ", " ### " } },
+    { '2', { " ### ", "#   #", "  ## ", " #   ", "#####" } },
+    { '3', { "#### ", "    #", " ### ", "    #", "#### " } },
+    { '4', { "#   #", "#   #", "#####", "    #", "    #" } },
+    { '5', { "#####", "#    ", "#### ", "    #", "#### " } },
+    { '6', { " ### ", "#    ", "#### ", "#   #", " ### " } },
+    { '7', { "#####", "    #", "   # ", "  #  ", "  #  " } },
+    { '8', { " ### ", "#   #", " ### ", "#   #", " ### " } },
+    { '9', { " ### ", "#   #", " ####", "    #", " ### " } },
+    { ' ', { "   ", "   ", "   ", "   ", "   " } },
+    { '-', { "   ", "   ", "###", "   ", "   " } },
+    { '.', { " ", " ", " ", " ", "#" } },
+    { ',', { "  ", "  ", "  ", " #", "# " } },
+    { ':', { " ", "#", " ", "#", " " } },
+    { '!', { "#", "#", "#", " ", "#" } },
+    { '?', { " ### ", "#   #", "  ## ", "     ", "  #  " } },
+};
+
+static const struct glyph* find_glyph(char c) {
+    c = toupper((unsigned char)c);
+    for (int i = 0; i < sizeof(font) / sizeof(font[0]); i++) {
+        if (font[i].c == c) {
+            return &font[i];
+        }
+    }
+    return NULL;
+}
+
+int title_width(const char* text) {
+    int width = 0;
+    for (const char* c = text; *c != 0; c++) {
+        const struct glyph* glyph = find_glyph(*c);
+        if (glyph == NULL) {
+            return -1;
+        }
+
+        // Characters are separated by an empty column
+        width += strlen(glyph->rows[0]) + (c != text ? 1 : 0);
+    }
+    return width;
+}
+
+void title_print_row(const char* text, int row, FILE* out) {
+    for (const char* c = text; *c != 0; c++) {
+        const struct glyph* glyph = find_glyph(*c);
+        if (c != text) {
+            fputc(' ', out);
+        }
+        for (const char* pixel = glyph->rows[row]; *pixel != 0; pixel++) {
+            fputs(*pixel == '#' ? PIXEL : " ", out);
+        }
+    }
+}
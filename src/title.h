@@ -0,0 +1,28 @@
+#ifndef __TITLE_H__
+#define __TITLE_H__
+
+#include <stdio.h>
+
+// Number of rows of a title drawn in block letters
+#define TITLE_HEIGHT 5
+
+/**
+ *    Measures the width of a text drawn in block letters.
+ *    The built-in font has only letters (drawn in upper case), digits, spaces and some punctuation.
+ *
+ *    @param  text Text to draw.
+ *    @return The number of columns taken, or `-1` if some of the characters are not in the font.
+ */
+int title_width(const char* text);
+
+/**
+ *    Prints one of the rows of a text drawn in block letters, without the newline.
+ *    The text must only contain characters in the font, as checked by `title_width`.
+ *
+ *    @param text Text to draw.
+ *    @param row  Row to print, between `0` and `TITLE_HEIGHT - 1`.
+ *    @param out  Stream to print to.
+ */
+void title_print_row(const char* text, int row, FILE* out);
+
+#endif
@@ -7,12 +7,16 @@
 #include <unistd.h>
 #include <linux/vt.h>
 #include <linux/tiocl.h>
+#include <linux/kd.h>
 #include <errno.h>
+#include <time.h>
 
 #include "vt.h"
 
 #define CONSOLEBLANK_PATH "/sys/module/kernel/parameters/consoleblank"
 #define MIN_VT_NUMBER 13
+#define VT_STATE_SIZE 16 // VT_GETSTATE reports only the state of the first 16 vts
+#define FLASH_DURATION_MS 150
 
 static int console_fd = -1;
 
@@ -50,9 +54,23 @@ static int ensure_console_blank_timer_enabled(struct vt* vt) {
 
 
 
+/**
+ * Applies the attributes in `term` to the terminal.
+ * Standard streams that are not a terminal have no attributes to apply.
+ */
+static int set_term(struct vt* vt, const struct termios* term) {
+    if (vt->stdio && !isatty(vt->fd)) {
+        return 0;
+    }
+
+    int ret;
+    while ((ret = tcsetattr(vt->fd, TCSANOW, term)) == -1 && errno == EINTR);
+    return ret;
+}
+
 int vt_init() {
     while ((console_fd = open(VT_CONSOLE_DEVICE, O_RDWR)) == -1 && errno == EINTR);
-    return console_fd != -1;
+    return console_fd == -1 ? -1 : 0;
 }
 
 void vt_end() {
@@ -83,9 +101,107 @@ struct vt* vt_getcurrent() {
 
 }
 
+int vt_getstate(unsigned int* active, unsigned short* in_use) {
+    int ret;
+    struct vt_stat vtstate;
+    while ((ret = ioctl(console_fd, VT_GETSTATE, &vtstate)) == -1 && errno == EINTR);
+    if (ret < 0) {
+        return -1;
+    }
+    *active = vtstate.v_active;
+    *in_use = vtstate.v_state;
+    return 0;
+}
+
+/**
+ *    Fast path to find a free vt not lower than `min`:
+ *    the kernel provides a quick way to get the state of the first 16 vts
+ *    by returning a mask with 1s indicating the ones in use.
+ *    Sets `*num` to 0 if all of them are already occupied.
+ */
+static int find_free(unsigned int min, int* num) {
+    unsigned int active;
+    unsigned short in_use;
+    if (vt_getstate(&active, &in_use) < 0) {
+        return -1;
+    }
+
+    for (int i = min; i < VT_STATE_SIZE; i++) {
+        if ((in_use & (1 << i)) == 0) {
+            *num = i;
+            return 0;
+        }
+    }
+    *num = 0;
+    return 0;
+}
+
+int vt_querynew() {
+    int ret;
+    int num;
+    while ((ret = ioctl(console_fd, VT_OPENQRY, &num)) == -1 && errno == EINTR);
+    if (ret < 0) {
+        return -1;
+    }
+    if (num < MIN_VT_NUMBER && find_free(MIN_VT_NUMBER, &num) < 0) {
+        return -1;
+    }
+    return num;
+}
+
+/**
+ *    Opens the device file of `vt->number`, unless `vt->fd` is already open,
+ *    and sets the attributes of the terminal.
+ */
+static int setup_term(struct vt* vt) {
+
+    // Get terminal attributes, saving them to be restored when the vt is not ours
+    if (!vt->stdio || isatty(vt->fd)) {
+        int ret;
+        while ((ret = tcgetattr(vt->fd, &vt->term)) == -1 && errno == EINTR);
+        if (ret < 0) {
+            return -1;
+        }
+    }
+    vt->old_term = vt->term;
+
+    // By default we turn off echo and signal generation.
+    // We also disable Ctrl+D for EOF, since we will almost never want it.
+    vt->term.c_iflag |= IGNBRK;
+    vt->term.c_lflag &= ~(ECHO | ISIG);
+    vt->term.c_cc[VEOF] = 0;
+    return set_term(vt, &vt->term);
+}
+
+static int setup(struct vt* vt) {
+
+    // Open the corresponding device file
+    if (vt->fd == -1) {
+        char path[1024];
+        snprintf(path, sizeof(path), VT_TTY_FORMAT, vt->number);
+        while ((vt->stream = fopen(path, "r+")) == NULL && errno == EINTR);
+        if (vt->stream == NULL) {
+            return -1;
+        }
+        vt->fd = fileno(vt->stream);
+    } else {
+        // Reuse the same fd we found during the slow path of the previous check
+        while ((vt->stream = fdopen(vt->fd, "r+")) == NULL && errno == EINTR);
+        if (vt->stream == NULL) {
+            return -1;
+        }
+    }
+
+    return setup_term(vt);
+}
+
 struct vt* vt_createnew() {
+    return vt_createabove(MIN_VT_NUMBER);
+}
+
+struct vt* vt_createabove(unsigned int min) {
 
-    struct vt* vt = (struct vt*)malloc(sizeof(struct vt));
+    struct vt* vt = (struct vt*)calloc(1, sizeof(struct vt));
     if (vt == NULL) {
         return NULL;
     }
@@ -104,24 +220,11 @@ struct vt* vt_createnew() {
     // and we don't want to step on systemd, otherwise bad things will happen.
     // We chose 13 as the lower limit because the user can manually switch up to vt number 12.
     // On most systems, the maximum number of vts is 64, so this should not be a problem.
-    if (num < MIN_VT_NUMBER) {
-        num = MIN_VT_NUMBER;
-       
-        // Fast path: the kernel provides a quick way to get the state of the first 16 vts
-        // by returning a mask with 1s indicating the ones in use.
-        struct vt_stat stat;
-        int ret;
-        while ((ret = ioctl(console_fd, VT_GETSTATE, &stat)) == -1 && errno == EINTR);
-        if (ret < 0) {
-            goto error;
-        }
+    if (num < min) {
 
-        int found = 0;
-        for (unsigned short mask = 1 << num; num < 16; ++num, mask <<= 1) {
-            if ((stat.v_state & mask) == 0) {
-                found = 1;
-                break;
-            }
+        // Fast path: look at the first 16 vts
+        if (find_free(min, &num) < 0) {
+            goto error;
         }
 
         // Slow path: we might be unlucky, and all the first 16 vts are already occupied.
@@ -129,12 +232,13 @@ struct vt* vt_createnew() {
         //
         // Here the kernel does not help us and we have to test each single vt one by one:
         // by issuing a VT_OPENQRY ioctl we can get back the first free vt.
-        // We keep opening file descriptors until the next free vt is greater than MIN_VT_NUMBER.
+        // We keep opening file descriptors until the next free vt is not lower than `min`.
         //
         // I don't have words to describe how ugly and problematic this is,
         // but it's the only stable working solution I found. I seriously hope that this will never be needed.
-        if (!found) {
-            
+        if (num == 0) {
+            num = min > VT_STATE_SIZE ? min : VT_STATE_SIZE;
+
             // Keep track of the fds we open
             int fds[MAX_NR_CONSOLES];
             for (int i = 0; i < MAX_NR_CONSOLES; ++i) {
@@ -152,6 +256,12 @@ struct vt* vt_createnew() {
                     goto error;
                 }
 
+                // No free vt is left, at least none we can keep track of
+                if (first_free <= 0 || first_free >= MAX_NR_CONSOLES) {
+                    errno = EBUSY;
+                    goto error;
+                }
+
                 // Open the corresponding device file to mark it as busy
                 snprintf(path, sizeof(path), VT_TTY_FORMAT, first_free);
                 while ((ret = open(path, O_RDWR)) == -1 && errno == EINTR);
@@ -177,37 +287,9 @@ struct vt* vt_createnew() {
 
     }
     vt->number = num;
+    vt->allocated = 1;
 
-    // Then we open the corresponding device file
-    if (vt->fd == -1) {
-        char path[1024];
-        snprintf(path, sizeof(path), VT_TTY_FORMAT, vt->number);
-        while ((vt->stream = fopen(path, "r+")) == NULL && errno == EINTR);
-        if (vt->stream == NULL) {
-            goto error;
-        }
-        vt->fd = fileno(vt->stream);
-    } else {
-        // Reuse the same fd we found during the slow path of the previous check
-        while ((vt->stream = fdopen(vt->fd, "r+")) == NULL && errno == EINTR);
-        if (vt->stream == NULL) {
-            goto error;
-        }
-    }
-
-    // And get terminal attributes
-    while ((ret = tcgetattr(vt->fd, &vt->term)) == -1 && errno == EINTR);
-    if (ret < 0) {
-        goto error;
-    }
-
-    // By default we turn off echo and signal generation.
-    // We also disable Ctrl+D for EOF, since we will almost never want it.
-    vt->term.c_iflag |= IGNBRK;
-    vt->term.c_lflag &= ~(ECHO | ISIG);
-    vt->term.c_cc[VEOF] = 0;
-    while ((ret = tcsetattr(vt->fd, TCSANOW, &vt->term)) == -1 && errno == EINTR);
-    if (ret < 0) {
+    if (setup(vt) < 0) {
         goto error;
     }
 
@@ -218,6 +300,8 @@ error:
     if (vt != NULL) {
         if (vt->stream != NULL) {
             fclose(vt->stream);
+        } else if (vt->fd != -1) {
+            close(vt->fd);
         }
         free(vt);
     }
@@ -225,15 +309,63 @@ error:
     return NULL;
 }
 
+struct vt* vt_open(unsigned int number) {
+    struct vt* vt = (struct vt*)calloc(1, sizeof(struct vt));
+    if (vt == NULL) {
+        return NULL;
+    }
+    vt->number = number;
+    vt->fd = -1;
+
+    if (setup(vt) < 0) {
+        if (vt->stream != NULL) {
+            fclose(vt->stream);
+        }
+        free(vt);
+        return NULL;
+    }
+
+    return vt;
+}
+
+struct vt* vt_fromstdio() {
+    struct vt* vt = (struct vt*)calloc(1, sizeof(struct vt));
+    if (vt == NULL) {
+        return NULL;
+    }
+    vt->fd = STDIN_FILENO;
+    vt->stream = stdin;
+    vt->stdio = 1;
+
+    if (setup_term(vt) < 0) {
+        free(vt);
+        return NULL;
+    }
+
+    return vt;
+}
+
 void vt_free(struct vt* vt) {
     if (vt == NULL) {
         return;
     }
-    if (vt->stream != NULL) {
-        fclose(vt->stream);
+    if (vt->stdio) {
+
+        // The standard streams belong to the process
+        set_term(vt, &vt->old_term);
 
+    } else if (vt->stream != NULL) {
         int ret;
-        while ((ret = ioctl(console_fd, VT_DISALLOCATE, vt->number)) == -1 && errno == EINTR);
+
+        // A vt we did not allocate goes back to its owner as we found it
+        if (!vt->allocated) {
+            set_term(vt, &vt->old_term);
+        }
+        fclose(vt->stream);
+
+        if (vt->allocated) {
+            while ((ret = ioctl(console_fd, VT_DISALLOCATE, vt->number)) == -1 && errno == EINTR);
+        }
     }
     free(vt);
 }
@@ -275,9 +407,41 @@ int vt_setecho(struct vt* vt, int echo) {
         vt->term.c_lflag &= ~ECHO;
     }
 
-    int ret;
-    while ((ret = tcsetattr(vt->fd, TCSANOW, &vt->term)) == -1 && errno == EINTR);
-    return ret;
+    return set_term(vt, &vt->term);
+}
+
+int vt_setcanonical(struct vt* vt, int canonical) {
+    if (canonical) {
+        vt->term.c_lflag |= ICANON;
+    } else {
+        vt->term.c_lflag &= ~ICANON;
+        vt->term.c_cc[VMIN] = 1;
+        vt->term.c_cc[VTIME] = 0;
+    }
+
+    return set_term(vt, &vt->term);
+}
+
+int vt_setsane(struct vt* vt, int sane) {
+    struct termios term = vt->term;
+    if (sane) {
+        term.c_iflag |= ICRNL;
+        term.c_lflag |= ECHO | ECHOE | ICANON | ISIG;
+        term.c_cc[VINTR] = 3;
+        term.c_cc[VQUIT] = 034;
+        term.c_cc[VSUSP] = 032;
+        term.c_cc[VEOF] = 4;
+        term.c_cc[VEOL] = 0;
+    }
+
+    return set_term(vt, &term);
+}
+
+void vt_restoreonsignal(struct vt* vt) {
+    tcsetattr(vt->fd, TCSANOW, &vt->old_term);
+    if (!vt->stdio) {
+        ioctl(vt->fd, KDSETMODE, KD_TEXT);
+    }
 }
 
 int vt_flush(struct vt* vt) {
@@ -285,7 +449,66 @@ int vt_flush(struct vt* vt) {
 }
 
 int vt_clear(struct vt* vt) {
-    return write(vt->fd, "\033[H\033[J", 6) == 6 ? 0 : -1;
+    return write(vt->fd, "\033[0m\033[H\033[J", 10) == 10 ? 0 : -1;
+}
+
+int vt_clearbelow(struct vt* vt, unsigned int row) {
+    char seq[32];
+    int len;
+    if (row > 0) {
+        len = snprintf(seq, sizeof(seq), "\033[0m\033[%u;1H\033[J", row);
+    } else {
+        len = snprintf(seq, sizeof(seq), "\033[0m\033[H");
+    }
+    return write(vt->fd, seq, len) == len ? 0 : -1;
+}
+
+int vt_bell(struct vt* vt) {
+
+    // A muted bell is rung with its default duration, and muted again right after
+    if (vt->bell_muted) {
+        return write(vt->fd, "\033[11]\a\033[11;0]", 13) == 13 ? 0 : -1;
+    }
+    return write(vt->fd, "\a", 1) == 1 ? 0 : -1;
+}
+
+int vt_mutebell(struct vt* vt, int muted) {
+
+    // The duration of the bell, in milliseconds: without a value, the default one
+    int len = muted ? 7 : 5;
+    if (write(vt->fd, muted ? "\033[11;0]" : "\033[11]", len) != len) {
+        return -1;
+    }
+    vt->bell_muted = muted;
+    return 0;
+}
+
+int vt_setcursor(struct vt* vt, int visible) {
+    return write(vt->fd, visible ? "\033[?25h" : "\033[?25l", 6) == 6 ? 0 : -1;
+}
+
+int vt_flash(struct vt* vt) {
+    if (write(vt->fd, "\033[?5h", 5) != 5) {
+        return -1;
+    }
+
+    struct timespec duration = { 0, FLASH_DURATION_MS * 1000000L };
+    while (nanosleep(&duration, &duration) == -1 && errno == EINTR);
+
+    return write(vt->fd, "\033[?5l", 5) == 5 ? 0 : -1;
+}
+
+int vt_getsize(struct vt* vt, unsigned int* rows, unsigned int* cols) {
+    struct winsize size;
+    int ret;
+    while ((ret = ioctl(vt->fd, TIOCGWINSZ, &size)) == -1 && errno == EINTR);
+    if (ret < 0) {
+        return -1;
+    }
+
+    *rows = size.ws_row;
+    *cols = size.ws_col;
+    return 0;
 }
 
 int vt_blank(struct vt* vt, int blank) {
@@ -322,16 +545,20 @@ int vt_signals(struct vt* vt, vt_signals_t sigs) {
     if ((sigs & VT_SIGQUIT) == 0) {
         vt->term.c_cc[VQUIT] = 0;
     } else {
-        vt->term.c_cc[VQUIT] = 34;
+        vt->term.c_cc[VQUIT] = 034;
     }
     if ((sigs & VT_SIGTSTP) == 0) {
         vt->term.c_cc[VSUSP] = 0;
     } else {
-        vt->term.c_cc[VSUSP] = 32;
+        vt->term.c_cc[VSUSP] = 032;
     }
 
     // And update the terminal
-    int ret;
-    while ((ret = tcsetattr(vt->fd, TCSANOW, &vt->term)) == -1 && errno == EINTR);
-    return ret;
+    return set_term(vt, &vt->term);
+}
+
+int vt_seteol(struct vt* vt, unsigned char c) {
+    vt->term.c_cc[VEOL] = c;
+
+    return set_term(vt, &vt->term);
 }
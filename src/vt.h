@@ -7,6 +7,11 @@
 #define VT_CONSOLE_DEVICE "/dev/console"
 #define VT_TTY_FORMAT "/dev/tty%d"
 
+// Escape sequences used to style the text (highlights are the defaults, see `struct theme`)
+#define HIGHLIGHT "\033[1m\033[34m"
+#define ERROR_HIGHLIGHT "\033[1m\033[31m"
+#define RESET "\033[0m"
+
 /**
  *    Structure representing a virtual terminal.
  *
@@ -22,12 +27,22 @@
  *        This stream points to the same file descriptor as `fd`.
  *    @field term Structure `termios` containing informations
  *        about the attributes of the terminal.
+ *    @field old_term Attributes of the terminal before it was opened,
+ *        restored when a terminal that was not allocated by us is freed.
+ *    @field allocated `1` if the terminal has been allocated by `vt_createnew`,
+ *        and has to be deallocated when freed.
+ *    @field bell_muted `1` if the bell has been muted with `vt_mutebell`.
+ *    @field stdio `1` if this is not a virtual terminal, but the standard streams (see `vt_fromstdio`).
  */
 struct vt {
     unsigned int number;
     unsigned int fd;
     FILE* stream;
     struct termios term;
+    struct termios old_term;
+    int allocated;
+    int bell_muted;
+    int stdio;
 };
 
 typedef enum {
@@ -53,6 +68,24 @@ void vt_end();
  */
 struct vt* vt_getcurrent();
 
+/**
+ *    Gets the state of the virtual terminals.
+ *
+ *    @param  active Filled with the number of the active terminal.
+ *    @param  in_use Filled with a mask of the terminals in use among the first 16:
+ *                   bit N is set if terminal N is in use.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_getstate(unsigned int* active, unsigned short* in_use);
+
+/**
+ *    Returns the number of the terminal `vt_createnew` would allocate, without allocating it.
+ *
+ *    @return The number of the terminal, `0` if it cannot be determined without allocating
+ *            other terminals (all of the first 16 are in use), `-1` in case of error and sets `errno`.
+ */
+int vt_querynew();
+
 /**
  *    Creates and allocates a new virtual terminal.
  *
@@ -60,6 +93,33 @@ struct vt* vt_getcurrent();
  */
 struct vt* vt_createnew();
 
+/**
+ *    Like `vt_createnew`, but allocates a terminal whose number is not lower than `min`.
+ *    Used to try again with a higher terminal when the one allocated has been taken by someone else.
+ *
+ *    @param  min Lowest acceptable number of the terminal.
+ *    @return An open `struct vt` representing the new terminal allocated, or `NULL` in case of error and sets `errno`.
+ */
+struct vt* vt_createabove(unsigned int min);
+
+/**
+ *    Opens an existing virtual terminal, with the same attributes as the ones created by `vt_createnew`.
+ *    When freed, the terminal is not deallocated, and its attributes are restored.
+ *
+ *    @param  number Number of the terminal to open.
+ *    @return An open `struct vt` representing the terminal, or `NULL` in case of error and sets `errno`.
+ */
+struct vt* vt_open(unsigned int number);
+
+/**
+ *    Wraps the standard streams of the process as if they were a virtual terminal, for testing.
+ *    Reads come from `stdin`, and if it is not a terminal, changes to its attributes are silently skipped.
+ *    When freed, the attributes are restored and the streams are left open.
+ *
+ *    @return A `struct vt` numbered 0, or `NULL` in case of error and sets `errno`.
+ */
+struct vt* vt_fromstdio();
+
 /**
  *    Frees all the resources held by a `struct vt`.
  *
@@ -92,6 +152,35 @@ int vt_lockswitch(int lock);
  */
 int vt_setecho(struct vt* vt, int echo);
 
+/**
+ *    Enables or disables canonical mode (line by line input).
+ *    When canonical mode is disabled, reads return as soon as a single character is available.
+ *
+ *    @param  vt        Virtual terminal to operate on.
+ *    @param  canonical `1` to enable canonical mode, `0` to disable.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_setcanonical(struct vt* vt, int canonical);
+
+/**
+ *    Temporarily puts the terminal in a sane state suitable for interactive programs
+ *    (echo, line editing and signals enabled), or restores the state it had before.
+ *
+ *    @param  vt   Virtual terminal to operate on.
+ *    @param  sane `1` to put the terminal in a sane state, `0` to restore the previous state.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_setsane(struct vt* vt, int sane);
+
+/**
+ *    Gives the terminal back its attributes from before it was opened, and puts it in text mode.
+ *    Only async-signal-safe functions are used, so that it can be called from a signal handler:
+ *    errors are ignored, and the `struct vt` is left untouched.
+ *
+ *    @param vt Virtual terminal to restore.
+ */
+void vt_restoreonsignal(struct vt* vt);
+
 /**
  *    Flushes all the data written by the user but not yet read by the application.
  *
@@ -101,13 +190,70 @@ int vt_setecho(struct vt* vt, int echo);
 int vt_flush(struct vt* vt);
 
 /**
- *    Clears the terminal.
+ *    Clears the terminal, moving the cursor to the top left corner and resetting the text attributes.
  *
  *    @param  vt Virtual terminal to clear.
  *    @return `0` in case of success, `-1` otherwise and sets `errno`.
  */
 int vt_clear(struct vt* vt);
 
+/**
+ *    Clears the terminal from the given row down, leaving the rows above untouched,
+ *    moving the cursor to the beginning of the row and resetting the text attributes.
+ *
+ *    @param  vt  Virtual terminal to clear.
+ *    @param  row Number of the first row to clear, starting from 1,
+ *                or `0` to clear nothing and move the cursor to the top left corner.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_clearbelow(struct vt* vt, unsigned int row);
+
+/**
+ *    Shows or hides the cursor.
+ *
+ *    @param  vt      Virtual terminal.
+ *    @param  visible `1` to show the cursor, `0` to hide it.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_setcursor(struct vt* vt, int visible);
+
+/**
+ *    Rings the terminal bell.
+ *
+ *    @param  vt Virtual terminal.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_bell(struct vt* vt);
+
+/**
+ *    Mutes or unmutes the bell of the terminal, whoever rings it.
+ *    The bell can still be rung explicitly with `vt_bell`.
+ *    Since the previous settings of the bell cannot be read, unmuting restores the default ones.
+ *
+ *    @param  vt    Virtual terminal.
+ *    @param  muted `1` to mute the bell, `0` to unmute it.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_mutebell(struct vt* vt, int muted);
+
+/**
+ *    Briefly flashes the screen of the terminal by inverting its colors.
+ *
+ *    @param  vt Virtual terminal.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_flash(struct vt* vt);
+
+/**
+ *    Gets the size of the terminal.
+ *
+ *    @param  vt   Virtual terminal to query.
+ *    @param  rows Pointer that will receive the number of rows.
+ *    @param  cols Pointer that will receive the number of columns.
+ *    @return `0` in case of success, `-1` otherwise and sets `errno`.
+ */
+int vt_getsize(struct vt* vt, unsigned int* rows, unsigned int* cols);
+
 /**
  *    Blanks the screen of the given terminal.
  *
@@ -131,4 +277,14 @@ int vt_blank(struct vt* vt, int blank);
  */
 int vt_signals(struct vt* vt, vt_signals_t sigs);
 
+/**
+ *    Sets an additional character that ends a line in canonical mode, like the newline does.
+ *    The character is kept at the end of the line read.
+ *
+ *    @param  vt Terminal to modify.
+ *    @param  c  Character that ends a line, or `0` to only end lines with newlines.
+ *    @return    `0` if the operation completed successfully, `-1` otherwise.
+ */
+int vt_seteol(struct vt* vt, unsigned char c);
+
 #endif
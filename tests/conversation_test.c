@@ -0,0 +1,480 @@
+// The PAM conversation is private to auth.c: the test is built together with it, to drive the conversation
+// directly with synthetic messages, and with a scripted source of answers instead of the terminal.
+// The answers are also read from a pseudo-terminal, to check that only the visible prompts are echoed.
+//
+// The allocations and releases made by auth.c are tracked, to check that a failed conversation releases
+// exactly what it allocated, once, and that a successful one leaves everything to PAM.
+// The system headers are included first, so that only the calls in auth.c are tracked.
+#define _XOPEN_SOURCE 700
+#include <time.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <limits.h>
+#include <signal.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <termios.h>
+#include <getopt.h>
+#include <sys/stat.h>
+#include <sys/wait.h>
+#include <crypt.h>
+#include <security/pam_appl.h>
+
+static void* tracked_calloc(size_t n, size_t size);
+static void tracked_free(void* ptr);
+#define calloc(n, size) tracked_calloc(n, size)
+#define free(ptr) tracked_free(ptr)
+#include "auth.c"
+#undef calloc
+#undef free
+
+#define MAX_ANSWERS 8
+#define MAX_TRACKED 64
+#define OUTPUT_SIZE 4096
+#define TYPE_WAIT_MS 10
+#define TYPE_MAX_WAITS 500
+
+/**
+ *    A block allocated during a conversation, and how many times it has been released.
+ */
+struct allocation {
+    void* ptr;
+    int frees;
+};
+
+static struct allocation tracked[MAX_TRACKED];
+static int tracked_size = 0;
+static int unknown_frees = 0;
+
+static void track(void* ptr) {
+    if (ptr != NULL && tracked_size < MAX_TRACKED) {
+        tracked[tracked_size].ptr = ptr;
+        tracked[tracked_size].frees = 0;
+        tracked_size++;
+    }
+}
+
+static void* tracked_calloc(size_t n, size_t size) {
+    void* ptr = calloc(n, size);
+    track(ptr);
+    return ptr;
+}
+
+/**
+ *    Counts the release of a tracked block. The address of a released block might be reused
+ *    by a later one, so the live block with that address is looked for first.
+ */
+static void tracked_free(void* ptr) {
+    if (ptr == NULL) {
+        return;
+    }
+    int found = -1;
+    for (int i = tracked_size - 1; i >= 0; i--) {
+        if (tracked[i].ptr == ptr && (found < 0 || tracked[i].frees == 0)) {
+            found = i;
+        }
+    }
+    if (found >= 0) {
+        tracked[found].frees++;
+    } else {
+        unknown_frees++;
+    }
+
+    // Blocks released more than once are never given back, or the second release would crash the test
+    if (found < 0 || tracked[found].frees == 1) {
+        free(ptr);
+    }
+}
+
+// The answers given to the prompts, in order, and the echo requested by every prompt
+static const char* answers[MAX_ANSWERS];
+static int answers_size = 0;
+static int answers_read = 0;
+static int echoes[MAX_ANSWERS];
+
+// Left in the responses before a conversation that must fail, to check that they are reset
+static struct pam_response dangling;
+
+/**
+ *    Answers the prompts with the scripted answers, failing as on EOF when they are over.
+ */
+static char* read_scripted_answer(struct conv_state* state, int echo) {
+    if (answers_read == answers_size) {
+        return NULL;
+    }
+    echoes[answers_read] = echo;
+    char* answer = strdup(answers[answers_read++]);
+    track(answer);
+    return answer;
+}
+
+/**
+ *    Prepares a new conversation, which will be given the answers passed (up to a `NULL`).
+ */
+static void start_conversation(struct conv_state* state, struct options* options, const char** script) {
+    free_messages(state);
+    memset(state, 0, sizeof(*state));
+    state->options = options;
+    state->read_answer = read_scripted_answer;
+
+    answers_size = 0;
+    answers_read = 0;
+    tracked_size = 0;
+    unknown_frees = 0;
+    for (int i = 0; script[i] != NULL && i < MAX_ANSWERS; i++) {
+        answers[answers_size++] = script[i];
+    }
+}
+
+/**
+ *    Runs a conversation made of the given messages.
+ */
+static int converse(struct conv_state* state, const struct pam_message* messages, int num_msg, struct pam_response** resp) {
+    const struct pam_message* msg[PAM_MAX_NUM_MSG];
+    for (int i = 0; i < num_msg; i++) {
+        msg[i] = &messages[i];
+    }
+    return conversation(num_msg, msg, resp, state);
+}
+
+static int fail(const char* test, const char* what) {
+    fprintf(stderr, "FAIL: %s: %s\n", test, what);
+    return 1;
+}
+
+/**
+ *    Checks that everything allocated by a failed conversation, and nothing else, has been released exactly once.
+ *
+ *    @return The number of failures.
+ */
+static int check_released(const char* test) {
+    int failures = 0;
+    for (int i = 0; i < tracked_size; i++) {
+        if (tracked[i].frees != 1) {
+            failures += fail(test, tracked[i].frees == 0 ? "block leaked" : "block released more than once");
+        }
+    }
+    if (unknown_frees > 0) {
+        failures += fail(test, "block released that the conversation did not allocate");
+    }
+    return failures;
+}
+
+/**
+ *    Checks that a successful conversation has released nothing that it gave to PAM.
+ *
+ *    @return The number of failures.
+ */
+static int check_kept(const char* test, struct pam_response* resp, int num_msg) {
+    int failures = 0;
+    for (int i = 0; i < tracked_size; i++) {
+        int given = tracked[i].ptr == resp;
+        for (int j = 0; j < num_msg; j++) {
+            given |= tracked[i].ptr == resp[j].resp;
+        }
+        if (given && tracked[i].frees > 0) {
+            failures += fail(test, "response released before PAM could use it");
+        }
+    }
+    return failures;
+}
+
+/**
+ *    Answers a single prompt from a new pseudo-terminal, as when locked, with the given keys.
+ *    The keys are typed by another process once the terminal has been set up for the prompt,
+ *    so that they are echoed only if the terminal has been asked to.
+ *
+ *    @return The number of failures: the answer must be `expected`, the terminal must have echoed `expected_echo`,
+ *            and what has been printed must contain `expected_shown` and, if the prompt is hidden, never the answer.
+ */
+static int answer_on_terminal(const char* test, struct options* options, int style, const char* keys, const char* expected,
+                              const char* expected_echo, const char* expected_shown) {
+    int echo = style == PAM_PROMPT_ECHO_ON;
+    int master_fd = posix_openpt(O_RDWR | O_NOCTTY);
+    if (master_fd < 0 || grantpt(master_fd) < 0 || unlockpt(master_fd) < 0) {
+        return fail(test, "cannot open a pseudo-terminal");
+    }
+    int slave_fd = open(ptsname(master_fd), O_RDWR | O_NOCTTY);
+    if (slave_fd < 0 || dup2(slave_fd, STDIN_FILENO) < 0) {
+        close(master_fd);
+        return fail(test, "cannot open a pseudo-terminal");
+    }
+    close(slave_fd);
+    clearerr(stdin);
+    FILE* output = tmpfile();
+    if (output == NULL) {
+        close(master_fd);
+        return fail(test, "cannot capture the output");
+    }
+    fflush(stdout);
+    int saved_stdout = dup(STDOUT_FILENO);
+    dup2(fileno(output), STDOUT_FILENO);
+
+    // Start from the opposite of what the prompt needs, to know when the terminal is ready
+    struct termios term;
+    tcgetattr(STDIN_FILENO, &term);
+    term.c_lflag = echo ? term.c_lflag & ~ECHO : term.c_lflag | ECHO;
+    tcsetattr(STDIN_FILENO, TCSANOW, &term);
+
+    pid_t pid = fork();
+    if (pid == 0) {
+        struct timespec wait = { 0, TYPE_WAIT_MS * 1000000L };
+        for (int i = 0; i < TYPE_MAX_WAITS; i++) {
+            if (tcgetattr(STDIN_FILENO, &term) == 0 && ((term.c_lflag & ECHO) != 0) == echo) {
+                break;
+            }
+            nanosleep(&wait, NULL);
+        }
+        _exit(write(master_fd, keys, strlen(keys)) < 0);
+    }
+
+    const char* none[] = { NULL };
+    struct pam_message prompt[] = { { style, echo ? "Code: " : "Password: " } };
+    struct conv_state state;
+    memset(&state, 0, sizeof(state));
+    start_conversation(&state, options, none);
+    state.read_answer = read_answer_from_terminal;
+    struct pam_response* resp = NULL;
+    int ret = converse(&state, prompt, 1, &resp);
+    waitpid(pid, NULL, 0);
+    free_messages(&state);
+
+    int failures = 0;
+    if (ret != PAM_SUCCESS) {
+        failures += fail(test, "conversation failed");
+    } else {
+        if (strcmp(resp[0].resp, expected) != 0) {
+            failures += fail(test, "wrong response");
+        }
+        free_responses(resp, 1);
+    }
+
+    char echoed[OUTPUT_SIZE];
+    fcntl(master_fd, F_SETFL, O_NONBLOCK);
+    ssize_t len = read(master_fd, echoed, sizeof(echoed) - 1);
+    echoed[len > 0 ? len : 0] = 0;
+    close(master_fd);
+    if (strcmp(echoed, expected_echo) != 0) {
+        failures += fail(test, echo ? "answer not echoed by the terminal" : "answer echoed by the terminal");
+    }
+
+    char shown[OUTPUT_SIZE];
+    fflush(stdout);
+    dup2(saved_stdout, STDOUT_FILENO);
+    close(saved_stdout);
+    rewind(output);
+    len = fread(shown, 1, sizeof(shown) - 1, output);
+    shown[len] = 0;
+    fclose(output);
+    if (strstr(shown, expected_shown) == NULL) {
+        failures += fail(test, "wrong feedback shown");
+    }
+    if (!echo && strstr(shown, expected) != NULL) {
+        failures += fail(test, "answer shown");
+    }
+    if (echo && (strchr(shown, '*') != NULL || strstr(shown, "\u25cf") != NULL)) {
+        failures += fail(test, "visible answer masked");
+    }
+    return failures;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    // What the conversation draws on the screen is not checked here
+    if (freopen("/dev/null", "w", stdout) == NULL) {
+        perror("freopen");
+        return 1;
+    }
+
+    // The warnings about the users are not interesting either
+    char* args[] = { "simplylock", "--force", NULL };
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(2, args);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    if (options == NULL) {
+        return 1;
+    }
+
+    struct conv_state state;
+    memset(&state, 0, sizeof(state));
+    struct pam_response* resp;
+
+    // A password prompt gets the answer, without echo
+    const char* password[] = { "secret", NULL };
+    struct pam_message prompt_off[] = { { PAM_PROMPT_ECHO_OFF, "Password: " } };
+    start_conversation(&state, options, password);
+    if (converse(&state, prompt_off, 1, &resp) != PAM_SUCCESS) {
+        failures += fail("echo off", "conversation failed");
+    } else {
+        if (strcmp(resp[0].resp, "secret") != 0 || resp[0].resp_retcode != 0) {
+            failures += fail("echo off", "wrong response");
+        }
+        if (echoes[0] != 0) {
+            failures += fail("echo off", "answer echoed");
+        }
+        failures += check_kept("echo off", resp, 1);
+        free_responses(resp, 1);
+    }
+
+    // A visible prompt, e.g. for a one-time code, is answered with echo
+    const char* code[] = { "123456", NULL };
+    struct pam_message prompt_on[] = { { PAM_PROMPT_ECHO_ON, "Code: " } };
+    start_conversation(&state, options, code);
+    if (converse(&state, prompt_on, 1, &resp) != PAM_SUCCESS) {
+        failures += fail("echo on", "conversation failed");
+    } else {
+        if (strcmp(resp[0].resp, "123456") != 0) {
+            failures += fail("echo on", "wrong response");
+        }
+        if (echoes[0] != 1) {
+            failures += fail("echo on", "answer not echoed");
+        }
+        free_responses(resp, 1);
+    }
+
+    // Informational and error messages have no response, and are kept to be shown above the prompts
+    const char* none[] = { NULL };
+    struct pam_message mixed[] = {
+        { PAM_TEXT_INFO, "Touch your key" },
+        { PAM_ERROR_MSG, "Key not found" },
+        { PAM_PROMPT_ECHO_OFF, "Password: " }
+    };
+    start_conversation(&state, options, password);
+    if (converse(&state, mixed, 3, &resp) != PAM_SUCCESS) {
+        failures += fail("messages", "conversation failed");
+    } else {
+        if (resp[0].resp != NULL || resp[1].resp != NULL || strcmp(resp[2].resp, "secret") != 0) {
+            failures += fail("messages", "wrong responses");
+        }
+        failures += check_kept("messages", resp, 3);
+        free_responses(resp, 3);
+    }
+    if (state.messages_size != 2 || strcmp(state.messages[0], "Touch your key") != 0 || state.messages_style[0] != PAM_TEXT_INFO ||
+            strcmp(state.messages[1], "Key not found") != 0 || state.messages_style[1] != PAM_ERROR_MSG) {
+        failures += fail("messages", "messages not kept");
+    }
+
+    // Only the last messages are kept
+    struct pam_message infos[MAX_MESSAGES + 2];
+    char texts[MAX_MESSAGES + 2][16];
+    for (int i = 0; i < MAX_MESSAGES + 2; i++) {
+        snprintf(texts[i], sizeof(texts[i]), "Message %d", i);
+        infos[i].msg_style = PAM_TEXT_INFO;
+        infos[i].msg = texts[i];
+    }
+    start_conversation(&state, options, none);
+    if (converse(&state, infos, MAX_MESSAGES + 2, &resp) != PAM_SUCCESS) {
+        failures += fail("many messages", "conversation failed");
+    } else {
+        free_responses(resp, MAX_MESSAGES + 2);
+    }
+    if (state.messages_size != MAX_MESSAGES || strcmp(state.messages[0], texts[2]) != 0 ||
+            strcmp(state.messages[MAX_MESSAGES - 1], texts[MAX_MESSAGES + 1]) != 0) {
+        failures += fail("many messages", "wrong messages kept");
+    }
+
+    // Unknown kinds of messages, and answers that cannot be read, fail the conversation without responses
+    struct pam_message unknown[] = { { PAM_TEXT_INFO, "Hello" }, { 1000, "?" } };
+    start_conversation(&state, options, none);
+    resp = &dangling;
+    if (converse(&state, unknown, 2, &resp) != PAM_CONV_ERR || resp != NULL) {
+        failures += fail("unknown style", "conversation not failed");
+    }
+    start_conversation(&state, options, none);
+    resp = &dangling;
+    if (converse(&state, prompt_off, 1, &resp) != PAM_CONV_ERR || resp != NULL) {
+        failures += fail("no answer", "conversation not failed");
+    }
+
+    // And so do conversations with no messages, or with too many
+    start_conversation(&state, options, none);
+    resp = &dangling;
+    if (converse(&state, prompt_off, 0, &resp) != PAM_CONV_ERR || resp != NULL) {
+        failures += fail("no messages", "conversation not failed");
+    }
+    const struct pam_message* too_many[PAM_MAX_NUM_MSG + 1];
+    for (int i = 0; i < PAM_MAX_NUM_MSG + 1; i++) {
+        too_many[i] = &prompt_off[0];
+    }
+    resp = &dangling;
+    if (conversation(PAM_MAX_NUM_MSG + 1, too_many, &resp, &state) != PAM_CONV_ERR || resp != NULL || answers_read > 0) {
+        failures += fail("too many messages", "conversation not failed");
+    }
+
+    // A failure after some prompts have been answered releases the answers and the responses, once
+    const char* two_answers[] = { "first", "second", NULL };
+    struct pam_message three_prompts[] = {
+        { PAM_PROMPT_ECHO_ON, "Login: " },
+        { PAM_PROMPT_ECHO_OFF, "Password: " },
+        { PAM_PROMPT_ECHO_OFF, "Code: " }
+    };
+    start_conversation(&state, options, two_answers);
+    if (converse(&state, three_prompts, 3, &resp) != PAM_CONV_ERR || resp != NULL) {
+        failures += fail("last answer missing", "conversation not failed");
+    }
+    failures += check_released("last answer missing");
+
+    struct pam_message prompt_then_unknown[] = {
+        { PAM_PROMPT_ECHO_OFF, "Password: " },
+        { PAM_TEXT_INFO, "Checking" },
+        { 1000, "?" }
+    };
+    start_conversation(&state, options, password);
+    if (converse(&state, prompt_then_unknown, 3, &resp) != PAM_CONV_ERR || resp != NULL) {
+        failures += fail("unknown style after a prompt", "conversation not failed");
+    }
+    failures += check_released("unknown style after a prompt");
+
+    // The duress password aborts the conversation, and its answer never reaches PAM
+    const char* hash = crypt("duress", "$6$simplylock$");
+    if (hash == NULL || *hash == '*') {
+        failures += fail("duress", "cannot hash the duress password");
+    } else {
+        snprintf(duress_hash, sizeof(duress_hash), "%s", hash);
+        const char* duress[] = { "first", "duress", NULL };
+        struct pam_message login_and_password[] = {
+            { PAM_PROMPT_ECHO_ON, "Login: " },
+            { PAM_PROMPT_ECHO_OFF, "Password: " }
+        };
+        start_conversation(&state, options, duress);
+        if (converse(&state, login_and_password, 2, &resp) != PAM_CONV_ERR || resp != NULL || !state.duress) {
+            failures += fail("duress", "conversation not aborted");
+        }
+        failures += check_released("duress");
+        *duress_hash = 0;
+    }
+
+    // On the terminal, a visible prompt is echoed as typed and a password never is, whatever the echo mode
+    options->echo_mode = ECHO_HIDDEN;
+    failures += answer_on_terminal("hidden, echo off", options, PAM_PROMPT_ECHO_OFF, "secret\n", "secret", "", "Password: ");
+    failures += answer_on_terminal("hidden, echo on", options, PAM_PROMPT_ECHO_ON, "123456\n", "123456", "123456\r\n", "Code: ");
+    options->echo_mode = ECHO_ASTERISK;
+    failures += answer_on_terminal("asterisk, echo off", options, PAM_PROMPT_ECHO_OFF, "secret\n", "secret", "", "******\n");
+    failures += answer_on_terminal("asterisk, echo on", options, PAM_PROMPT_ECHO_ON, "123456\n", "123456", "123456\r\n", "Code: ");
+    options->echo_mode = ECHO_DOTS;
+    options->mask_dots = 4;
+    failures += answer_on_terminal("dots, echo off", options, PAM_PROMPT_ECHO_OFF, "secret\n", "secret", "", "\u25cf\u25cf\u25cf\u25cf\n");
+    failures += answer_on_terminal("dots, echo on", options, PAM_PROMPT_ECHO_ON, "123456\n", "123456", "123456\r\n", "Code: ");
+    options->echo_mode = ECHO_REVEAL;
+    failures += answer_on_terminal("reveal, echo off", options, PAM_PROMPT_ECHO_OFF, "secret\n", "secret", "", "s\b*");
+    failures += answer_on_terminal("reveal, echo on", options, PAM_PROMPT_ECHO_ON, "123456\n", "123456", "123456\r\n", "Code: ");
+
+    free_messages(&state);
+    options_free(options);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
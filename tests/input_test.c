@@ -0,0 +1,283 @@
+#define _XOPEN_SOURCE 700
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <termios.h>
+#include <time.h>
+#include <sys/wait.h>
+#include "input.h"
+
+#define OUTPUT_SIZE 4096
+
+// The dots of --echo-mode dots, as drawn
+#define EMPTY "\u25cb"
+#define FULL "\u25cf"
+
+// Master side of the pseudo-terminal standing for the locked terminal: what is written to it is typed
+static int master_fd = -1;
+
+// Where stdout goes, to check what is shown while typing
+static FILE* output_file = NULL;
+
+/**
+ *    Opens a new pseudo-terminal as stdin, replacing the previous one.
+ */
+static int open_terminal() {
+    if (master_fd >= 0) {
+        close(master_fd);
+    }
+    master_fd = posix_openpt(O_RDWR | O_NOCTTY);
+    if (master_fd < 0 || grantpt(master_fd) < 0 || unlockpt(master_fd) < 0) {
+        return -1;
+    }
+    int slave_fd = open(ptsname(master_fd), O_RDWR | O_NOCTTY);
+    if (slave_fd < 0 || dup2(slave_fd, STDIN_FILENO) < 0) {
+        return -1;
+    }
+    close(slave_fd);
+    clearerr(stdin);
+    return 0;
+}
+
+/**
+ *    Types the given keys, as a user would while simplylock waits for them.
+ *    In non-canonical mode, the keys are not edited by the terminal, and are read one by one.
+ */
+static void type(const char* keys, int canonical) {
+    struct termios term;
+    tcgetattr(STDIN_FILENO, &term);
+    term.c_lflag &= ~ECHO;
+    if (canonical) {
+        term.c_lflag |= ICANON;
+    } else {
+        term.c_lflag &= ~ICANON;
+        term.c_cc[VMIN] = 1;
+        term.c_cc[VTIME] = 0;
+    }
+    tcsetattr(STDIN_FILENO, TCSANOW, &term);
+    if (write(master_fd, keys, strlen(keys)) < 0) {
+        perror("write");
+    }
+}
+
+/**
+ *    Hangs up the terminal: from now on, reading from it fails as on EOF.
+ */
+static void hang_up() {
+    close(master_fd);
+    master_fd = -1;
+}
+
+/**
+ *    Replaces stdin with a pipe that gives the given input, then EOF: unlike a terminal that is hung up,
+ *    it never discards a line typed only in part.
+ */
+static int open_pipe(const char* input) {
+    int fds[2];
+    if (pipe(fds) < 0) {
+        return -1;
+    }
+    ssize_t len = write(fds[1], input, strlen(input));
+    close(fds[1]);
+    if (len < 0 || dup2(fds[0], STDIN_FILENO) < 0) {
+        close(fds[0]);
+        return -1;
+    }
+    close(fds[0]);
+    clearerr(stdin);
+    return 0;
+}
+
+/**
+ *    Forgets what has been shown so far.
+ */
+static void start_output() {
+    fflush(stdout);
+    if (ftruncate(fileno(output_file), 0) < 0) {
+        perror("ftruncate");
+    }
+    lseek(fileno(output_file), 0, SEEK_SET);
+}
+
+/**
+ *    Returns what has been shown since the last call to `start_output`.
+ */
+static const char* get_output() {
+    static char output[OUTPUT_SIZE];
+    fflush(stdout);
+    ssize_t len = pread(fileno(output_file), output, sizeof(output) - 1, 0);
+    output[len > 0 ? len : 0] = 0;
+    return output;
+}
+
+/**
+ *    Types the given keys after the given number of milliseconds, from another process,
+ *    once it has checked that what is shown at that moment is the expected output.
+ *
+ *    @return The PID of the process typing, which exits with `1` if the output was not the expected one.
+ */
+static pid_t type_later(const char* keys, long ms, const char* expected_output) {
+    pid_t pid = fork();
+    if (pid != 0) {
+        return pid;
+    }
+    struct timespec delay = { ms / 1000, (ms % 1000) * 1000000 };
+    nanosleep(&delay, NULL);
+    int ok = strcmp(get_output(), expected_output) == 0;
+    if (!ok) {
+        fprintf(stderr, "FAIL: after %ld ms, expected the output \"%s\", got \"%s\"\n", ms, expected_output, get_output());
+    }
+    if (write(master_fd, keys, strlen(keys)) < 0) {
+        perror("write");
+    }
+    _exit(!ok);
+}
+
+/**
+ *    Checks the line read against the expected one, `NULL` meaning that nothing must have been read.
+ *    The line is freed.
+ *
+ *    @return `0` if they match, `1` otherwise.
+ */
+static int check_line(const char* test, char* line, const char* expected) {
+    int ok = line == NULL ? expected == NULL : expected != NULL && strcmp(line, expected) == 0;
+    if (!ok) {
+        fprintf(stderr, "FAIL: %s: expected %s%s%s, got %s%s%s\n", test,
+                expected != NULL ? "\"" : "", expected != NULL ? expected : "nothing", expected != NULL ? "\"" : "",
+                line != NULL ? "\"" : "", line != NULL ? line : "nothing", line != NULL ? "\"" : "");
+    }
+    free(line);
+    return !ok;
+}
+
+/**
+ *    Checks what has been shown since the last call to `start_output`.
+ *
+ *    @return `0` if it is the expected output, `1` otherwise.
+ */
+static int check_output(const char* test, const char* expected) {
+    const char* output = get_output();
+    if (strcmp(output, expected) == 0) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: %s: expected the output \"%s\", got \"%s\"\n", test, expected, output);
+    return 1;
+}
+
+/**
+ *    Types the given keys on a new terminal and reads them masked, checking both the line read and what is shown.
+ *
+ *    @return The number of failures.
+ */
+static int check_masked(const char* test, const char* keys, unsigned int dots, int reveal, size_t max_len, int bell,
+                        const char* expected_line, const char* expected_output) {
+    int timed_out = 0;
+    open_terminal();
+    type(keys, 0);
+    start_output();
+    int failures = check_line(test, input_read_masked(0, dots, reveal, max_len, bell, 0, &timed_out), expected_line);
+    return failures + check_output(test, expected_output);
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+    int timed_out = 0;
+
+    output_file = tmpfile();
+    if (output_file == NULL || dup2(fileno(output_file), STDOUT_FILENO) < 0) {
+        perror("tmpfile");
+        return 1;
+    }
+
+    // As in simplylock, every key is shown as soon as it is typed
+    setbuf(stdout, NULL);
+
+    // A line is read as typed
+    if (open_terminal() < 0) {
+        perror("open_terminal");
+        return 1;
+    }
+    type("secret\n", 1);
+    failures += check_line("line", input_read_line(0, 256, 0, &timed_out), "secret");
+    type("secret\n", 0);
+    failures += check_line("masked line", input_read_masked(0, 0, 0, 256, 0, 0, &timed_out), "secret");
+
+    // EOF never gives an empty answer, nor the part of the line typed before it
+    hang_up();
+    failures += check_line("line on EOF", input_read_line(0, 256, 0, &timed_out), NULL);
+    open_terminal();
+    hang_up();
+    failures += check_line("masked line on EOF", input_read_masked(0, 0, 0, 256, 0, 0, &timed_out), NULL);
+    open_terminal();
+    type("sec", 0);
+    hang_up();
+    failures += check_line("masked line cut by EOF", input_read_masked(0, 0, 0, 256, 0, 0, &timed_out), NULL);
+    open_pipe("");
+    failures += check_line("empty input", input_read_line(0, 256, 0, &timed_out), NULL);
+    open_pipe("sec");
+    failures += check_line("line cut by EOF", input_read_line(0, 256, 0, &timed_out), NULL);
+
+    // A line can still be read after EOF, e.g. from a terminal opened again
+    open_terminal();
+    type("again\n", 1);
+    failures += check_line("line after EOF", input_read_line(0, 256, 0, &timed_out), "again");
+
+    // Backspace erases a whole character, however many bytes it takes, and a single `*`
+    failures += check_masked("backspace after é", "a\xc3\xa9\x7f\n", 0, 0, 256, 0, "a", "**\b \b\n");
+    failures += check_masked("backspace after €", "\xe2\x82\xac\x7f\x7f" "b\n", 0, 0, 256, 0, "b", "*\b \b*\n");
+    failures += check_masked("^H after é", "\xc3\xa9" "a\b\b\n", 0, 0, 256, 0, "", "**\b \b\b \b\n");
+    failures += check_masked("é kept", "\xc3\xa9\xc3\xa9\x7f\xc3\xa8\n", 0, 0, 256, 0, "\xc3\xa9\xc3\xa8", "**\b \b*\n");
+
+    // Once the line is as long as allowed, keys are ignored, with a bell if asked, until something is erased
+    failures += check_masked("cap", "abcd\n", 0, 0, 3, 0, "abc", "***\n");
+    failures += check_masked("cap with bell", "abcde\n", 0, 0, 3, 1, "abc", "***\a\a\n");
+    failures += check_masked("cap then backspace", "abcde\x7fx\n", 0, 0, 3, 0, "abx", "***\b \b*\n");
+
+    // Characters are never cut: one that starts within the limit is kept whole, one past it is ignored whole
+    failures += check_masked("cap with é started", "a\xc3\xa9\n", 0, 0, 2, 0, "a\xc3\xa9", "**\n");
+    failures += check_masked("cap with é ignored", "ab\xc3\xa9\n", 0, 0, 2, 1, "ab", "**\a\n");
+    open_terminal();
+    type("abcdef\n", 1);
+    failures += check_line("line cap", input_read_line(0, 3, 0, &timed_out), "abc");
+    type("a\xc3\xa9\n", 1);
+    failures += check_line("line cap within é", input_read_line(0, 2, 0, &timed_out), "a");
+
+    // --echo-mode hidden: nothing is shown but the newline that was not echoed
+    open_terminal();
+    type("s3cr\xc3\xa9t\n", 1);
+    start_output();
+    failures += check_line("hidden", input_read_line(0, 256, 0, &timed_out), "s3cr\xc3\xa9t");
+    failures += check_output("hidden", "\n");
+
+    // --echo-mode asterisk: a `*` per character
+    failures += check_masked("asterisk", "s3cr\xc3\xa9t\n", 0, 0, 256, 0, "s3cr\xc3\xa9t", "******\n");
+
+    // --echo-mode dots:N: the row is redrawn at every key, and never shows more than N characters
+    failures += check_masked("dots:2", "ab\x7f" "cd\n", 2, 0, 256, 0, "acd",
+                             EMPTY EMPTY "\b\b" FULL EMPTY "\b\b" FULL FULL "\b\b" FULL EMPTY "\b\b" FULL FULL "\n");
+    failures += check_masked("dots:2 past the row", "abc\x7f\n", 2, 0, 256, 0, "ab",
+                             EMPTY EMPTY "\b\b" FULL EMPTY "\b\b" FULL FULL "\b\b" FULL EMPTY "\n");
+
+    // --echo-mode reveal: every character until the next key, then its `*`
+    failures += check_masked("reveal", "a\xc3\xa9\x7f" "b\n", 0, 1, 256, 0, "ab", "a\b*\xc3\xa9\b*\b \bb\b*\n");
+
+    // ...or until a moment has passed without keys
+    open_terminal();
+    type("a", 0);
+    start_output();
+    pid_t pid = type_later("b\n", 1500, "a\b*");
+    failures += check_line("reveal timeout", input_read_masked(0, 0, 1, 256, 0, 0, &timed_out), "ab");
+    failures += check_output("reveal timeout", "a\b*b\b*\n");
+    int status;
+    waitpid(pid, &status, 0);
+    failures += !WIFEXITED(status) || WEXITSTATUS(status) != 0;
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
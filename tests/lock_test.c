@@ -0,0 +1,275 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <signal.h>
+#include <errno.h>
+#include <sys/wait.h>
+#include <sys/resource.h>
+#include "lock.h"
+#include "options.h"
+#include "vt.h"
+
+#define MAX_ARGS 10
+
+// Values like the ones of a real kernel, with the separators printk really uses,
+// and how they are written back when restored
+#define SYSRQ_VALUE "176\n"
+#define PRINTK_VALUE "4\t4\t1\t7\n"
+#define SYSRQ_RESTORED "176"
+#define PRINTK_RESTORED "4 4 1 7"
+
+static char sysrq_path[] = "/tmp/simplylock-sysrq-XXXXXX";
+static char printk_path[] = "/tmp/simplylock-printk-XXXXXX";
+
+static int write_file(const char* path, const char* content) {
+    FILE* file = fopen(path, "w");
+    if (file == NULL) {
+        return -1;
+    }
+    int ret = fputs(content, file) < 0 ? -1 : 0;
+    return fclose(file) != 0 ? -1 : ret;
+}
+
+static void read_file(const char* path, char* content, size_t n) {
+    content[0] = 0;
+    FILE* file = fopen(path, "r");
+    if (file == NULL) {
+        return;
+    }
+    size_t len = fread(content, 1, n - 1, file);
+    content[len] = 0;
+    fclose(file);
+}
+
+/**
+ *    Checks that a control file contains the expected value.
+ *
+ *    @return `0` if it does, `1` otherwise.
+ */
+static int check_file(const char* test, const char* when, const char* path, const char* expected) {
+    char content[100];
+    read_file(path, content, sizeof(content));
+    if (strcmp(content, expected) == 0) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: %s: %s, expected \"%s\" but found \"%s\"\n", test, when, expected, content);
+    return 1;
+}
+
+/**
+ *    Parses the command line of a test, with the warnings printed on stderr discarded,
+ *    and points the control files to the temporary ones.
+ *    The terminal is locked in place and nothing else is done to it, so that the test needs no console.
+ */
+static struct options* parse(const char* const* args) {
+    char* argv[MAX_ARGS + 4] = { "simplylock", "--force", "--in-place" };
+    int argc = 3;
+    for (int i = 0; i < MAX_ARGS && args[i] != NULL; i++) {
+        argv[argc++] = (char*)args[i];
+    }
+    argv[argc] = NULL;
+
+    // Every parse starts from scratch
+    optind = 0;
+
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(argc, argv);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    if (options == NULL) {
+        return NULL;
+    }
+    options->sysrq_path = sysrq_path;
+    options->printk_path = printk_path;
+    options->block_vt_switch = 0;
+    options->hide_cursor = 0;
+    return options;
+}
+
+/**
+ *    A vt that is never opened: locking in place does not need to touch it,
+ *    and restoring it after a crash fails without touching the terminal of the test.
+ */
+static struct vt* fake_vt() {
+    struct vt* vt = (struct vt*)calloc(1, sizeof(struct vt));
+    if (vt != NULL) {
+        vt->number = 63;
+        vt->fd = -1;
+    }
+    return vt;
+}
+
+/**
+ *    Locks and unlocks with the given options, checking the control files while locked and after unlocking.
+ *
+ *    @return The number of failures.
+ */
+static int run_test(const char* test, const char* const* args, const char* locked_sysrq, const char* locked_printk,
+                    const char* unlocked_sysrq, const char* unlocked_printk) {
+    if (write_file(sysrq_path, SYSRQ_VALUE) < 0 || write_file(printk_path, PRINTK_VALUE) < 0) {
+        fprintf(stderr, "FAIL: %s: cannot write the control files\n", test);
+        return 1;
+    }
+    struct options* options = parse(args);
+    if (options == NULL) {
+        fprintf(stderr, "FAIL: %s: options refused\n", test);
+        return 1;
+    }
+
+    int failures = 0;
+    if (lock_with_vt(options, fake_vt()) == NULL) {
+        fprintf(stderr, "FAIL: %s: lock failed\n", test);
+        failures++;
+    } else {
+        failures += check_file(test, "while locked", sysrq_path, locked_sysrq);
+        failures += check_file(test, "while locked", printk_path, locked_printk);
+    }
+
+    unlock(options);
+    failures += check_file(test, "after unlocking", sysrq_path, unlocked_sysrq);
+    failures += check_file(test, "after unlocking", printk_path, unlocked_printk);
+
+    options_free(options);
+    return failures;
+}
+
+/**
+ *    Locks with the given options when the console cannot be opened, so that locking fails after the control files
+ *    have been changed, and checks that they have been written back.
+ *    Without `in_place`, the switch to the new vt fails, otherwise the locking of vt switching does.
+ *
+ *    @return The number of failures.
+ */
+static int run_failure_test(const char* test, const char* const* args, int in_place) {
+    if (write_file(sysrq_path, SYSRQ_VALUE) < 0 || write_file(printk_path, PRINTK_VALUE) < 0) {
+        fprintf(stderr, "FAIL: %s: cannot write the control files\n", test);
+        return 1;
+    }
+    struct options* options = parse(args);
+    if (options == NULL) {
+        fprintf(stderr, "FAIL: %s: options refused\n", test);
+        return 1;
+    }
+
+    // The vt to go back to is given, since the current one cannot be asked to the console
+    options->in_place = in_place;
+    options->switch_back_to = 2;
+    options->block_vt_switch = 1;
+
+    int failures = 0;
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct vt* locked = lock_with_vt(options, fake_vt());
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    if (locked != NULL) {
+        fprintf(stderr, "FAIL: %s: locked without a console\n", test);
+        failures++;
+        unlock(options);
+    }
+    failures += check_file(test, "after failing", sysrq_path, SYSRQ_RESTORED);
+    failures += check_file(test, "after failing", printk_path, PRINTK_RESTORED);
+
+    options_free(options);
+    return failures;
+}
+
+/**
+ *    Locks in a child process that then crashes, and checks that the control files have been written back
+ *    and that the process died of the signal of the crash.
+ *
+ *    @return The number of failures.
+ */
+static int run_crash_test(const char* test, const char* const* args, const char* crashed_sysrq,
+                          const char* crashed_printk) {
+    if (write_file(sysrq_path, SYSRQ_VALUE) < 0 || write_file(printk_path, PRINTK_VALUE) < 0) {
+        fprintf(stderr, "FAIL: %s: cannot write the control files\n", test);
+        return 1;
+    }
+
+    pid_t pid = fork();
+    if (pid == 0) {
+
+        // The crash is expected, and must not leave a core dump behind
+        struct rlimit no_core = { 0, 0 };
+        setrlimit(RLIMIT_CORE, &no_core);
+        struct options* options = parse(args);
+        if (options == NULL || lock_with_vt(options, fake_vt()) == NULL || lock_handle_crashes(1) < 0) {
+            _exit(2);
+        }
+        raise(SIGSEGV);
+        _exit(3);
+    } else if (pid == -1) {
+        perror("fork");
+        return 1;
+    }
+
+    int failures = 0;
+    int status;
+    pid_t wpid;
+    while ((wpid = waitpid(pid, &status, 0)) == -1 && errno == EINTR);
+    if (wpid == -1 || !WIFSIGNALED(status) || WTERMSIG(status) != SIGSEGV) {
+        fprintf(stderr, "FAIL: %s: expected the process to die of SIGSEGV\n", test);
+        failures++;
+    }
+    failures += check_file(test, "after the crash", sysrq_path, crashed_sysrq);
+    failures += check_file(test, "after the crash", printk_path, crashed_printk);
+    return failures;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    int sysrq_fd = mkstemp(sysrq_path);
+    int printk_fd = mkstemp(printk_path);
+    if (sysrq_fd < 0 || printk_fd < 0) {
+        perror("mkstemp");
+        return 1;
+    }
+    close(sysrq_fd);
+    close(printk_fd);
+
+    // Saved, then zeroed (printk only in its current log level), then written back
+    const char* none[] = { NULL };
+    failures += run_test("save, zero and restore", none, "0", "0 4 1 7", SYSRQ_RESTORED, PRINTK_RESTORED);
+
+    const char* mask[] = { "--sysrq-mask", "48", NULL };
+    failures += run_test("--sysrq-mask", mask, "48", "0 4 1 7", SYSRQ_RESTORED, PRINTK_RESTORED);
+
+    // Skipped files must not even be rewritten
+    const char* no_sysrq[] = { "-s", NULL };
+    failures += run_test("-s", no_sysrq, SYSRQ_VALUE, "0 4 1 7", SYSRQ_VALUE, PRINTK_RESTORED);
+
+    const char* no_printk[] = { "-k", NULL };
+    failures += run_test("-k", no_printk, "0", PRINTK_VALUE, SYSRQ_RESTORED, PRINTK_VALUE);
+
+    // Failing after the control files have been changed writes them back
+    failures += run_failure_test("failed switch", none, 0);
+    failures += run_failure_test("failed lock of vt switching", none, 1);
+
+    // A crash writes back the saved values, and leaves alone the files that were never changed
+    failures += run_crash_test("crash", none, SYSRQ_RESTORED, PRINTK_RESTORED);
+    failures += run_crash_test("crash with -s", no_sysrq, SYSRQ_VALUE, PRINTK_RESTORED);
+
+    unlink(sysrq_path);
+    unlink(printk_path);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
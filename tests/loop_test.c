@@ -0,0 +1,376 @@
+// The auth loop is private to main.c, which is included with its main renamed.
+// The loop runs in a child process, on a pseudo-terminal standing for the locked terminal,
+// and the test plays the user: it waits for the screen to show something, then types.
+// Pseudo-terminals need the X/Open definitions, which main.c does not ask for.
+#define _XOPEN_SOURCE 700
+#define main simplylock_main
+#include "main.c"
+#undef main
+
+#define SCREEN_SIZE 65536
+#define MAX_ARGS 16
+#define MAX_SCRIPT 8
+#define ARG_SIZE 256
+#define EXPECT_TIMEOUT_MS 5000
+#define SIGNAL_DELAY_MS 200
+
+// The password whose hash is given with --password-hash
+#define PASSWORD "right horse"
+
+static char hash_path[] = "/tmp/simplylock-hash-XXXXXX";
+
+// Master side of the pseudo-terminal: what is written to it is typed, what is read from it has been drawn
+static int master_fd = -1;
+
+// Process running the loop
+static pid_t loop_pid = -1;
+
+// Outcomes of the attempts made with the scripted authenticator, in order: the last one is repeated
+static auth_result_t script[MAX_SCRIPT];
+static int script_size = 0;
+
+// Everything drawn so far, and how much of it has already been expected
+static char screen[SCREEN_SIZE];
+static size_t screen_len = 0;
+static size_t screen_seen = 0;
+
+/**
+ *    Authenticator standing for PAM, which gives the outcomes in `script` without reading anything,
+ *    and draws which user it has been asked to authenticate.
+ */
+static auth_result_t authenticate_scripted(struct auth_session* session, char* user) {
+    fprintf(stdout, "[authenticate %s]", user != NULL ? user : "(none)");
+    static int attempt = 0;
+    return script[attempt < script_size - 1 ? attempt++ : script_size - 1];
+}
+
+/**
+ *    Locks the pseudo-terminal as the locking process does with --io-stdio, and runs the auth loop on it,
+ *    authenticating with `authenticate`.
+ *    Never returns: the process exits with `0` once the station is unlocked.
+ */
+static void run_locked(const char* slave_path, struct options* options,
+                       auth_result_t (*authenticate)(struct auth_session* session, char* user)) {
+
+    // The terminal becomes the controlling one, so that Ctrl+C is delivered as on a real console
+    setsid();
+    int slave_fd = open(slave_path, O_RDWR);
+    if (slave_fd < 0 || dup2(slave_fd, STDIN_FILENO) < 0 || dup2(slave_fd, STDOUT_FILENO) < 0) {
+        _exit(2);
+    }
+    close(slave_fd);
+
+    sigset_t winch_mask;
+    sigemptyset(&winch_mask);
+    sigaddset(&winch_mask, SIGWINCH);
+    if (register_signal(SIGINT, on_sigint) < 0 || register_signal(SIGWINCH, on_sigwinch) < 0 ||
+            sigprocmask(SIG_BLOCK, &winch_mask, NULL) < 0) {
+        _exit(2);
+    }
+
+    struct vt* vt = vt_fromstdio();
+    if (vt == NULL || vt_signals(vt, VT_SIGINT) < 0 || vt_seteol(vt, REDRAW_KEY) < 0) {
+        _exit(2);
+    }
+    setbuf(stdout, NULL);
+
+    struct auth_session* session = auth_load_hashes(options) < 0 ? NULL : auth_session_start(options);
+    if (session == NULL) {
+        _exit(2);
+    }
+    void* bg = NULL;
+    char* user = options->pam_username || options->ask_username ? NULL : options->users[0];
+    run_loop(options, vt, &bg, session, user, authenticate);
+    _exit(0);
+}
+
+/**
+ *    Starts the auth loop for the given command line, on a new pseudo-terminal.
+ *    The attempts have the given outcomes, in order, or are checked against the password hash if `outcomes` is `NULL`.
+ *    The warnings printed on stderr while parsing the command line are discarded.
+ *
+ *    @return `0` if the loop is running, `-1` otherwise.
+ */
+static int start_loop(const char* const* args, const auth_result_t* outcomes, int outcomes_size) {
+    char args_copy[MAX_ARGS][ARG_SIZE];
+    char* argv[MAX_ARGS + 6] = { "simplylock", "--force", "--io-stdio", "--fail-delay", "0", "--password-hash", hash_path };
+    int argc = 7;
+    for (int i = 0; i < MAX_ARGS && args[i] != NULL; i++) {
+        snprintf(args_copy[i], ARG_SIZE, "%s", args[i]);
+        argv[argc++] = args_copy[i];
+    }
+    argv[argc] = NULL;
+
+    optind = 0;
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(argc, argv);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    if (options == NULL) {
+        return -1;
+    }
+
+    master_fd = posix_openpt(O_RDWR | O_NOCTTY);
+    if (master_fd < 0 || grantpt(master_fd) < 0 || unlockpt(master_fd) < 0) {
+        options_free(options);
+        return -1;
+    }
+    screen_len = screen_seen = 0;
+    script_size = 0;
+    for (int i = 0; outcomes != NULL && i < outcomes_size && i < MAX_SCRIPT; i++) {
+        script[script_size++] = outcomes[i];
+    }
+    loop_pid = fork();
+    if (loop_pid == 0) {
+        run_locked(ptsname(master_fd), options, script_size > 0 ? authenticate_scripted : auth_session_authenticate);
+    }
+    options_free(options);
+    return loop_pid > 0 ? 0 : -1;
+}
+
+/**
+ *    Waits for the loop to draw `text`, after whatever has been expected before.
+ *
+ *    @return `0` if it has been drawn, `1` if it has not in time.
+ */
+static int expect(const char* test, const char* text) {
+    for (int waited = 0; ; ) {
+        screen[screen_len] = 0;
+        char* found = strstr(screen + screen_seen, text);
+        if (found != NULL) {
+            screen_seen = found - screen + strlen(text);
+            return 0;
+        }
+
+        struct pollfd pfd = { .fd = master_fd, .events = POLLIN };
+        if (waited >= EXPECT_TIMEOUT_MS || poll(&pfd, 1, 100) < 0) {
+            break;
+        }
+        waited += 100;
+        if (pfd.revents & POLLIN) {
+            ssize_t n = read(master_fd, screen + screen_len, sizeof(screen) - 1 - screen_len);
+            if (n > 0) {
+                screen_len += n;
+            }
+        } else if (pfd.revents & (POLLHUP | POLLERR)) {
+            break;
+        }
+    }
+    fprintf(stderr, "FAIL: %s: \"%s\" never shown after \"%s\"\n", test, text, screen + screen_seen);
+    return 1;
+}
+
+/**
+ *    Types the given keys on the locked terminal.
+ */
+static void type(const char* keys) {
+    if (write(master_fd, keys, strlen(keys)) < 0) {
+        perror("write");
+    }
+}
+
+/**
+ *    Types a key that raises a signal on the locked terminal.
+ *    The prompt is drawn just before the loop starts waiting for enter, and handling the signals:
+ *    the key is typed a moment later, as a user would.
+ */
+static void type_signal(const char* key) {
+    struct timespec delay = { 0, SIGNAL_DELAY_MS * 1000000L };
+    nanosleep(&delay, NULL);
+    type(key);
+}
+
+/**
+ *    Waits a moment and tells whether the station has been unlocked, i.e. the loop has returned.
+ */
+static int unlocked(int wait_ms) {
+    for (int waited = 0; waited <= wait_ms; waited += 50) {
+        int status;
+        pid_t pid = waitpid(loop_pid, &status, WNOHANG);
+        if (pid == loop_pid) {
+            loop_pid = -1;
+            return WIFEXITED(status) && WEXITSTATUS(status) == 0;
+        }
+        struct timespec delay = { 0, 50000000 };
+        nanosleep(&delay, NULL);
+    }
+    return 0;
+}
+
+/**
+ *    Stops the loop, if it is still running, and closes the terminal.
+ */
+static void stop_loop() {
+    if (loop_pid > 0) {
+        kill(loop_pid, SIGKILL);
+        waitpid(loop_pid, NULL, 0);
+        loop_pid = -1;
+    }
+    close(master_fd);
+    master_fd = -1;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    // The hash file must belong to root, and be readable only by root: created by the test, run as root, it is
+    int hash_fd = mkstemp(hash_path);
+    const char* hash = crypt(PASSWORD, "$6$simplylocktest$");
+    if (hash_fd < 0 || hash == NULL || write(hash_fd, hash, strlen(hash)) < 0) {
+        perror("mkstemp");
+        return 1;
+    }
+    close(hash_fd);
+
+    // A wrong password does not unlock, and the right one does
+    const char* root[] = { "--users", "root", NULL };
+    if (start_loop(root, NULL, 0) < 0) {
+        fprintf(stderr, "FAIL: cannot start the loop\n");
+        failures++;
+    } else {
+        failures += expect("wrong password", "Press enter to unlock as");
+        type("\n");
+        failures += expect("wrong password", "Password: ");
+        type("wrong\n");
+        failures += expect("wrong password", "Authentication failed.");
+        if (unlocked(200)) {
+            fprintf(stderr, "FAIL: wrong password: unlocked\n");
+            failures++;
+        }
+
+        // Back to the initial screen, which Ctrl+L draws again
+        failures += expect("after a failed attempt", "Press enter to unlock as");
+        type("\f");
+        failures += expect("Ctrl+L", "Press enter to unlock as");
+
+        type("\n");
+        failures += expect("right password", "Password: ");
+        type(PASSWORD "\n");
+        if (!unlocked(EXPECT_TIMEOUT_MS)) {
+            fprintf(stderr, "FAIL: right password: not unlocked\n");
+            failures++;
+        }
+        stop_loop();
+    }
+
+    // The password cannot be typed before enter is pressed: what was typed before is discarded
+    if (start_loop(root, NULL, 0) < 0) {
+        fprintf(stderr, "FAIL: cannot start the loop\n");
+        failures++;
+    } else {
+        failures += expect("typed ahead", "Press enter to unlock as");
+        type(PASSWORD "\n");
+        failures += expect("typed ahead", "Password: ");
+        type("\n");
+        failures += expect("typed ahead", "Authentication failed.");
+        if (unlocked(200)) {
+            fprintf(stderr, "FAIL: typed ahead: unlocked\n");
+            failures++;
+        }
+        stop_loop();
+    }
+
+    // After a failed attempt, the user can try again, and is told how many attempts are left
+    const char* attempts[] = { "--users", "root", "--max-attempts", "3", NULL };
+    const auth_result_t fail_then_succeed[] = { AUTH_FAILURE, AUTH_SUCCESS };
+    if (start_loop(attempts, fail_then_succeed, 2) < 0) {
+        fprintf(stderr, "FAIL: cannot start the loop\n");
+        failures++;
+    } else {
+        failures += expect("retry", "Press enter to unlock as");
+        type("\n");
+        failures += expect("retry", "[authenticate root]");
+        failures += expect("retry", "2 attempts remaining.");
+        failures += expect("retry", "Press enter to unlock as");
+        type("\n");
+        failures += expect("retry", "[authenticate root]");
+        if (!unlocked(EXPECT_TIMEOUT_MS)) {
+            fprintf(stderr, "FAIL: retry: not unlocked\n");
+            failures++;
+        }
+        stop_loop();
+    }
+
+    // An attempt that timed out is not a failed one
+    const auth_result_t timeout_then_fail[] = { AUTH_TIMEOUT, AUTH_FAILURE };
+    if (start_loop(attempts, timeout_then_fail, 2) < 0) {
+        fprintf(stderr, "FAIL: cannot start the loop\n");
+        failures++;
+    } else {
+        failures += expect("timeout", "Press enter to unlock as");
+        type("\n");
+        failures += expect("timeout", "[authenticate root]");
+        failures += expect("timeout", "Press enter to unlock as");
+        type("\n");
+        failures += expect("timeout", "[authenticate root]");
+        failures += expect("timeout", "Authentication failed. 2 attempts remaining.");
+        if (unlocked(200)) {
+            fprintf(stderr, "FAIL: timeout: unlocked\n");
+            failures++;
+        }
+        stop_loop();
+    }
+
+    // With more users, Ctrl+C goes to the selection of the user, and the one chosen is the one authenticated
+    const char* users[] = { "--users", "root,daemon", NULL };
+    const auth_result_t succeed[] = { AUTH_SUCCESS };
+    if (start_loop(users, succeed, 1) < 0) {
+        fprintf(stderr, "FAIL: cannot start the loop\n");
+        failures++;
+    } else {
+        failures += expect("Ctrl+C", "Press enter to unlock as");
+        type_signal("\003");
+        failures += expect("Ctrl+C", "2. daemon");
+        type("2");
+        failures += expect("Ctrl+C", "Press enter to unlock as");
+        failures += expect("Ctrl+C", "daemon");
+        type("\n");
+        failures += expect("Ctrl+C", "[authenticate daemon]");
+        if (!unlocked(EXPECT_TIMEOUT_MS)) {
+            fprintf(stderr, "FAIL: Ctrl+C: not unlocked\n");
+            failures++;
+        }
+        stop_loop();
+    }
+
+    // Asking for the user name, only the allowed users are ever authenticated
+    const char* ask[] = { "--no-root", "--users", "daemon", "--ask-username", NULL };
+    if (start_loop(ask, succeed, 1) < 0) {
+        fprintf(stderr, "FAIL: cannot start the loop\n");
+        failures++;
+    } else {
+        failures += expect("--ask-username", "Press enter to unlock");
+        type("\n");
+        failures += expect("--ask-username", "Username: ");
+        type("root\n");
+        failures += expect("--ask-username", "User root is not allowed to unlock.");
+        failures += expect("--ask-username", "Press enter to unlock");
+        type("\n");
+        failures += expect("--ask-username", "Username: ");
+        type("daemon\n");
+        failures += expect("--ask-username", "[authenticate daemon]");
+        if (strstr(screen, "[authenticate root]") != NULL) {
+            fprintf(stderr, "FAIL: --ask-username: root authenticated\n");
+            failures++;
+        }
+        if (!unlocked(EXPECT_TIMEOUT_MS)) {
+            fprintf(stderr, "FAIL: --ask-username: not unlocked\n");
+            failures++;
+        }
+        stop_loop();
+    }
+
+    unlink(hash_path);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
@@ -0,0 +1,320 @@
+#include <stdio.h>
+#include <string.h>
+#include <errno.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <pwd.h>
+#include <shadow.h>
+#include <sys/wait.h>
+#include "options.h"
+#include "smartcard.h"
+
+#define MAX_ARGS 10
+#define MAX_ARG_LEN 256
+
+// Only the builds for the tests accept the options meant for testing
+#ifdef SIMPLYLOCK_TESTING
+#define TESTING_BUILD 1
+#else
+#define TESTING_BUILD 0
+#endif
+
+/**
+ *    A command line to parse, and whether it must be accepted.
+ *    `--force` is always added, so that the test does not depend on the passwords of the users.
+ */
+struct test_case {
+    const char* args[MAX_ARGS];
+    int valid;
+};
+
+static const struct test_case cases[] = {
+    { { "--best-effort" }, 1 },
+    { { "--sysrq-mask", "48" }, 1 },
+    { { "--users", "root" }, 1 },
+    { { "--users", "0" }, 1 },
+    { { "--groups", "root" }, 1 },
+    { { "--ignore-unknown-users" }, 1 },
+    { { "--ignore-unknown-users", "--users", "root,simplylock-no-such-user" }, 1 },
+    { { "--users", "simplylock-no-such-user" }, 0 },
+    { { "--users", "root", "--no-root" }, 1 },
+    { { "--pam-username" }, 1 },
+    { { "--ask-username" }, 1 },
+    { { "--hide-users" }, 1 },
+    { { "--rescue-shell" }, 1 },
+    { { "--title", "Locked" }, 1 },
+    { { "--selection-message", "Who are you?" }, 1 },
+    { { "--prompt", "Press enter" }, 1 },
+    { { "--busy-message", "Wait" }, 1 },
+    { { "--markup" }, 1 },
+    { { "--no-clear" }, 1 },
+    { { "--no-clear", "--no-clear-row", "5" }, 1 },
+    { { "--no-clear", "--no-clear-row", "0" }, 0 },
+    { { "--no-clear-row", "5" }, 0 },
+    { { "--no-clear", "--center" }, 0 },
+    { { "--center" }, 1 },
+    { { "--box" }, 1 },
+    { { "--show-last" }, 1 },
+    { { "--show-vt" }, 1 },
+    { { "--color-prompt", "red" }, 1 },
+    { { "--color-user", "light-green" }, 1 },
+    { { "--color-error", "196" }, 1 },
+    { { "--echo-mode", "asterisk" }, 1 },
+    { { "--echo-mode", "dots:4" }, 1 },
+    { { "--echo-mode", "reveal" }, 1 },
+    { { "--echo-mode", "loud" }, 0 },
+    { { "--mask" }, 1 },
+    { { "--mask-style", "dots:4" }, 1 },
+    { { "--mask", "--mask-timeout", "500" }, 1 },
+    { { "--max-input", "64" }, 1 },
+    { { "--max-attempts", "3" }, 1 },
+    { { "--fail-delay", "5" }, 1 },
+    { { "--fail-message", "Nope" }, 1 },
+    { { "--timeout", "30" }, 1 },
+    { { "--bell" }, 1 },
+    { { "--quiet" }, 1 },
+    { { "--flash" }, 1 },
+    { { "--pam-timeout", "10" }, 1 },
+    { { "--pam-session" }, 1 },
+    { { "--step-up-service", "login" }, 0 },
+    { { "--pam-service", "login,root=../shadow" }, 0 },
+    { { "--pam-service", ".login" }, 0 },
+    { { "--pam-service", "log in" }, 0 },
+    { { "--idle", "60", "--step-up-service", "login*", "--step-up-after", "3" }, 0 },
+    { { "--password-fifo", "/run/simplylock.fifo" }, 1 },
+    { { "--password-hash", "/etc/simplylock-password" }, 1 },
+    { { "--password-hash", "/etc/simplylock-password", "--pam-session" }, 0 },
+    { { "--duress-file", "/etc/simplylock-duress" }, 0 },
+    { { "--emergency-file", "/run/unlock", "--emergency-key", "/etc/simplylock.pub" }, 1 },
+    { { "--emergency-file", "/run/unlock" }, 0 },
+    { { "--max-lock-time", "3600", "--max-lock-action", "warn" }, 1 },
+    { { "--background", "/tmp/bg.png", "--background-interval", "30" }, 1 },
+    { { "--bg-color", "blue" }, 1 },
+    { { "--all-fbdevs" }, 1 },
+    { { "--all-fbdevs", "--fbdev", "/dev/fb0" }, 0 },
+    { { "--no-hide-cursor" }, 1 },
+    { { "--lock-timeout", "10", "--kill-on-lock-timeout" }, 1 },
+    { { "--in-place" }, 1 },
+    { { "--io-stdio" }, TESTING_BUILD },
+    { { "--switch-back-to", "2" }, 1 },
+    { { "--return-vt", "2" }, 1 },
+    { { "--paranoid" }, 1 },
+    { { "--idle", "60", "--once" }, 1 },
+    { { "--pidfile", "/run/simplylock.pid", "--idle", "60" }, 0 },
+    { { "--verbose" }, 1 },
+    { { "--timings" }, 1 },
+    { { "--check", "--format", "json" }, 1 },
+    { { "--list-users" }, 1 },
+    { { "--list-vts" }, 1 },
+    { { "--build-info" }, 1 }
+};
+
+/**
+ *    Command lines that make simplylock, which is setuid root, run commands or use files chosen by the caller:
+ *    they must be accepted from root, and refused from anybody else.
+ */
+static const char* const root_only_cases[][MAX_ARGS] = {
+    { "--greeter-command", "date" },
+    { "--greeter-command", "date", "--greeter-interval", "60" },
+    { "--on-fail", "true" },
+    { "--pidfile", "/run/simplylock.pid" },
+    { "--sysrq-path", "/tmp/sysrq" },
+    { "--printk-path", "/tmp/printk" },
+    { "--pam-service", "login,root=su" },
+    { "--pam-service", "common-auth,root=system_auth.d" },
+    { "--idle", "60", "--step-up-service", "login", "--step-up-after", "3", "--step-up-window", "600" },
+    { "--max-lock-time", "3600", "--max-lock-action", "suspend" },
+    { "--max-lock-time", "3600", "--max-lock-action", "poweroff" },
+    { "--all-seats" },
+    { "--duress-file", "/etc/simplylock-duress", "--duress-command", "true", "--duress-action", "fail" }
+};
+
+/**
+ *    Parses the command line of a test case, with the usage and the warnings printed on stderr discarded.
+ *    Without `force`, the passwords of the users allowed to unlock are checked.
+ */
+static struct options* parse_forced(const char* const* args, int force) {
+    // Like the real argv, the arguments must be writable (the lists of users are split in place),
+    // and must stay around until the options are freed
+    static char copies[MAX_ARGS][MAX_ARG_LEN];
+    char* argv[MAX_ARGS + 3] = { "simplylock", "--force" };
+    int argc = force ? 2 : 1;
+    for (int i = 0; i < MAX_ARGS && args[i] != NULL; i++) {
+        snprintf(copies[i], MAX_ARG_LEN, "%s", args[i]);
+        argv[argc++] = copies[i];
+    }
+    argv[argc] = NULL;
+
+    // Every parse starts from scratch
+    optind = 0;
+
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(argc, argv);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    return options;
+}
+
+static struct options* parse(const char* const* args) {
+    return parse_forced(args, 1);
+}
+
+/**
+ *    Tells whether the command line is accepted when simplylock is started by a user other than root,
+ *    with `--force` if `force` is set.
+ *    When the test runs as root, the command line is parsed by a child process running as nobody.
+ *
+ *    @return `1` if accepted, `0` if refused, `-1` if the test could not be run.
+ */
+static int accepted_from_user(const char* const* args, int force) {
+    pid_t pid = fork();
+    if (pid == 0) {
+        struct passwd* nobody = getpwnam("nobody");
+        if (getuid() == 0 && (nobody == NULL || setgid(nobody->pw_gid) < 0 || setuid(nobody->pw_uid) < 0)) {
+            _exit(2);
+        }
+        _exit(parse_forced(args, force) != NULL ? 0 : 1);
+    } else if (pid == -1) {
+        return -1;
+    }
+
+    int status;
+    pid_t wpid;
+    while ((wpid = waitpid(pid, &status, 0)) == -1 && errno == EINTR);
+    if (wpid == -1 || !WIFEXITED(status) || WEXITSTATUS(status) > 1) {
+        return -1;
+    }
+    return WEXITSTATUS(status) == 0;
+}
+
+/**
+ *    Tells whether the shadow entry of root is hidden from the users other than root, as it usually is.
+ *    Some systems give it to everybody through NSS, and then no lookup ever fails.
+ *
+ *    @return `1` if hidden, `0` if not, `-1` if it could not be told.
+ */
+static int shadow_hidden_from_user() {
+    pid_t pid = fork();
+    if (pid == 0) {
+        struct passwd* nobody = getpwnam("nobody");
+        if (getuid() == 0 && (nobody == NULL || setgid(nobody->pw_gid) < 0 || setuid(nobody->pw_uid) < 0)) {
+            _exit(2);
+        }
+        _exit(getspnam("root") == NULL ? 0 : 1);
+    } else if (pid == -1) {
+        return -1;
+    }
+
+    int status;
+    pid_t wpid;
+    while ((wpid = waitpid(pid, &status, 0)) == -1 && errno == EINTR);
+    if (wpid == -1 || !WIFEXITED(status) || WEXITSTATUS(status) > 1) {
+        return -1;
+    }
+    return WEXITSTATUS(status) == 0;
+}
+
+static void print_args(const char* const* args) {
+    for (int i = 0; i < MAX_ARGS && args[i] != NULL; i++) {
+        fprintf(stderr, " %s", args[i]);
+    }
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    for (int i = 0; i < sizeof(cases) / sizeof(cases[0]); i++) {
+        struct options* options = parse(cases[i].args);
+        if ((options != NULL) != cases[i].valid) {
+            fprintf(stderr, "FAIL: expected");
+            print_args(cases[i].args);
+            fprintf(stderr, " to be %s\n", cases[i].valid ? "accepted" : "refused");
+            failures++;
+        }
+        if (options != NULL) {
+            options_free(options);
+        }
+    }
+
+    for (int i = 0; i < sizeof(root_only_cases) / sizeof(root_only_cases[0]); i++) {
+        int accepted = accepted_from_user(root_only_cases[i], 1);
+        if (accepted != 0) {
+            fprintf(stderr, "FAIL: expected");
+            print_args(root_only_cases[i]);
+            fprintf(stderr, " to be refused from users other than root%s\n", accepted < 0 ? ", but could not run as nobody" : "");
+            failures++;
+        }
+
+        // Only a test run by root can check that root is allowed
+        if (getuid() != 0) {
+            continue;
+        }
+        struct options* options = parse(root_only_cases[i]);
+        if (options == NULL) {
+            fprintf(stderr, "FAIL: expected");
+            print_args(root_only_cases[i]);
+            fprintf(stderr, " to be accepted from root\n");
+            failures++;
+        } else {
+            options_free(options);
+        }
+    }
+
+    // Only available to root, and only when built with smartcard support
+    const char* smartcard[] = { "--smartcard-service", "pam_pkcs11", NULL };
+    if (accepted_from_user(smartcard, 1) != 0) {
+        fprintf(stderr, "FAIL: expected --smartcard-service to be refused from users other than root\n");
+        failures++;
+    }
+    struct options* options;
+    if (getuid() == 0) {
+        options = parse(smartcard);
+        if ((options != NULL) != smartcard_supported()) {
+            fprintf(stderr, "FAIL: --smartcard-service %s\n", options != NULL ? "accepted without smartcard support" : "refused");
+            failures++;
+        }
+        if (options != NULL) {
+            options_free(options);
+        }
+    }
+
+    // Users other than root cannot read the shadow file: a password that cannot be checked is not assumed to work
+    const char* unchecked[] = { "--users", "root", NULL };
+    if (shadow_hidden_from_user() != 0) {
+        int accepted = accepted_from_user(unchecked, 0);
+        if (accepted != 0) {
+            fprintf(stderr, "FAIL: expected unchecked passwords to be refused without --force%s\n",
+                    accepted < 0 ? ", but could not run as nobody" : "");
+            failures++;
+        }
+    }
+    if (accepted_from_user(unchecked, 1) != 1) {
+        fprintf(stderr, "FAIL: expected unchecked passwords to be accepted with --force\n");
+        failures++;
+    }
+
+    // The values must actually be stored, not only accepted
+    const char* values[] = { "--selection-message", "Who are you?", "--ignore-unknown-users", "--prompt", "Go", NULL };
+    options = parse(values);
+    if (options == NULL || strcmp(options->selection_message, "Who are you?") != 0 ||
+            !options->ignore_unknown_users || strcmp(options->prompt, "Go") != 0) {
+        fprintf(stderr, "FAIL: --selection-message, --ignore-unknown-users or --prompt not stored\n");
+        failures++;
+    }
+    if (options != NULL) {
+        options_free(options);
+    }
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
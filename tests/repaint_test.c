@@ -0,0 +1,213 @@
+// The drawing of the console is private to main.c, which is included with its main renamed.
+// Pseudo-terminals need the X/Open definitions, which main.c does not ask for.
+#define _XOPEN_SOURCE 700
+#define main simplylock_main
+#include "main.c"
+#undef main
+
+#include <sys/ioctl.h>
+
+#define OUTPUT_SIZE 4096
+#define MAX_ARGS 10
+#define ARG_SIZE 64
+#define ROWS 24
+#define COLS 80
+
+// What is drawn before the text, to clear the screen
+#define CLEAR "\033[0m\033[H\033[J"
+
+// A user name in the default style
+#define USER(name) RESET HIGHLIGHT name RESET
+
+// The hint shown after the prompt when there are more users
+#define HINT " [Press Ctrl+C to change user]" RESET
+
+// Master side of the pseudo-terminal standing for the locked terminal: what is drawn on it is read from here
+static int master_fd = -1;
+
+/**
+ *    Opens a new pseudo-terminal of `ROWS` rows and `COLS` columns as stdin and stdout,
+ *    and returns the vt drawing on it.
+ *    Output is not processed, so that what is read is exactly what has been drawn.
+ */
+static struct vt* open_terminal() {
+    master_fd = posix_openpt(O_RDWR | O_NOCTTY);
+    if (master_fd < 0 || grantpt(master_fd) < 0 || unlockpt(master_fd) < 0) {
+        return NULL;
+    }
+    struct winsize size = { ROWS, COLS, 0, 0 };
+    ioctl(master_fd, TIOCSWINSZ, &size);
+    int slave_fd = open(ptsname(master_fd), O_RDWR | O_NOCTTY);
+    if (slave_fd < 0 || dup2(slave_fd, STDIN_FILENO) < 0 || dup2(slave_fd, STDOUT_FILENO) < 0) {
+        return NULL;
+    }
+    close(slave_fd);
+    fcntl(master_fd, F_SETFL, O_NONBLOCK);
+
+    struct termios term;
+    tcgetattr(STDOUT_FILENO, &term);
+    term.c_oflag &= ~OPOST;
+    tcsetattr(STDOUT_FILENO, TCSANOW, &term);
+
+    // As in simplylock, everything is drawn as soon as it is printed
+    setbuf(stdout, NULL);
+    return vt_fromstdio();
+}
+
+/**
+ *    Repaints the console for the given command line and user, and returns what has been drawn.
+ *    The warnings printed on stderr while parsing the command line are discarded.
+ */
+static const char* repaint(const char* const* args, struct vt* vt, const char* user) {
+    static char output[OUTPUT_SIZE];
+    output[0] = 0;
+
+    // The arguments are copied, since the options point to them and some are split in place
+    char args_copy[MAX_ARGS][ARG_SIZE];
+    char* argv[MAX_ARGS + 3] = { "simplylock", "--force" };
+    int argc = 2;
+    for (int i = 0; i < MAX_ARGS && args[i] != NULL; i++) {
+        snprintf(args_copy[i], ARG_SIZE, "%s", args[i]);
+        argv[argc++] = args_copy[i];
+    }
+    argv[argc] = NULL;
+
+    optind = 0;
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(argc, argv);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    if (options == NULL) {
+        return output;
+    }
+
+    repaint_console(options, vt, NULL, user);
+
+    size_t len = 0;
+    ssize_t n;
+    while ((n = read(master_fd, output + len, sizeof(output) - 1 - len)) > 0) {
+        len += n;
+    }
+    output[len] = 0;
+    options_free(options);
+    return output;
+}
+
+/**
+ *    Prints a string on stderr, with the escape sequences and control characters shown as `\ooo`.
+ */
+static void print_escaped(const char* str) {
+    for (const char* c = str; *c != 0; c++) {
+        if ((unsigned char)*c < ' ' || *c == 0x7f) {
+            fprintf(stderr, "\\%03o", (unsigned char)*c);
+        } else {
+            fputc(*c, stderr);
+        }
+    }
+}
+
+/**
+ *    Checks what has been drawn, showing the escape sequences if it is not the expected output.
+ *
+ *    @return `0` if it is the expected output, `1` otherwise.
+ */
+static int check_output(const char* test, const char* output, const char* expected) {
+    if (strcmp(output, expected) == 0) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: %s:\n  expected: ", test);
+    print_escaped(expected);
+    fprintf(stderr, "\n  got:      ");
+    print_escaped(output);
+    fprintf(stderr, "\n");
+    return 1;
+}
+
+/**
+ *    Checks that what has been drawn starts with `prefix` and contains `part`.
+ *
+ *    @return `0` if it does, `1` otherwise.
+ */
+static int check_contains(const char* test, const char* output, const char* prefix, const char* part) {
+    if (strncmp(output, prefix, strlen(prefix)) == 0 && strstr(output, part) != NULL) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: %s: expected to start with \"", test);
+    print_escaped(prefix);
+    fprintf(stderr, "\" and contain \"");
+    print_escaped(part);
+    fprintf(stderr, "\", got \"");
+    print_escaped(output);
+    fprintf(stderr, "\"\n");
+    return 1;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    struct vt* vt = open_terminal();
+    if (vt == NULL) {
+        perror("open_terminal");
+        return 1;
+    }
+
+    // The prompt alone, with the user in their own style
+    const char* single[] = { "--no-root", "--users", "root", NULL };
+    failures += check_output("no message", repaint(single, vt, "root"),
+                             CLEAR "\nPress enter to unlock as " USER("root") "." RESET " ");
+
+    // The message goes above the prompt, with its placeholders filled
+    const char* message[] = { "--no-root", "--users", "root", "-m", "Locked by %u\nBack soon", NULL };
+    failures += check_output("message", repaint(message, vt, "root"),
+                             CLEAR "\nLocked by root\nBack soon\n\nPress enter to unlock as " USER("root") "." RESET " ");
+
+    // With more users, the one that is unlocking is shown, with the hint to change user
+    const char* users[] = { "--no-root", "--users", "root,daemon", NULL };
+    failures += check_output("first user", repaint(users, vt, "root"),
+                             CLEAR "\nPress enter to unlock as " USER("root") "." RESET HINT " ");
+    failures += check_output("second user", repaint(users, vt, "daemon"),
+                             CLEAR "\nPress enter to unlock as " USER("daemon") "." RESET HINT " ");
+    const char* hidden[] = { "--no-root", "--users", "root,daemon", "--hide-users", NULL };
+    failures += check_output("--hide-users", repaint(hidden, vt, "daemon"), CLEAR "\nPress enter to unlock." RESET HINT " ");
+
+    // Colors apply to the prompt and to the user names
+    const char* colors[] = { "--no-root", "--users", "root", "--color-prompt", "green", "--color-user", "201", NULL };
+    failures += check_output("colors", repaint(colors, vt, "root"),
+                             CLEAR "\n\033[32mPress enter to unlock as " RESET "\033[1m\033[38;5;201mroot" RESET "\033[32m." RESET " ");
+
+    // Centered, the text is moved to the middle of the screen, and the cursor to the line below
+    const char* center[] = { "--no-root", "--users", "root", "--center", NULL };
+    failures += check_output("--center", repaint(center, vt, "root"),
+                             CLEAR "\033[12;25HPress enter to unlock as " USER("root") "." RESET " \033[13;1H");
+    const char* center_message[] = { "--no-root", "--users", "root", "--center", "-m", "Hello", NULL };
+    failures += check_output("--center with a message", repaint(center_message, vt, "root"),
+                             CLEAR "\033[11;25HHello\033[12;25H\033[13;25HPress enter to unlock as " USER("root") "." RESET
+                             " \033[14;1H");
+
+    // With --no-clear, nothing is cleared, or only what is below the given row
+    const char* no_clear[] = { "--no-root", "--users", "root", "--no-clear", NULL };
+    failures += check_output("--no-clear", repaint(no_clear, vt, "root"),
+                             "\033[0m\033[H\nPress enter to unlock as " USER("root") "." RESET " ");
+    const char* no_clear_row[] = { "--no-root", "--users", "root", "--no-clear", "--no-clear-row", "5", NULL };
+    failures += check_output("--no-clear-row", repaint(no_clear_row, vt, "root"),
+                             "\033[0m\033[5;1H\033[J\nPress enter to unlock as " USER("root") "." RESET " ");
+
+    // The title goes first, centered
+    const char* title[] = { "--no-root", "--users", "root", "--title", "Hi", NULL };
+    failures += check_contains("--title", repaint(title, vt, "root"), CLEAR "\n" HIGHLIGHT,
+                               "\n                                  \u2588   \u2588 \u2588\u2588\u2588\u2588\u2588\n");
+
+    vt_free(vt);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
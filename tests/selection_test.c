@@ -0,0 +1,188 @@
+#define _XOPEN_SOURCE 700
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <limits.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <termios.h>
+#include <getopt.h>
+#include "selection.h"
+#include "options.h"
+#include "vt.h"
+
+#define OUTPUT_SIZE 4096
+
+// Master side of the pseudo-terminal standing for the locked terminal: what is written to it is typed
+static int master_fd = -1;
+
+/**
+ *    Opens a new pseudo-terminal as stdin, and returns the vt reading from it.
+ */
+static struct vt* open_terminal() {
+    master_fd = posix_openpt(O_RDWR | O_NOCTTY);
+    if (master_fd < 0 || grantpt(master_fd) < 0 || unlockpt(master_fd) < 0) {
+        return NULL;
+    }
+    int slave_fd = open(ptsname(master_fd), O_RDWR | O_NOCTTY);
+    if (slave_fd < 0 || dup2(slave_fd, STDIN_FILENO) < 0) {
+        return NULL;
+    }
+    close(slave_fd);
+    clearerr(stdin);
+    return vt_fromstdio();
+}
+
+/**
+ *    Hangs up the terminal: from now on, reading from it fails as on EOF.
+ */
+static void hang_up() {
+    close(master_fd);
+    master_fd = -1;
+}
+
+/**
+ *    Types the given keys. Single keys are typed with the terminal already in non-canonical mode,
+ *    as when the user presses them while simplylock waits, so that no line editing happens.
+ */
+static void type(const char* keys, int canonical) {
+    struct termios term;
+    tcgetattr(STDIN_FILENO, &term);
+    if (!canonical) {
+        term.c_lflag &= ~ICANON;
+        term.c_cc[VMIN] = 1;
+        term.c_cc[VTIME] = 0;
+    }
+    tcsetattr(STDIN_FILENO, TCSANOW, &term);
+    if (write(master_fd, keys, strlen(keys)) < 0) {
+        perror("write");
+    }
+}
+
+/**
+ *    Prints the list of users for the given command line, and returns what has been printed.
+ *    The warnings printed on stderr while parsing the command line are discarded.
+ */
+static const char* print_users(char** argv, int argc, struct vt* vt) {
+    static char output[OUTPUT_SIZE];
+    output[0] = 0;
+
+    optind = 0;
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(argc, argv);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    if (options == NULL) {
+        return output;
+    }
+
+    FILE* out = tmpfile();
+    if (out == NULL) {
+        options_free(options);
+        return output;
+    }
+    fflush(stdout);
+    int saved_stdout = dup(STDOUT_FILENO);
+    dup2(fileno(out), STDOUT_FILENO);
+    selection_print_users(options, vt, options->users[0]);
+    fflush(stdout);
+    dup2(saved_stdout, STDOUT_FILENO);
+    close(saved_stdout);
+
+    rewind(out);
+    size_t len = fread(output, 1, sizeof(output) - 1, out);
+    output[len] = 0;
+    fclose(out);
+    options_free(options);
+    return output;
+}
+
+static int check_prefix(const char* test, const char* output, const char* expected) {
+    if (strncmp(output, expected, strlen(expected)) == 0) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: %s: expected the output to start with \"%s\", got \"%s\"\n", test, expected, output);
+    return 1;
+}
+
+static int check_index(const char* test, int index, int expected) {
+    if (index == expected) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: %s: expected %d, got %d\n", test, expected, index);
+    return 1;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    struct vt* vt = open_terminal();
+    if (vt == NULL) {
+        perror("open_terminal");
+        return 1;
+    }
+
+    // The list starts with the header, the configured one or the default one
+    char users[] = "root,daemon";
+    char message[] = "Who is there?";
+    char* custom[] = { "simplylock", "--force", "--users", users, "--selection-message", message, NULL };
+    const char* output = print_users(custom, 6, vt);
+    failures += check_prefix("--selection-message", output, "\nWho is there?\n\n");
+    if (strstr(output, "2. daemon\n") == NULL) {
+        fprintf(stderr, "FAIL: --selection-message: users not listed after the header: \"%s\"\n", output);
+        failures++;
+    }
+    char default_users[] = "root,daemon";
+    char* standard[] = { "simplylock", "--force", "--users", default_users, NULL };
+    failures += check_prefix("default header", print_users(standard, 4, vt), "\nThe following users are authorized to unlock:\n\n");
+
+    // A single key selects one of the first nine users, any other key nobody
+    type("2", 0);
+    failures += check_index("key 2", selection_read_key(vt), 1);
+    type("9", 0);
+    failures += check_index("key 9", selection_read_key(vt), 8);
+    type("0", 0);
+    failures += check_index("key 0", selection_read_key(vt), INT_MAX);
+    type("a", 0);
+    failures += check_index("key a", selection_read_key(vt), INT_MAX);
+
+    // The digits in escape sequences (here, F1 and the down arrow) must not select anybody
+    type("\033[11~", 0);
+    failures += check_index("key F1", selection_read_key(vt), INT_MAX);
+    type("\033[B", 0);
+    failures += check_index("key down", selection_read_key(vt), INT_MAX);
+    type("3", 0);
+    failures += check_index("key 3 after escape sequences", selection_read_key(vt), 2);
+
+    // Longer lists need the number and enter
+    type("12\n", 1);
+    failures += check_index("line 12", selection_read_line(vt), 11);
+    type("1\n", 1);
+    failures += check_index("line 1", selection_read_line(vt), 0);
+    type("0\n", 1);
+    failures += check_index("line 0", selection_read_line(vt), INT_MAX);
+    type("-3\n", 1);
+    failures += check_index("line -3", selection_read_line(vt), INT_MAX);
+    type("2x\n", 1);
+    failures += check_index("line 2x", selection_read_line(vt), INT_MAX);
+    type("\n", 1);
+    failures += check_index("empty line", selection_read_line(vt), INT_MAX);
+
+    // EOF is reported, and never taken for a selection
+    hang_up();
+    failures += check_index("key on EOF", selection_read_key(vt), -1);
+    failures += check_index("line on EOF", selection_read_line(vt), -1);
+    vt_free(vt);
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
@@ -0,0 +1,301 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <errno.h>
+#include <unistd.h>
+#include <fcntl.h>
+#include <getopt.h>
+#include <signal.h>
+#include <sys/wait.h>
+#include <sys/resource.h>
+#include "lock.h"
+#include "options.h"
+#include "vt.h"
+
+#define OUTPUT_SIZE 4096
+
+// Number of the first vt allocated for the lock, and of the one active before locking
+#define FIRST_VT 10
+#define CURRENT_VT 1
+
+/*
+ *    A console where someone else takes the vts we allocate: the vt library is replaced
+ *    by these functions, which only count what is asked of them.
+ */
+
+// How many of the next switches fail as if the vt had been taken, and whether allocating fails
+static int taken = 0;
+static int createabove_fails = 0;
+
+// What has been asked
+static int createabove_calls = 0;
+static int live_vts = 0;
+static unsigned int last_switch = 0;
+
+// Where every locking (`1`) and unlocking (`0`) of vt switching is written, if open
+static int lockswitch_fd = -1;
+
+static struct vt* make_vt(unsigned int number, int allocated) {
+    struct vt* vt = (struct vt*)calloc(1, sizeof(struct vt));
+    if (vt != NULL) {
+        vt->number = number;
+        vt->allocated = allocated;
+        live_vts++;
+    }
+    return vt;
+}
+
+struct vt* vt_createnew() {
+    return make_vt(FIRST_VT, 1);
+}
+
+struct vt* vt_createabove(unsigned int min) {
+    createabove_calls++;
+    if (createabove_fails) {
+        errno = ENOENT;
+        return NULL;
+    }
+    return make_vt(min, 1);
+}
+
+struct vt* vt_getcurrent() {
+    return make_vt(CURRENT_VT, 0);
+}
+
+void vt_free(struct vt* vt) {
+    if (vt != NULL) {
+        live_vts--;
+        free(vt);
+    }
+}
+
+int vt_switch(struct vt* to) {
+    if (taken > 0) {
+        taken--;
+        errno = ENXIO;
+        return -1;
+    }
+    last_switch = to->number;
+    return 0;
+}
+
+int vt_getstate(unsigned int* active, unsigned short* in_use) {
+    *active = last_switch;
+    *in_use = 0;
+    return 0;
+}
+
+int vt_lockswitch(int lock) {
+    if (lockswitch_fd >= 0 && write(lockswitch_fd, lock ? "1" : "0", 1) < 0) {
+        return -1;
+    }
+    return 0;
+}
+
+int vt_setcursor(struct vt* vt, int visible) {
+    return 0;
+}
+
+int vt_mutebell(struct vt* vt, int muted) {
+    vt->bell_muted = muted;
+    return 0;
+}
+
+int vt_blank(struct vt* vt, int blank) {
+    return 0;
+}
+
+void vt_restoreonsignal(struct vt* vt) {
+}
+
+/**
+ *    Parses the command line of the tests, with the warnings printed on stderr discarded.
+ *    The control files are left alone, so that only the vts are touched.
+ */
+static struct options* parse() {
+    char* argv[] = { "simplylock", "--force", "-s", "-k", NULL };
+    optind = 0;
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    int null_fd = open("/dev/null", O_WRONLY);
+    dup2(null_fd, STDERR_FILENO);
+    close(null_fd);
+    struct options* options = options_parse(4, argv);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+    return options;
+}
+
+/**
+ *    Locks a new vt, of which the given number of switches fail, and unlocks it.
+ *    What is printed on stderr is returned in `errors`.
+ *
+ *    @return The number of the vt locked, or `-1` if locking failed.
+ */
+static int try_lock(int allocated, char* errors) {
+    errors[0] = 0;
+    createabove_calls = 0;
+    last_switch = 0;
+    struct options* options = parse();
+    if (options == NULL) {
+        return -1;
+    }
+
+    FILE* err = tmpfile();
+    fflush(stderr);
+    int saved_stderr = dup(STDERR_FILENO);
+    dup2(fileno(err), STDERR_FILENO);
+    struct vt* vt = vt_createnew();
+    vt->allocated = allocated;
+    struct vt* locked = lock_with_vt(options, vt);
+    int number = locked != NULL ? (int)locked->number : -1;
+    if (locked != NULL && last_switch != locked->number) {
+        fprintf(stderr, "VT %u locked, but VT %u active\n", locked->number, last_switch);
+    }
+    unlock(options);
+    fflush(stderr);
+    dup2(saved_stderr, STDERR_FILENO);
+    close(saved_stderr);
+
+    rewind(err);
+    size_t len = fread(errors, 1, OUTPUT_SIZE - 1, err);
+    errors[len] = 0;
+    fclose(err);
+    options_free(options);
+    return number;
+}
+
+/**
+ *    Checks the outcome of `try_lock`.
+ *
+ *    @return The number of failures.
+ */
+static int check(const char* test, int number, int expected_number, int expected_calls, const char* errors,
+                 const char* expected_error) {
+    int failures = 0;
+    if (number != expected_number) {
+        fprintf(stderr, "FAIL: %s: expected VT %d to be locked, got %d\n", test, expected_number, number);
+        failures++;
+    }
+    if (createabove_calls != expected_calls) {
+        fprintf(stderr, "FAIL: %s: expected %d other VT(s) allocated, got %d\n", test, expected_calls, createabove_calls);
+        failures++;
+    }
+    if (live_vts != 0) {
+        fprintf(stderr, "FAIL: %s: %d VT(s) never freed\n", test, live_vts);
+        failures++;
+        live_vts = 0;
+    }
+    if (expected_error != NULL ? strstr(errors, expected_error) == NULL : strstr(errors, "VT") != NULL) {
+        fprintf(stderr, "FAIL: %s: expected %s%s%s on stderr, got \"%s\"\n", test,
+                expected_error != NULL ? "\"" : "", expected_error != NULL ? expected_error : "nothing about VTs",
+                expected_error != NULL ? "\"" : "", errors);
+        failures++;
+    }
+    taken = 0;
+    createabove_fails = 0;
+    return failures;
+}
+
+/**
+ *    Locks in a child process that then crashes, and tells what has been done to vt switching.
+ *
+ *    @return `0` if the test could be run, `-1` otherwise.
+ */
+static int crash_while_locked(char* switches, size_t n) {
+    int pipe_fds[2];
+    if (pipe(pipe_fds) < 0) {
+        return -1;
+    }
+    pid_t pid = fork();
+    if (pid == 0) {
+        close(pipe_fds[0]);
+        lockswitch_fd = pipe_fds[1];
+
+        // The crash is expected, and must not leave a core dump behind
+        struct rlimit no_core = { 0, 0 };
+        setrlimit(RLIMIT_CORE, &no_core);
+        struct options* options = parse();
+        if (options == NULL || lock_with_vt(options, vt_createnew()) == NULL || lock_handle_crashes(1) < 0) {
+            _exit(2);
+        }
+        raise(SIGSEGV);
+        _exit(3);
+    }
+    close(pipe_fds[1]);
+    if (pid == -1) {
+        close(pipe_fds[0]);
+        return -1;
+    }
+
+    size_t len = 0;
+    ssize_t ret;
+    while (len < n - 1 && (ret = read(pipe_fds[0], switches + len, n - 1 - len)) != 0) {
+        if (ret > 0) {
+            len += ret;
+        } else if (errno != EINTR) {
+            break;
+        }
+    }
+    switches[len] = 0;
+    close(pipe_fds[0]);
+
+    int status;
+    pid_t wpid;
+    while ((wpid = waitpid(pid, &status, 0)) == -1 && errno == EINTR);
+    return wpid != -1 && WIFSIGNALED(status) && WTERMSIG(status) == SIGSEGV ? 0 : -1;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+    char errors[OUTPUT_SIZE];
+
+    // The vt allocated is free
+    int number = try_lock(1, errors);
+    failures += check("free VT", number, FIRST_VT, 0, errors, NULL);
+
+    // Taken twice: the next two higher vts are tried
+    taken = 2;
+    number = try_lock(1, errors);
+    failures += check("VT taken twice", number, FIRST_VT + 2, 2, errors, NULL);
+
+    // Taken every time: the retries are bounded, and say how many vts were tried
+    taken = 1000;
+    number = try_lock(1, errors);
+    failures += check("VT always taken", number, -1, 4, errors, "tried 5 VT(s)");
+    taken = 5;
+    number = try_lock(1, errors);
+    failures += check("VT taken 5 times", number, -1, 4, errors, "tried 5 VT(s)");
+    taken = 4;
+    number = try_lock(1, errors);
+    failures += check("VT taken 4 times", number, FIRST_VT + 4, 4, errors, NULL);
+
+    // A vt we did not allocate is never replaced by another one
+    taken = 1;
+    number = try_lock(0, errors);
+    failures += check("VT not allocated by us", number, -1, 0, errors, "tried 1 VT(s)");
+
+    // Nothing left to allocate
+    taken = 1;
+    createabove_fails = 1;
+    number = try_lock(1, errors);
+    failures += check("no VT left", number, -1, 1, errors, "Could not allocate another VT, tried 1 VT(s)");
+
+    // A crash enables vt switching again
+    char switches[16];
+    if (crash_while_locked(switches, sizeof(switches)) < 0) {
+        fprintf(stderr, "FAIL: crash: expected the process to die of SIGSEGV\n");
+        failures++;
+    } else if (strcmp(switches, "10") != 0) {
+        fprintf(stderr, "FAIL: crash: expected vt switching to be locked and then unlocked, got \"%s\"\n", switches);
+        failures++;
+    }
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}
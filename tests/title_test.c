@@ -0,0 +1,129 @@
+#include <stdio.h>
+#include <string.h>
+#include "title.h"
+
+#define ROW_SIZE 1024
+
+// How a pixel is printed
+#define PIXEL "█"
+
+/**
+ *    Prints a row of a title, and returns it with every pixel replaced by a `#`, as in the font.
+ */
+static const char* print_row(const char* text, int row) {
+    static char output[ROW_SIZE];
+    char printed[ROW_SIZE];
+    output[0] = 0;
+
+    FILE* out = tmpfile();
+    if (out == NULL) {
+        return output;
+    }
+    title_print_row(text, row, out);
+    rewind(out);
+    size_t len = fread(printed, 1, sizeof(printed) - 1, out);
+    printed[len] = 0;
+    fclose(out);
+
+    char* o = output;
+    for (const char* p = printed; *p != 0; o++) {
+        if (strncmp(p, PIXEL, strlen(PIXEL)) == 0) {
+            *o = '#';
+            p += strlen(PIXEL);
+        } else {
+            *o = *p++;
+        }
+    }
+    *o = 0;
+    return output;
+}
+
+static int check_width(const char* text, int expected) {
+    int width = title_width(text);
+    if (width == expected) {
+        return 0;
+    }
+    fprintf(stderr, "FAIL: width of \"%s\": expected %d, got %d\n", text, expected, width);
+    return 1;
+}
+
+/**
+ *    Checks every row of a title against the expected ones.
+ *
+ *    @return The number of failures.
+ */
+static int check_rows(const char* text, const char* const expected[TITLE_HEIGHT]) {
+    int failures = 0;
+    for (int row = 0; row < TITLE_HEIGHT; row++) {
+        const char* output = print_row(text, row);
+        if (strcmp(output, expected[row]) != 0) {
+            fprintf(stderr, "FAIL: row %d of \"%s\": expected \"%s\", got \"%s\"\n", row, text, expected[row], output);
+            failures++;
+        }
+    }
+    return failures;
+}
+
+/**
+ *    Checks that every row of a title is exactly as wide as measured, so that it can be centered.
+ *
+ *    @return The number of failures.
+ */
+static int check_aligned(const char* text) {
+    int failures = 0;
+    int width = title_width(text);
+    for (int row = 0; row < TITLE_HEIGHT; row++) {
+        int len = (int)strlen(print_row(text, row));
+        if (len != width) {
+            fprintf(stderr, "FAIL: row %d of \"%s\": expected %d columns, got %d\n", row, text, width, len);
+            failures++;
+        }
+    }
+    return failures;
+}
+
+int main(int argc, char** argv) {
+    int failures = 0;
+
+    // Characters are separated by an empty column, and lower case is drawn as upper case
+    failures += check_width("", 0);
+    failures += check_width("A", 5);
+    failures += check_width("Hi", 11);
+    failures += check_width("a b", 15);
+    failures += check_width("1.5", 13);
+    failures += check_width("Lock!", 25);
+
+    // Characters missing from the font are reported, so that the plain text is shown instead
+    failures += check_width("A_B", -1);
+    failures += check_width("caf\xc3\xa9", -1);
+    failures += check_width("\t", -1);
+
+    const char* const hi[TITLE_HEIGHT] = {
+        "#   # #####",
+        "#   #   #  ",
+        "#####   #  ",
+        "#   #   #  ",
+        "#   # #####",
+    };
+    failures += check_rows("Hi", hi);
+    failures += check_rows("hI", hi);
+
+    const char* const version[TITLE_HEIGHT] = {
+        "  #      ### ",
+        " ##     #  ##",
+        "  #     # # #",
+        "  #     ##  #",
+        " ###  #  ### ",
+    };
+    failures += check_rows("1.0", version);
+
+    failures += check_aligned("Kiosk 42: locked, ok? -- yes!");
+    failures += check_aligned("the quick brown fox jumps over the lazy dog 0123456789");
+
+    if (failures > 0) {
+        fprintf(stderr, "%d test(s) failed\n", failures);
+        return 1;
+    }
+    fprintf(stderr, "All tests passed\n");
+    return 0;
+}